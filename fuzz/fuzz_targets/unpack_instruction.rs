@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use testsolana::instruction::GachaMarketplaceInstruction;
+
+// arbitrary instruction data must be rejected, never panic, and whatever
+// decodes must be the canonical encoding of what it decoded to
+fuzz_target!(|data: &[u8]| {
+    if let Ok(instruction) = GachaMarketplaceInstruction::unpack(data) {
+        assert_eq!(instruction.pack(), data);
+    }
+});
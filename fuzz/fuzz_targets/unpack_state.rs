@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use testsolana::state::{ProgramAccount, State};
+
+// arbitrary state account data must be rejected, never panic, and whatever
+// decodes must be stable once written back in the current layout
+fuzz_target!(|data: &[u8]| {
+    if let Ok(state) = State::unpack_account(data) {
+        let packed = state.pack_account().unwrap();
+        let repacked = State::unpack_account(&packed).unwrap().pack_account().unwrap();
+        assert_eq!(repacked, packed);
+    }
+});
@@ -0,0 +1,78 @@
+//! Operates the marketplace from the command line; run without arguments
+//! for usage.
+
+use std::{env, error::Error, path::PathBuf, process};
+
+use solana_rpc_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    signature::{read_keypair_file, Signer},
+    transaction::Transaction,
+};
+use testsolana::{
+    cli::{self, Command, Options},
+    event,
+    state::{ProgramAccount, State},
+};
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("gacha-cli: {err}");
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let options = Options::parse(env::args().skip(1))?;
+    let rpc = RpcClient::new_with_commitment(options.url.clone(), CommitmentConfig::confirmed());
+    let (state_address, _) = State::find_address(&options.program_id);
+    let state = if options.command.needs_state() {
+        Some(State::unpack_account(
+            &rpc.get_account_data(&state_address)?,
+        )?)
+    } else {
+        None
+    };
+
+    if let Command::InspectState = options.command {
+        let state = state.ok_or("the marketplace state is required")?;
+        print!("{}", cli::describe_state(&state_address, &state));
+        return Ok(());
+    }
+
+    let keypair_path = match options.keypair {
+        Some(path) => path,
+        None => PathBuf::from(env::var("HOME")?).join(".config/solana/id.json"),
+    };
+    let signer = read_keypair_file(&keypair_path)
+        .map_err(|err| format!("reading {}: {err}", keypair_path.display()))?;
+    let instructions =
+        options
+            .command
+            .instructions(&options.program_id, &signer.pubkey(), state.as_ref())?;
+    let transaction = Transaction::new_signed_with_payer(
+        &instructions,
+        Some(&signer.pubkey()),
+        &[&signer],
+        rpc.get_latest_blockhash()?,
+    );
+
+    if options.dry_run {
+        let simulation = rpc.simulate_transaction(&transaction)?.value;
+        let logs = simulation.logs.unwrap_or_default();
+        for log in &logs {
+            println!("{log}");
+        }
+        for event in event::parse_logs(&options.program_id, &logs) {
+            println!("event: {event:?}");
+        }
+        if let Some(err) = simulation.err {
+            return Err(format!("simulation failed: {err}").into());
+        }
+        println!("simulation succeeded, nothing was sent");
+    } else {
+        let signature = rpc.send_and_confirm_transaction(&transaction)?;
+        println!("{signature}");
+    }
+    Ok(())
+}
@@ -0,0 +1,34 @@
+//! Prints the program's Anchor IDL as JSON.
+//!
+//!     gacha-idl [--program <id>] [<output-file>]
+
+use std::{env, error::Error, fs, process};
+
+use solana_program::pubkey::Pubkey;
+use testsolana::idl;
+
+const USAGE: &str = "usage: gacha-idl [--program <id>] [<output-file>]";
+
+fn main() {
+    if let Err(err) = run(env::args().skip(1).collect()) {
+        eprintln!("gacha-idl: {err}");
+        process::exit(1);
+    }
+}
+
+fn run(mut args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let mut program_id = testsolana::id();
+    if let Some(at) = args.iter().position(|arg| arg == "--program") {
+        let value = args.get(at + 1).ok_or(USAGE)?;
+        program_id = value.parse::<Pubkey>()?;
+        args.drain(at..at + 2);
+    }
+
+    let json = serde_json::to_string_pretty(&idl::generate(&program_id.to_string()))?;
+    match args.as_slice() {
+        [] => println!("{json}"),
+        [path] => fs::write(path, json + "\n")?,
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
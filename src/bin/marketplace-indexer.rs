@@ -0,0 +1,46 @@
+//! Builds a SQLite database of marketplace activity.
+//!
+//!     marketplace-indexer <db> replay <fixture-dir> [--program <id>]
+//!     marketplace-indexer <db> sync <rpc-url> [--program <id>]
+
+use std::{env, process};
+
+use solana_program::pubkey::Pubkey;
+use solana_rpc_client::rpc_client::RpcClient;
+use testsolana::indexer::{Indexer, Result};
+
+const USAGE: &str =
+    "usage: marketplace-indexer <db> (replay <fixture-dir> | sync <rpc-url>) [--program <id>]";
+
+fn main() {
+    if let Err(err) = run(env::args().skip(1).collect()) {
+        eprintln!("marketplace-indexer: {err}");
+        process::exit(1);
+    }
+}
+
+fn run(mut args: Vec<String>) -> Result<()> {
+    let mut program_id = testsolana::id();
+    if let Some(at) = args.iter().position(|arg| arg == "--program") {
+        let value = args.get(at + 1).ok_or(USAGE)?;
+        program_id = value.parse::<Pubkey>()?;
+        args.drain(at..at + 2);
+    }
+    let [db, command, source] = args.as_slice() else {
+        return Err(USAGE.into());
+    };
+
+    let mut indexer = Indexer::open(db, program_id)?;
+    match command.as_str() {
+        "replay" => {
+            let files = indexer.replay_dir(source)?;
+            println!("replayed {files} fixture files into {db}");
+        }
+        "sync" => {
+            let applied = indexer.sync_rpc(&RpcClient::new(source.clone()))?;
+            println!("applied {applied} transactions into {db}");
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,355 @@
+//! Argument parsing and instruction planning for the `gacha-cli` binary,
+//! kept apart from the RPC plumbing so it can be exercised in tests.
+
+use std::{fmt::Write, path::PathBuf};
+
+use solana_program::{instruction::Instruction, pubkey::Pubkey};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account_idempotent,
+};
+
+use crate::{
+    client,
+    instruction::factory::{self, ConfigUpdate},
+    state::State,
+};
+
+pub const USAGE: &str =
+    "usage: gacha-cli [--url <rpc-url>] [--keypair <path>] [--program <id>] [--dry-run] <command>
+
+commands:
+    init-state <listing-price>
+    list <mint> <price> <amount> [--name <file-name>] [--description <text>]
+    delist <item-id>
+    buy <item-id> [--amount <units>] [--referrer <pubkey>]
+    create-pool <qty>
+    draw <qty> <price> [--referrer <pubkey>]
+    update-config [--listing-price <lamports>] [--fee-bps <bps>] [--max-gacha-qty <qty>]
+                  [--treasury <pubkey>] [--gacha-fee <lamports>]
+    inspect-state";
+
+pub const DEFAULT_URL: &str = "http://localhost:8899";
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    InitState {
+        listing_price: u64,
+    },
+    List {
+        mint: Pubkey,
+        price: u64,
+        amount: u64,
+        file_name: String,
+        description: String,
+    },
+    Delist {
+        item_id: u128,
+    },
+    Buy {
+        item_id: u128,
+        amount: u64,
+        referrer: Option<Pubkey>,
+    },
+    CreatePool {
+        qty: u8,
+    },
+    Draw {
+        qty: u8,
+        price: u64,
+        referrer: Option<Pubkey>,
+    },
+    UpdateConfig(ConfigUpdate),
+    InspectState,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub url: String,
+    pub keypair: Option<PathBuf>, // the Solana CLI default keypair when unset
+    pub program_id: Pubkey,
+    pub dry_run: bool,
+    pub command: Command,
+}
+
+impl Options {
+    /// Parses the arguments after the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = Args::new(args);
+        let url = args
+            .flag("--url")?
+            .unwrap_or_else(|| DEFAULT_URL.to_string());
+        let keypair = args.flag("--keypair")?.map(PathBuf::from);
+        let program_id = args.parsed_flag("--program")?.unwrap_or_else(crate::id);
+        let dry_run = args.switch("--dry-run");
+
+        let command = match args.positional("command")?.as_str() {
+            "init-state" => Command::InitState {
+                listing_price: args.parsed("listing-price")?,
+            },
+            "list" => Command::List {
+                mint: args.parsed("mint")?,
+                price: args.parsed("price")?,
+                amount: args.parsed("amount")?,
+                file_name: args.flag("--name")?.unwrap_or_default(),
+                description: args.flag("--description")?.unwrap_or_default(),
+            },
+            "delist" => Command::Delist {
+                item_id: args.parsed("item-id")?,
+            },
+            "buy" => Command::Buy {
+                item_id: args.parsed("item-id")?,
+                amount: args.parsed_flag("--amount")?.unwrap_or(1),
+                referrer: args.parsed_flag("--referrer")?,
+            },
+            "create-pool" => Command::CreatePool {
+                qty: args.parsed("qty")?,
+            },
+            "draw" => Command::Draw {
+                qty: args.parsed("qty")?,
+                price: args.parsed("price")?,
+                referrer: args.parsed_flag("--referrer")?,
+            },
+            "update-config" => Command::UpdateConfig(ConfigUpdate {
+                listing_price: args.parsed_flag("--listing-price")?,
+                fee_bps: args.parsed_flag("--fee-bps")?,
+                max_gacha_qty: args.parsed_flag("--max-gacha-qty")?,
+                treasury: args.parsed_flag("--treasury")?,
+                gacha_fee: args.parsed_flag("--gacha-fee")?,
+            }),
+            "inspect-state" => Command::InspectState,
+            other => return Err(format!("unknown command `{other}`")),
+        };
+        args.finish()?;
+
+        Ok(Self {
+            url,
+            keypair,
+            program_id,
+            dry_run,
+            command,
+        })
+    }
+}
+
+impl Command {
+    /// Whether `instructions` needs the current state, to look up items,
+    /// the treasury or the gacha pool.
+    pub fn needs_state(&self) -> bool {
+        !matches!(
+            self,
+            Command::InitState { .. } | Command::List { .. } | Command::UpdateConfig(_)
+        )
+    }
+
+    /// The instructions `signer` sends to carry out the command. Token
+    /// accounts the signer receives into are created first if missing.
+    pub fn instructions(
+        &self,
+        program_id: &Pubkey,
+        signer: &Pubkey,
+        state: Option<&State>,
+    ) -> Result<Vec<Instruction>, String> {
+        let state = || state.ok_or_else(|| "the marketplace state is required".to_string());
+        let item = |item_id: &u128| {
+            state()?
+                .map
+                .get(item_id)
+                .ok_or_else(|| format!("item {item_id} is not listed"))
+        };
+        let receive = |mint: &Pubkey| {
+            create_associated_token_account_idempotent(signer, signer, mint, &spl_token::id())
+        };
+
+        Ok(match self {
+            Command::InitState { listing_price } => {
+                vec![factory::init_state(program_id, signer, *listing_price)]
+            }
+            Command::List {
+                mint,
+                price,
+                amount,
+                file_name,
+                description,
+            } => vec![factory::create_market_item(
+                program_id,
+                signer,
+                mint,
+                &get_associated_token_address(signer, mint),
+                *price,
+                *amount,
+                file_name,
+                description,
+            )],
+            Command::Delist { item_id } => {
+                let item = item(item_id)?;
+                vec![
+                    receive(&item.mint_address),
+                    factory::cancel_market_item(
+                        program_id,
+                        signer,
+                        &get_associated_token_address(signer, &item.mint_address),
+                        &item.mint_address,
+                        *item_id,
+                    ),
+                ]
+            }
+            Command::Buy {
+                item_id,
+                amount,
+                referrer,
+            } => {
+                let item = item(item_id)?;
+                vec![
+                    receive(&item.mint_address),
+                    factory::purchase_sale(
+                        program_id,
+                        signer,
+                        &get_associated_token_address(signer, &item.mint_address),
+                        &item.seller,
+                        &state()?.treasury,
+                        &item.mint_address,
+                        *item_id,
+                        item.price,
+                        *amount,
+                        referrer.as_ref(),
+                    ),
+                ]
+            }
+            Command::CreatePool { qty } => {
+                vec![factory::create_gacha(
+                    program_id,
+                    signer,
+                    &state()?.treasury,
+                    *qty,
+                )]
+            }
+            Command::Draw {
+                qty,
+                price,
+                referrer,
+            } => {
+                let state = state()?;
+                let candidates = client::gacha_candidates(state, signer, *price);
+                if candidates.is_empty() {
+                    return Err(format!("no gacha items are priced at {price}"));
+                }
+                let mut instructions: Vec<Instruction> = candidates
+                    .iter()
+                    .map(|(_, mint, _)| receive(mint))
+                    .collect();
+                instructions.push(factory::gacha(
+                    program_id,
+                    signer,
+                    &state.treasury,
+                    *qty,
+                    *price,
+                    referrer.as_ref(),
+                    &candidates,
+                ));
+                instructions
+            }
+            Command::UpdateConfig(update) => {
+                vec![factory::update_config(program_id, signer, update.clone())]
+            }
+            Command::InspectState => vec![],
+        })
+    }
+}
+
+/// A human readable summary of the state for `inspect-state`.
+pub fn describe_state(address: &Pubkey, state: &State) -> String {
+    let mut out = String::new();
+    // writing into a String cannot fail
+    let _ = writeln!(out, "state:          {address}");
+    let _ = writeln!(out, "owner:          {}", state.owner);
+    let _ = writeln!(out, "treasury:       {}", state.treasury);
+    let _ = writeln!(out, "listing price:  {}", state.listing_price);
+    let _ = writeln!(out, "fee bps:        {}", state.fee_bps);
+    let _ = writeln!(out, "max gacha qty:  {}", state.max_gacha_qty);
+    let _ = writeln!(out, "gacha fee:      {}", state.gacha_fee);
+    let _ = writeln!(out, "paused:         {:#x}", state.paused);
+    let _ = writeln!(out, "items listed:   {}", state.item_ids);
+    let _ = writeln!(out, "items sold:     {}", state.item_sold);
+    for item in state.map.values() {
+        let _ = writeln!(
+            out,
+            "item {}: mint {} seller {} price {} remaining {}/{}{}{}",
+            item.item_id,
+            item.mint_address,
+            item.seller,
+            item.price,
+            item.remaining,
+            item.amount,
+            if item.gacha { " gacha" } else { "" },
+            if item.sold { " sold" } else { "" },
+        );
+    }
+    out
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid {name} `{value}`"))
+}
+
+/// Flags may appear anywhere; whatever is left once they are taken out
+/// are the positional arguments, in order.
+struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    fn new<I: IntoIterator<Item = String>>(args: I) -> Self {
+        Self {
+            args: args.into_iter().collect(),
+        }
+    }
+
+    fn switch(&mut self, name: &str) -> bool {
+        let before = self.args.len();
+        self.args.retain(|arg| arg != name);
+        self.args.len() != before
+    }
+
+    fn flag(&mut self, name: &str) -> Result<Option<String>, String> {
+        let Some(at) = self.args.iter().position(|arg| arg == name) else {
+            return Ok(None);
+        };
+        if at + 1 >= self.args.len() {
+            return Err(format!("{name} needs a value"));
+        }
+        Ok(self.args.drain(at..at + 2).nth(1))
+    }
+
+    fn parsed_flag<T: std::str::FromStr>(&mut self, name: &str) -> Result<Option<T>, String> {
+        self.flag(name)?
+            .map(|value| parse(name, &value))
+            .transpose()
+    }
+
+    /// The first argument that is neither a flag nor a flag's value; every
+    /// switch must already be taken out.
+    fn positional(&mut self, name: &str) -> Result<String, String> {
+        let mut at = 0;
+        while at < self.args.len() {
+            if !self.args[at].starts_with("--") {
+                return Ok(self.args.remove(at));
+            }
+            at += 2;
+        }
+        Err(format!("missing <{name}>\n\n{USAGE}"))
+    }
+
+    fn parsed<T: std::str::FromStr>(&mut self, name: &str) -> Result<T, String> {
+        let value = self.positional(name)?;
+        parse(name, &value)
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.args.first() {
+            Some(arg) => Err(format!("unexpected argument `{arg}`\n\n{USAGE}")),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,356 @@
+//! A typed async client over the instruction builders.
+//!
+//! `MarketplaceClient` derives the PDAs and token accounts an action needs,
+//! creates the signer's associated token accounts when it receives tokens,
+//! and decodes the program's custom errors back into `GachaError`. It talks
+//! to the cluster through `MarketplaceRpc`, which is implemented for
+//! `BanksClient` (feature `banks-client`) and the nonblocking `RpcClient`
+//! (feature `rpc-client`).
+
+use std::future::Future;
+
+use solana_program::{
+    hash::Hash, instruction::Instruction, program_error::ProgramError, pubkey::Pubkey,
+};
+use solana_sdk::{
+    signature::{Keypair, Signature, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account_idempotent,
+};
+use thiserror::Error;
+
+use crate::{
+    error::GachaError,
+    instruction::factory,
+    state::{IndexKind, MarketItem, ProgramAccount, State, WalletIndex},
+};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("{0}")]
+    Program(GachaError),
+    #[error("transaction failed: {0}")]
+    Transaction(TransactionError),
+    #[error("account {0} does not exist")]
+    AccountNotFound(Pubkey),
+    #[error("item {0} is not listed")]
+    ItemNotFound(u128),
+    #[error("account data could not be decoded: {0}")]
+    InvalidAccountData(ProgramError),
+    #[error("rpc error: {0}")]
+    Rpc(String),
+}
+
+/// The cluster access `MarketplaceClient` needs.
+pub trait MarketplaceRpc {
+    /// The account's data, or None if it does not exist.
+    fn get_account_data(
+        &mut self,
+        address: &Pubkey,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, ClientError>> + Send;
+
+    fn get_latest_blockhash(&mut self) -> impl Future<Output = Result<Hash, ClientError>> + Send;
+
+    /// Sends the transaction and waits for it to be confirmed. A failed
+    /// transaction is reported as `ClientError::Transaction`.
+    fn send_transaction(
+        &mut self,
+        transaction: Transaction,
+    ) -> impl Future<Output = Result<(), ClientError>> + Send;
+}
+
+#[cfg(feature = "banks-client")]
+impl MarketplaceRpc for solana_banks_client::BanksClient {
+    async fn get_account_data(&mut self, address: &Pubkey) -> Result<Option<Vec<u8>>, ClientError> {
+        let account = self
+            .get_account(*address)
+            .await
+            .map_err(|err| ClientError::Rpc(err.to_string()))?;
+        Ok(account.map(|account| account.data))
+    }
+
+    async fn get_latest_blockhash(&mut self) -> Result<Hash, ClientError> {
+        solana_banks_client::BanksClient::get_latest_blockhash(self)
+            .await
+            .map_err(|err| ClientError::Rpc(err.to_string()))
+    }
+
+    async fn send_transaction(&mut self, transaction: Transaction) -> Result<(), ClientError> {
+        use solana_banks_client::BanksClientError;
+
+        self.process_transaction(transaction)
+            .await
+            .map_err(|err| match err {
+                BanksClientError::TransactionError(err)
+                | BanksClientError::SimulationError { err, .. } => ClientError::Transaction(err),
+                err => ClientError::Rpc(err.to_string()),
+            })
+    }
+}
+
+#[cfg(feature = "rpc-client")]
+impl MarketplaceRpc for solana_rpc_client::nonblocking::rpc_client::RpcClient {
+    async fn get_account_data(&mut self, address: &Pubkey) -> Result<Option<Vec<u8>>, ClientError> {
+        let response = self
+            .get_account_with_commitment(address, self.commitment())
+            .await
+            .map_err(|err| ClientError::Rpc(err.to_string()))?;
+        Ok(response.value.map(|account| account.data))
+    }
+
+    async fn get_latest_blockhash(&mut self) -> Result<Hash, ClientError> {
+        solana_rpc_client::nonblocking::rpc_client::RpcClient::get_latest_blockhash(self)
+            .await
+            .map_err(|err| ClientError::Rpc(err.to_string()))
+    }
+
+    async fn send_transaction(&mut self, transaction: Transaction) -> Result<(), ClientError> {
+        self.send_and_confirm_transaction(&transaction)
+            .await
+            .map(|_| ())
+            .map_err(|err| match err.get_transaction_error() {
+                Some(err) => ClientError::Transaction(err),
+                None => ClientError::Rpc(err.to_string()),
+            })
+    }
+}
+
+/// Parameters of `MarketplaceClient::list_item`.
+#[derive(Clone, Debug, Default)]
+pub struct NewListing {
+    pub mint: Pubkey,
+    pub price: u64,  // lamports per unit
+    pub amount: u64, // units moved from the seller's associated token account
+    pub file_name: String,
+    pub description: String,
+}
+
+pub struct MarketplaceClient<R> {
+    rpc: R,
+    program_id: Pubkey,
+    payer: Keypair, // pays fees and rent for every transaction
+}
+
+impl<R: MarketplaceRpc> MarketplaceClient<R> {
+    pub fn new(rpc: R, program_id: Pubkey, payer: Keypair) -> Self {
+        Self {
+            rpc,
+            program_id,
+            payer,
+        }
+    }
+
+    pub fn program_id(&self) -> &Pubkey {
+        &self.program_id
+    }
+
+    pub fn payer(&self) -> &Keypair {
+        &self.payer
+    }
+
+    pub fn rpc(&mut self) -> &mut R {
+        &mut self.rpc
+    }
+
+    pub async fn get_state(&mut self) -> Result<State, ClientError> {
+        let (address, _) = State::find_address(&self.program_id);
+        let data = self
+            .rpc
+            .get_account_data(&address)
+            .await?
+            .ok_or(ClientError::AccountNotFound(address))?;
+        State::unpack_account(&data).map_err(ClientError::InvalidAccountData)
+    }
+
+    pub async fn get_item(&mut self, item_id: u128) -> Result<MarketItem, ClientError> {
+        self.get_state()
+            .await?
+            .map
+            .remove(&item_id)
+            .ok_or(ClientError::ItemNotFound(item_id))
+    }
+
+    /// The items in `wallet`'s index of `kind`, none if it has no index yet.
+    pub async fn get_wallet_items(
+        &mut self,
+        wallet: &Pubkey,
+        kind: IndexKind,
+    ) -> Result<Vec<MarketItem>, ClientError> {
+        let (address, _) = WalletIndex::find_address(&self.program_id, wallet, kind);
+        let Some(data) = self.rpc.get_account_data(&address).await? else {
+            return Ok(vec![]);
+        };
+        let index = WalletIndex::unpack_account(&data).map_err(ClientError::InvalidAccountData)?;
+        let state = self.get_state().await?;
+        Ok(index.items(&state).cloned().collect())
+    }
+
+    /// Lists units held in the seller's associated token account and
+    /// returns the new item's id.
+    pub async fn list_item(
+        &mut self,
+        seller: &Keypair,
+        listing: &NewListing,
+    ) -> Result<u128, ClientError> {
+        let instruction = factory::create_market_item(
+            &self.program_id,
+            &seller.pubkey(),
+            &listing.mint,
+            &get_associated_token_address(&seller.pubkey(), &listing.mint),
+            listing.price,
+            listing.amount,
+            &listing.file_name,
+            &listing.description,
+        );
+        let listed_after = self.get_state().await?.item_ids;
+        self.send(&[instruction], &[seller]).await?;
+
+        // ids only grow, so the first listing of this mint by the seller past
+        // the counter read before sending is this one, even if other
+        // listings landed in between
+        let state = self.get_state().await?;
+        state
+            .map
+            .range(listed_after + 1..)
+            .map(|(_, item)| item)
+            .find(|item| item.seller == seller.pubkey() && item.mint_address == listing.mint)
+            .map(|item| item.item_id)
+            .ok_or(ClientError::Program(GachaError::ItemNotFound))
+    }
+
+    pub async fn buy(
+        &mut self,
+        buyer: &Keypair,
+        item_id: u128,
+        amount: u64,
+        referrer: Option<&Pubkey>,
+    ) -> Result<Signature, ClientError> {
+        let state = self.get_state().await?;
+        let item = state
+            .map
+            .get(&item_id)
+            .ok_or(ClientError::ItemNotFound(item_id))?;
+        let instructions = [
+            create_associated_token_account_idempotent(
+                &self.payer.pubkey(),
+                &buyer.pubkey(),
+                &item.mint_address,
+                &spl_token::id(),
+            ),
+            factory::purchase_sale(
+                &self.program_id,
+                &buyer.pubkey(),
+                &get_associated_token_address(&buyer.pubkey(), &item.mint_address),
+                &item.seller,
+                &state.treasury,
+                &item.mint_address,
+                item_id,
+                item.price,
+                amount,
+                referrer,
+            ),
+        ];
+        self.send(&instructions, &[buyer]).await
+    }
+
+    /// Draws `qty` gacha items priced at `price`, passing every eligible
+    /// item as a candidate so the program can pick any of them.
+    pub async fn draw_gacha(
+        &mut self,
+        buyer: &Keypair,
+        qty: u8,
+        price: u64,
+        referrer: Option<&Pubkey>,
+    ) -> Result<Signature, ClientError> {
+        let state = self.get_state().await?;
+        let candidates = gacha_candidates(&state, &buyer.pubkey(), price);
+        let mut instructions: Vec<Instruction> = candidates
+            .iter()
+            .map(|(_, mint, _)| {
+                create_associated_token_account_idempotent(
+                    &self.payer.pubkey(),
+                    &buyer.pubkey(),
+                    mint,
+                    &spl_token::id(),
+                )
+            })
+            .collect();
+        instructions.push(factory::gacha(
+            &self.program_id,
+            &buyer.pubkey(),
+            &state.treasury,
+            qty,
+            price,
+            referrer,
+            &candidates,
+        ));
+        self.send(&instructions, &[buyer]).await
+    }
+
+    /// Sends `instructions` signed by the payer and `signers`, decoding a
+    /// custom error raised by one of this program's instructions.
+    pub async fn send(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<Signature, ClientError> {
+        let mut all_signers = vec![&self.payer];
+        for signer in signers {
+            if !all_signers
+                .iter()
+                .any(|known| known.pubkey() == signer.pubkey())
+            {
+                all_signers.push(signer);
+            }
+        }
+        let blockhash = self.rpc.get_latest_blockhash().await?;
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.payer.pubkey()),
+            &all_signers,
+            blockhash,
+        );
+        let signature = transaction.signatures[0];
+
+        match self.rpc.send_transaction(transaction).await {
+            Err(ClientError::Transaction(TransactionError::InstructionError(index, err)))
+                if instructions
+                    .get(index as usize)
+                    .is_some_and(|instruction| instruction.program_id == self.program_id) =>
+            {
+                Err(GachaError::from_instruction_error(&err).map_or(
+                    ClientError::Transaction(TransactionError::InstructionError(index, err)),
+                    ClientError::Program,
+                ))
+            }
+            result => result.map(|()| signature),
+        }
+    }
+}
+
+/// `(seller, mint, buyer token account)` of every item a gacha draw at
+/// `price` may pick, in the form `factory::gacha` takes.
+pub fn gacha_candidates(
+    state: &State,
+    buyer: &Pubkey,
+    price: u64,
+) -> Vec<(Pubkey, Pubkey, Pubkey)> {
+    let mut candidates = vec![];
+    for item in state
+        .map
+        .values()
+        .filter(|item| item.gacha && !item.sold && item.remaining > 0 && item.price == price)
+    {
+        let candidate = (
+            item.seller,
+            item.mint_address,
+            get_associated_token_address(buyer, &item.mint_address),
+        );
+        if !candidates.contains(&candidate) {
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
@@ -1,8 +1,9 @@
 use solana_program::{
-    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, pubkey::Pubkey, msg,
+    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult,
+    program_error::PrintProgramError, pubkey::Pubkey,
 };
 
-use crate::processor::Processor;
+use crate::{error::GachaError, processor::Processor};
 
 entrypoint!(process_instruction);
 
@@ -11,5 +12,10 @@ pub fn process_instruction(
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    Processor::process(program_id, accounts, instruction_data)
+    if let Err(error) = Processor::process(program_id, accounts, instruction_data) {
+        // logs the message of a GachaError, or the builtin error's name
+        error.print::<GachaError>();
+        return Err(error);
+    }
+    Ok(())
 }
\ No newline at end of file
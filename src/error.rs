@@ -1,29 +1,153 @@
+use num_derive::FromPrimitive;
 use thiserror::Error;
+use num_traits::FromPrimitive as _;
 use solana_program::{
+    instruction::InstructionError,
     msg,
     program_error::{ProgramError, PrintProgramError},
     decode_error::DecodeError,
 };
-#[derive(Error, Debug, Copy, Clone)]
+/// Program errors, returned as `ProgramError::Custom(code)`. Codes are part
+/// of the interface: never renumber or reuse one. Retired variants keep
+/// their code and new ones are added at the end.
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+#[repr(u32)]
 pub enum GachaError {
     #[error("Price must be at least 1 lamports")]
-    InvalidPrice,
+    InvalidPrice = 0,
+    // cash back was replaced by loyalty points, the code is never reused
     #[error("Cash back should lower than 1")]
-    CashbackMax,
+    CashbackMax = 1,
     #[error("Please submit the asking price in order to complete the purchase")]
-    InvalidPayment,
+    InvalidPayment = 2,
     #[error("Invalid account")]
-    InvalidStateAccount,
+    InvalidStateAccount = 3,
     #[error("Account already has entry in Map")]
-    AccountAlreadyHasEntry,
+    AccountAlreadyHasEntry = 4,
     #[error("State already has been initialized")]
-    StateAlreadyInitialized,
+    StateAlreadyInitialized = 5,
+    #[error("Amount must be at least 1 unit")]
+    InvalidAmount = 6,
+    #[error("Not enough units remaining in the listing")]
+    InsufficientQuantity = 7,
+    #[error("Market item not found")]
+    ItemNotFound = 8,
+    #[error("Only the seller can perform this action")]
+    NotSeller = 9,
+    #[error("Seller account does not match the listing")]
+    InvalidSellerAccount = 10,
+    #[error("Owner account does not match the marketplace owner")]
+    InvalidOwnerAccount = 11,
+    #[error("Escrow token account does not match the listing")]
+    InvalidEscrowAccount = 12,
+    #[error("Not enough gacha items available")]
+    PoolEmpty = 13,
+    #[error("Accounts for a drawn gacha item were not supplied")]
+    MissingGachaAccounts = 14,
+    #[error("Mint does not match the expected mint")]
+    WrongMint = 15,
+    #[error("Too many items on one side of the swap")]
+    TooManySwapItems = 16,
+    #[error("Only the named counterparty can accept this swap")]
+    NotSwapCounterparty = 17,
+    #[error("Only the parties of a swap can cancel it")]
+    NotSwapParty = 18,
+    #[error("Swap account does not match the proposer and swap id")]
+    InvalidSwapAccount = 19,
+    #[error("Item is currently rented")]
+    RentalActive = 20,
+    #[error("Rental period is out of range")]
+    InvalidRentalPeriod = 21,
+    #[error("Rental account does not match the mint")]
+    InvalidRentalAccount = 22,
+    #[error("Only the owner can withdraw an item that is not rented out")]
+    NotRentalOwner = 23,
+    #[error("Only the marketplace owner can perform this action")]
+    NotMarketplaceOwner = 24,
+    #[error("Stake account does not match the mint")]
+    InvalidStakeAccount = 25,
+    #[error("Rarity tier is out of range")]
+    InvalidRarityTier = 26,
+    #[error("Only the staker can unstake or claim")]
+    NotStakeOwner = 27,
+    #[error("Loyalty account does not match the wallet")]
+    InvalidLoyaltyAccount = 28,
+    #[error("Too many collection multipliers configured")]
+    TooManyCollections = 29,
+    #[error("Referrer account is not registered for this wallet")]
+    InvalidReferrerAccount = 30,
+    #[error("Buyers cannot refer themselves")]
+    SelfReferral = 31,
+    #[error("Signer does not hold the required role")]
+    MissingRole = 32,
+    #[error("Multisig account or signer set is invalid")]
+    InvalidMultisig = 33,
+    #[error("Signer is not a member of the multisig")]
+    NotMultisigSigner = 34,
+    #[error("Proposal has not reached the approval threshold")]
+    ProposalNotApproved = 35,
+    #[error("Only the pending owner can accept ownership")]
+    NotPendingOwner = 36,
+    #[error("This feature is paused")]
+    Paused = 37,
+    #[error("Treasury account does not match the configured treasury")]
+    InvalidTreasuryAccount = 38,
+    #[error("Account is not owned by the expected program")]
+    InvalidAccountOwner = 39,
+    #[error("Account data is not the expected account type")]
+    InvalidDiscriminator = 40,
+    #[error("Account must be writable")]
+    AccountNotWritable = 41,
+    #[error("Token program must be the SPL token program")]
+    InvalidTokenProgram = 42,
+    #[error("Arithmetic overflow")]
+    MathOverflow = 43,
+    #[error("Account layout version is not supported")]
+    UnsupportedVersion = 44,
+    #[error("State account cannot grow to the required size")]
+    StateTooLarge = 45,
+    #[error("Wallet index account does not match the expected PDA")]
+    InvalidWalletIndexAccount = 46,
+    #[error("Market item has already sold out")]
+    ItemAlreadySold = 47,
+    #[error("Not enough loyalty points")]
+    InsufficientPoints = 48,
+    #[error("Collection account does not match the mint")]
+    InvalidCollectionAccount = 49,
+    #[error("Listing file name or description is too long")]
+    ListingTextTooLong = 50,
+    #[error("Item is in the gacha pool and can only be drawn")]
+    ItemInGachaPool = 51,
+    #[error("Mint has no rarity tier set")]
+    UnregisteredMint = 52,
+    #[error("Mint is not a non-fungible token")]
+    NotAnNft = 53,
+    #[error("Mint freeze authority must be the rental owner")]
+    InvalidFreezeAuthority = 54,
 }
 impl From<GachaError> for ProgramError {
     fn from(e: GachaError) -> Self {
         ProgramError::Custom(e as u32)
     }
 }
+impl GachaError {
+    /// The variant a failed instruction returned, if it is one of ours.
+    pub fn from_program_error(error: &ProgramError) -> Option<Self> {
+        match error {
+            ProgramError::Custom(code) => Self::from_u32(*code),
+            _ => None,
+        }
+    }
+
+    /// Like `from_program_error`, for the error a client gets back from
+    /// the runtime in `TransactionError::InstructionError`.
+    pub fn from_instruction_error(error: &InstructionError) -> Option<Self> {
+        match error {
+            InstructionError::Custom(code) => Self::from_u32(*code),
+            _ => None,
+        }
+    }
+}
 impl<T> DecodeError<T> for GachaError {
     fn type_of() -> &'static str {
         "Marketplace Error"
@@ -45,7 +169,56 @@ impl PrintProgramError for GachaError {
             GachaError::InvalidPayment => msg!("Error: Please submit the asking price in order to complete the purchase"),
             GachaError::InvalidStateAccount => msg!("Error: Invalid account"),
             GachaError::AccountAlreadyHasEntry => msg!("Error: Account already has entry in Map"),
-            GachaError::StateAlreadyInitialized => msg!("Error: State already has been initialized")
+            GachaError::StateAlreadyInitialized => msg!("Error: State already has been initialized"),
+            GachaError::InvalidAmount => msg!("Error: Amount must be at least 1 unit"),
+            GachaError::InsufficientQuantity => msg!("Error: Not enough units remaining in the listing"),
+            GachaError::ItemNotFound => msg!("Error: Market item not found"),
+            GachaError::NotSeller => msg!("Error: Only the seller can perform this action"),
+            GachaError::InvalidSellerAccount => msg!("Error: Seller account does not match the listing"),
+            GachaError::InvalidOwnerAccount => msg!("Error: Owner account does not match the marketplace owner"),
+            GachaError::InvalidEscrowAccount => msg!("Error: Escrow token account does not match the listing"),
+            GachaError::PoolEmpty => msg!("Error: Not enough gacha items available"),
+            GachaError::MissingGachaAccounts => msg!("Error: Accounts for a drawn gacha item were not supplied"),
+            GachaError::WrongMint => msg!("Error: Mint does not match the expected mint"),
+            GachaError::TooManySwapItems => msg!("Error: Too many items on one side of the swap"),
+            GachaError::NotSwapCounterparty => msg!("Error: Only the named counterparty can accept this swap"),
+            GachaError::NotSwapParty => msg!("Error: Only the parties of a swap can cancel it"),
+            GachaError::InvalidSwapAccount => msg!("Error: Swap account does not match the proposer and swap id"),
+            GachaError::RentalActive => msg!("Error: Item is currently rented"),
+            GachaError::InvalidRentalPeriod => msg!("Error: Rental period is out of range"),
+            GachaError::InvalidRentalAccount => msg!("Error: Rental account does not match the mint"),
+            GachaError::NotRentalOwner => msg!("Error: Only the owner can withdraw an item that is not rented out"),
+            GachaError::NotMarketplaceOwner => msg!("Error: Only the marketplace owner can perform this action"),
+            GachaError::InvalidStakeAccount => msg!("Error: Stake account does not match the mint"),
+            GachaError::InvalidRarityTier => msg!("Error: Rarity tier is out of range"),
+            GachaError::NotStakeOwner => msg!("Error: Only the staker can unstake or claim"),
+            GachaError::InvalidLoyaltyAccount => msg!("Error: Loyalty account does not match the wallet"),
+            GachaError::TooManyCollections => msg!("Error: Too many collection multipliers configured"),
+            GachaError::InvalidReferrerAccount => msg!("Error: Referrer account is not registered for this wallet"),
+            GachaError::SelfReferral => msg!("Error: Buyers cannot refer themselves"),
+            GachaError::MissingRole => msg!("Error: Signer does not hold the required role"),
+            GachaError::InvalidMultisig => msg!("Error: Multisig account or signer set is invalid"),
+            GachaError::NotMultisigSigner => msg!("Error: Signer is not a member of the multisig"),
+            GachaError::ProposalNotApproved => msg!("Error: Proposal has not reached the approval threshold"),
+            GachaError::NotPendingOwner => msg!("Error: Only the pending owner can accept ownership"),
+            GachaError::Paused => msg!("Error: This feature is paused"),
+            GachaError::InvalidTreasuryAccount => msg!("Error: Treasury account does not match the configured treasury"),
+            GachaError::InvalidAccountOwner => msg!("Error: Account is not owned by the expected program"),
+            GachaError::InvalidDiscriminator => msg!("Error: Account data is not the expected account type"),
+            GachaError::AccountNotWritable => msg!("Error: Account must be writable"),
+            GachaError::InvalidTokenProgram => msg!("Error: Token program must be the SPL token program"),
+            GachaError::MathOverflow => msg!("Error: Arithmetic overflow"),
+            GachaError::UnsupportedVersion => msg!("Error: Account layout version is not supported"),
+            GachaError::StateTooLarge => msg!("Error: State account cannot grow to the required size"),
+            GachaError::InvalidWalletIndexAccount => msg!("Error: Wallet index account does not match the expected PDA"),
+            GachaError::ItemAlreadySold => msg!("Error: Market item has already sold out"),
+            GachaError::InsufficientPoints => msg!("Error: Not enough loyalty points"),
+            GachaError::InvalidCollectionAccount => msg!("Error: Collection account does not match the mint"),
+            GachaError::ListingTextTooLong => msg!("Error: Listing file name or description is too long"),
+            GachaError::ItemInGachaPool => msg!("Error: Item is in the gacha pool and can only be drawn"),
+            GachaError::UnregisteredMint => msg!("Error: Mint has no rarity tier set"),
+            GachaError::NotAnNft => msg!("Error: Mint is not a non-fungible token"),
+            GachaError::InvalidFreezeAuthority => msg!("Error: Mint freeze authority must be the rental owner"),
         }
     }
-}
\ No newline at end of file
+}
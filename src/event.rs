@@ -0,0 +1,267 @@
+//! Machine readable events, one per state change, for indexers.
+//!
+//! Each event is logged with `sol_log_data` as `EVENT_DISCRIMINATOR`
+//! followed by the borsh encoding of the `MarketplaceEvent`, which shows up
+//! in the transaction logs as a `Program data: <base64>` line.
+
+#[cfg(feature = "idl")]
+use borsh::BorshSchema;
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{log::sol_log_data, pubkey::Pubkey};
+
+use crate::{instruction::LoyaltyReward, state::Role};
+
+/// The first 8 bytes of `sha256("event:MarketplaceEvent")`.
+pub const EVENT_DISCRIMINATOR: [u8; 8] = [62, 201, 30, 80, 28, 72, 23, 25];
+
+pub use wire::{ConfigChange, MarketplaceEvent};
+
+// The BorshSchema derive, only needed for the IDL, copies each variant's
+// fields into a struct that is never read, next to the enum it derives.
+#[cfg_attr(feature = "idl", allow(dead_code))]
+mod wire {
+    use super::*;
+
+    // Logged data is the borsh encoding of this enum, so the variant order is
+    // part of the wire format. It is frozen from this release onward, earlier
+    // logs may decode differently: only ever append new variants.
+    #[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "idl", derive(BorshSchema))]
+    pub enum MarketplaceEvent {
+        StateInitialized {
+            owner: Pubkey,
+            listing_price: u64,
+        },
+        ItemListed {
+            item_id: u128,
+            seller: Pubkey,
+            mint: Pubkey,
+            price: u64,
+            amount: u64,
+        },
+        ItemSold {
+            item_id: u128,
+            buyer: Pubkey,
+            seller: Pubkey,
+            price: u64,
+            amount: u64,
+        },
+        ListingCancelled {
+            item_id: u128,
+            seller: Pubkey,
+            returned: u64, // units sent back to the seller
+        },
+        GachaPoolFilled {
+            item_ids: Vec<u128>,
+        },
+        GachaDrawn {
+            item_id: u128,
+            buyer: Pubkey,
+            seller: Pubkey,
+            price: u64,
+        },
+        FeesCollected {
+            payer: Pubkey,
+            treasury: Pubkey,
+            amount: u64, // total fee, referral share included
+            referrer: Option<Pubkey>,
+            referral_amount: u64,
+        },
+        SwapProposed {
+            proposer: Pubkey,
+            counterparty: Pubkey,
+            swap_id: u64,
+        },
+        SwapAccepted {
+            proposer: Pubkey,
+            counterparty: Pubkey,
+            swap_id: u64,
+        },
+        SwapCancelled {
+            proposer: Pubkey,
+            swap_id: u64,
+            cancelled_by: Pubkey,
+        },
+        RentalCreated {
+            owner: Pubkey,
+            mint: Pubkey,
+            price_per_period: u64,
+            period_secs: i64,
+            max_periods: u32,
+        },
+        ItemRented {
+            mint: Pubkey,
+            renter: Pubkey,
+            paid: u64,
+            expires_at: i64,
+        },
+        RentalReclaimed {
+            owner: Pubkey,
+            mint: Pubkey,
+        },
+        StakePoolInitialized {
+            reward_mint: Pubkey,
+            emission_rates: Vec<u64>,
+        },
+        StakePoolFunded {
+            funder: Pubkey,
+            amount: u64,
+        },
+        EmissionRatesSet {
+            emission_rates: Vec<u64>,
+        },
+        RarityTierSet {
+            mint: Pubkey,
+            tier: u8,
+        },
+        NftStaked {
+            owner: Pubkey,
+            mint: Pubkey,
+            tier: u8,
+        },
+        NftUnstaked {
+            owner: Pubkey,
+            mint: Pubkey,
+        },
+        StakeRewardsPaid {
+            owner: Pubkey,
+            mint: Pubkey,
+            amount: u64,
+        },
+        LoyaltyConfigSet {
+            lamports_per_point: u64,
+            point_value: u64,
+            points_per_free_draw: u64,
+        },
+        CollectionMultiplierSet {
+            collection: Pubkey,
+            multiplier_bps: u16,
+        },
+        LoyaltyPointsRedeemed {
+            wallet: Pubkey,
+            points: u64,
+            reward: LoyaltyReward,
+        },
+        ReferrerRegistered {
+            wallet: Pubkey,
+        },
+        ReferralBpsSet {
+            referral_bps: u16,
+        },
+        ReferralEarningsClaimed {
+            wallet: Pubkey,
+            amount: u64,
+        },
+        RoleSet {
+            role: Role,
+            holder: Pubkey,
+        },
+        OwnershipTransferStarted {
+            owner: Pubkey,
+            pending_owner: Pubkey,
+        },
+        OwnershipTransferred {
+            previous_owner: Pubkey,
+            new_owner: Pubkey,
+        },
+        MultisigCreated {
+            multisig: Pubkey,
+            signers: Vec<Pubkey>,
+            threshold: u8,
+        },
+        MultisigActionProposed {
+            multisig: Pubkey,
+            index: u64,
+            proposer: Pubkey,
+        },
+        MultisigActionApproved {
+            multisig: Pubkey,
+            index: u64,
+            signer: Pubkey,
+        },
+        MultisigActionExecuted {
+            multisig: Pubkey,
+            index: u64,
+        },
+        PausedSet {
+            paused: u32,
+        },
+        ConfigUpdated {
+            change: ConfigChange,
+        },
+        StateMigrated {
+            from_version: u8,
+            to_version: u8,
+        },
+        MintCollectionSet {
+            mint: Pubkey,
+            collection: Option<Pubkey>,
+        },
+    }
+
+    /// One field changed by UpdateConfig, with its old and new value.
+    #[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "idl", derive(BorshSchema))]
+    pub enum ConfigChange {
+        ListingPrice { old: u64, new: u64 },
+        FeeBps { old: u16, new: u16 },
+        MaxGachaQty { old: u8, new: u8 },
+        Treasury { old: Pubkey, new: Pubkey },
+        GachaFee { old: u64, new: u64 },
+    }
+}
+
+impl MarketplaceEvent {
+    pub fn emit(&self) {
+        let mut data = EVENT_DISCRIMINATOR.to_vec();
+        // writing into a Vec cannot fail
+        self.serialize(&mut data).unwrap();
+        sol_log_data(&[&data]);
+    }
+
+    /// Decodes one `sol_log_data` field, if it holds an event.
+    pub fn from_log_data(data: &[u8]) -> Option<Self> {
+        let body = data.strip_prefix(&EVENT_DISCRIMINATOR[..])?;
+        Self::try_from_slice(body).ok()
+    }
+}
+
+/// Events emitted by `program_id`, in order, from a transaction's log
+/// messages. Data logged by other programs, including ones this program
+/// invokes, is skipped. A failed transaction's logs can still hold events
+/// of the changes it rolled back, so only pass logs of successful ones.
+#[cfg(feature = "client")]
+pub fn parse_logs(program_id: &Pubkey, logs: &[String]) -> Vec<MarketplaceEvent> {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let program = program_id.to_string();
+    // the program currently executing, innermost last
+    let mut stack: Vec<&str> = vec![];
+    let mut events = vec![];
+    for log in logs {
+        let Some(line) = log.strip_prefix("Program ") else {
+            continue;
+        };
+        if let Some(fields) = line.strip_prefix("data: ") {
+            if stack.last() != Some(&program.as_str()) {
+                continue;
+            }
+            for field in fields.split(' ') {
+                if let Some(event) = STANDARD
+                    .decode(field)
+                    .ok()
+                    .and_then(|data| MarketplaceEvent::from_log_data(&data))
+                {
+                    events.push(event);
+                }
+            }
+        } else if let Some((id, rest)) = line.split_once(' ') {
+            if rest.starts_with("invoke [") {
+                stack.push(id);
+            } else if rest == "success" || rest.starts_with("failed") {
+                stack.pop();
+            }
+        }
+    }
+    events
+}
@@ -0,0 +1,706 @@
+//! Generates an Anchor IDL (spec 0.1.0, as written by Anchor 0.30) from the
+//! borsh schemas of the instructions, accounts and events, so frontends can
+//! generate bindings instead of mirroring the types by hand.
+//!
+//! The program is not an Anchor program, which the IDL reflects through
+//! explicit discriminators: instructions start with their one byte variant
+//! index, accounts with `DISCRIMINATOR` followed by the layout version and
+//! events with `EVENT_DISCRIMINATOR`. Accounts that depend on the
+//! instruction arguments, like gacha candidates or swap items, follow the
+//! listed accounts and are described in the instruction's docs.
+
+use std::collections::{BTreeMap, HashMap};
+
+use borsh::{
+    schema::{Declaration, Definition, Fields},
+    BorshSchema,
+};
+use num_traits::FromPrimitive;
+use serde_json::{json, Value};
+
+use crate::{
+    error::GachaError,
+    event::{MarketplaceEvent, EVENT_DISCRIMINATOR},
+    instruction::GachaMarketplaceInstruction,
+    query::ItemPage,
+    state::{
+        LoyaltyAccount, MintCollection, Multisig, MultisigProposal, ProgramAccount, RarityTier,
+        ReferrerAccount, RentalListing, StakeEntry, StakePool, State, SwapOffer, WalletIndex,
+    },
+};
+
+/// One account an instruction takes, in order.
+pub struct IdlAccount {
+    pub name: &'static str,
+    pub writable: bool,
+    pub signer: bool,
+    pub optional: bool, // only passed when the matching argument is set
+}
+
+const fn account(name: &'static str) -> IdlAccount {
+    IdlAccount {
+        name,
+        writable: false,
+        signer: false,
+        optional: false,
+    }
+}
+
+const fn writable(name: &'static str) -> IdlAccount {
+    IdlAccount {
+        writable: true,
+        ..account(name)
+    }
+}
+
+const fn signer(name: &'static str) -> IdlAccount {
+    IdlAccount {
+        signer: true,
+        ..account(name)
+    }
+}
+
+const fn payer(name: &'static str) -> IdlAccount {
+    IdlAccount {
+        writable: true,
+        signer: true,
+        ..account(name)
+    }
+}
+
+const REFERRER: IdlAccount = IdlAccount {
+    writable: true,
+    optional: true,
+    ..account("referrer")
+};
+
+/// The accounts of each instruction, by variant name, and the accounts
+/// that follow them, if any. Mirrors the processor and the factory.
+pub const INSTRUCTION_ACCOUNTS: &[(&str, &[IdlAccount], Option<&str>)] = &[
+    (
+        "CreateMarketItem",
+        &[
+            payer("seller"),
+            writable("state"),
+            account("mint"),
+            writable("seller_token_account"),
+            writable("escrow"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+            writable("listed_index"),
+            account("collection"),
+        ],
+        None,
+    ),
+    (
+        "PurchaseSale",
+        &[
+            payer("buyer"),
+            writable("state"),
+            writable("seller"),
+            writable("treasury"),
+            account("mint"),
+            writable("escrow"),
+            writable("buyer_token_account"),
+            account("token_program"),
+            account("system_program"),
+            writable("loyalty"),
+            writable("owned_index"),
+            REFERRER,
+        ],
+        None,
+    ),
+    (
+        "CreateGacha",
+        &[
+            payer("authority"),
+            writable("state"),
+            writable("treasury"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "Gacha",
+        &[
+            payer("buyer"),
+            writable("state"),
+            writable("treasury"),
+            account("token_program"),
+            account("system_program"),
+            writable("loyalty"),
+            writable("owned_index"),
+            REFERRER,
+        ],
+        Some(
+            "Followed by seller (writable), mint, escrow (writable) and buyer token account \
+             (writable) for every item the draw may pick.",
+        ),
+    ),
+    (
+        "InitState",
+        &[
+            payer("authority"),
+            writable("state"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "CancelMarketItem",
+        &[
+            payer("seller"),
+            writable("state"),
+            account("mint"),
+            writable("escrow"),
+            writable("seller_token_account"),
+            account("token_program"),
+            writable("listed_index"),
+        ],
+        None,
+    ),
+    (
+        "ProposeSwap",
+        &[
+            payer("proposer"),
+            account("state"),
+            writable("swap"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+        ],
+        Some(
+            "Followed by mint, proposer token account (writable) and swap escrow (writable) \
+             for every offered mint.",
+        ),
+    ),
+    (
+        "AcceptSwap",
+        &[
+            payer("counterparty"),
+            account("state"),
+            writable("proposer"),
+            writable("swap"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+        ],
+        Some(
+            "Followed by mint, swap escrow (writable) and counterparty token account \
+             (writable) for every offered mint, then mint, counterparty token account \
+             (writable) and proposer token account (writable) for every requested mint.",
+        ),
+    ),
+    (
+        "CancelSwap",
+        &[
+            payer("authority"),
+            writable("proposer"),
+            writable("swap"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+        ],
+        Some(
+            "Followed by mint, swap escrow (writable) and proposer token account (writable) \
+             for every offered mint.",
+        ),
+    ),
+    (
+        "CreateRental",
+        &[
+            payer("owner"),
+            account("state"),
+            writable("rental"),
+            writable("mint"),
+            writable("owner_token_account"),
+            writable("vault"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "RentItem",
+        &[
+            payer("renter"),
+            account("state"),
+            writable("owner"),
+            writable("rental"),
+            account("mint"),
+            writable("vault"),
+            writable("renter_token_account"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+        ],
+        Some("Followed by the last renter's token account (writable) if it was rented before."),
+    ),
+    (
+        "ReclaimRental",
+        &[
+            signer("authority"),
+            writable("owner"),
+            writable("rental"),
+            writable("mint"),
+            writable("vault"),
+            writable("owner_token_account"),
+            account("token_program"),
+        ],
+        Some("Followed by the last renter's token account (writable) if it was rented."),
+    ),
+    (
+        "InitStakePool",
+        &[
+            payer("authority"),
+            account("state"),
+            writable("pool"),
+            account("reward_mint"),
+            writable("reward_vault"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "FundStakePool",
+        &[
+            signer("funder"),
+            account("pool"),
+            account("reward_mint"),
+            writable("funder_token_account"),
+            writable("reward_vault"),
+            account("token_program"),
+        ],
+        None,
+    ),
+    (
+        "SetEmissionRates",
+        &[signer("authority"), account("state"), writable("pool")],
+        None,
+    ),
+    (
+        "SetRarityTier",
+        &[
+            payer("authority"),
+            account("state"),
+            writable("rarity"),
+            account("mint"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "StakeNft",
+        &[
+            payer("owner"),
+            account("state"),
+            writable("pool"),
+            writable("entry"),
+            account("rarity"),
+            account("mint"),
+            writable("owner_token_account"),
+            writable("vault"),
+            account("token_program"),
+            account("associated_token_program"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "UnstakeNft",
+        &[
+            payer("owner"),
+            writable("pool"),
+            writable("entry"),
+            account("mint"),
+            writable("vault"),
+            writable("owner_token_account"),
+            account("reward_mint"),
+            writable("reward_vault"),
+            writable("reward_account"),
+            account("token_program"),
+        ],
+        None,
+    ),
+    (
+        "ClaimStakeRewards",
+        &[
+            signer("owner"),
+            writable("pool"),
+            writable("entry"),
+            account("reward_mint"),
+            writable("reward_vault"),
+            writable("reward_account"),
+            account("token_program"),
+        ],
+        None,
+    ),
+    (
+        "SetLoyaltyConfig",
+        &[signer("authority"), writable("state")],
+        None,
+    ),
+    (
+        "SetCollectionMultiplier",
+        &[
+            payer("authority"),
+            writable("state"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "RedeemLoyaltyPoints",
+        &[signer("wallet"), account("state"), writable("loyalty")],
+        None,
+    ),
+    (
+        "RegisterReferrer",
+        &[
+            payer("wallet"),
+            account("state"),
+            writable("referrer"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "SetReferralBps",
+        &[signer("authority"), writable("state")],
+        None,
+    ),
+    (
+        "ClaimReferralEarnings",
+        &[payer("wallet"), writable("referrer")],
+        None,
+    ),
+    ("SetRole", &[signer("authority"), writable("state")], None),
+    (
+        "TransferOwnership",
+        &[signer("authority"), writable("state")],
+        None,
+    ),
+    (
+        "AcceptOwnership",
+        &[signer("new_owner"), writable("state")],
+        None,
+    ),
+    (
+        "CreateMultisig",
+        &[
+            payer("payer"),
+            writable("multisig"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "ProposeMultisigAction",
+        &[
+            payer("proposer"),
+            writable("multisig"),
+            writable("proposal"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    (
+        "ApproveMultisigAction",
+        &[signer("signer"), account("multisig"), writable("proposal")],
+        None,
+    ),
+    (
+        "ExecuteMultisigAction",
+        &[
+            signer("executor"),
+            account("multisig"),
+            writable("proposal"),
+            writable("proposer"),
+            account("program"),
+        ],
+        Some(
+            "Followed by the accounts of the proposed instruction, where the multisig does \
+             not sign.",
+        ),
+    ),
+    ("SetPaused", &[signer("authority"), writable("state")], None),
+    (
+        "UpdateConfig",
+        &[signer("authority"), writable("state")],
+        None,
+    ),
+    (
+        "MigrateState",
+        &[
+            payer("upgrade_authority"),
+            writable("state"),
+            account("system_program"),
+        ],
+        None,
+    ),
+    ("FetchMarketItems", &[account("state")], None),
+    ("FetchMyNFTs", &[account("state")], None),
+    ("FetchItemsCreated", &[account("state")], None),
+    (
+        "SetMintCollection",
+        &[
+            payer("authority"),
+            account("state"),
+            writable("collection"),
+            account("mint"),
+            account("system_program"),
+        ],
+        None,
+    ),
+];
+
+/// The IDL of the program deployed at `address`.
+pub fn generate(address: &str) -> Value {
+    let mut types = TypeCollector::default();
+
+    let instruction_schema = GachaMarketplaceInstruction::schema_container();
+    let Some(Definition::Enum { variants }) = instruction_schema
+        .definitions
+        .get(&instruction_schema.declaration)
+    else {
+        unreachable!("instructions are an enum");
+    };
+    let instructions: Vec<Value> = variants
+        .iter()
+        .enumerate()
+        .map(|(index, (name, declaration))| {
+            let (accounts, docs) = INSTRUCTION_ACCOUNTS
+                .iter()
+                .find(|(variant, _, _)| variant == name)
+                .map(|(_, accounts, docs)| (*accounts, *docs))
+                .unwrap_or_else(|| panic!("no accounts listed for {name}"));
+            let mut instruction = json!({
+                "name": snake_case(name),
+                "discriminator": [index],
+                "accounts": accounts.iter().map(account_json).collect::<Vec<_>>(),
+                "args": types.fields(&instruction_schema.definitions, declaration),
+            });
+            if let Some(docs) = docs {
+                instruction["docs"] = json!([docs]);
+            }
+            instruction
+        })
+        .collect();
+
+    let mut accounts = vec![];
+    macro_rules! add_accounts {
+        ($($account:ty),*) => {
+            $({
+                let schema = <$account>::schema_container();
+                types.add(&schema.definitions, &schema.declaration);
+                accounts.push(json!({
+                    "name": schema.declaration,
+                    "discriminator": account_discriminator::<$account>(),
+                }));
+            })*
+        };
+    }
+    add_accounts!(
+        State,
+        Multisig,
+        MultisigProposal,
+        LoyaltyAccount,
+        ReferrerAccount,
+        SwapOffer,
+        RentalListing,
+        StakePool,
+        RarityTier,
+        MintCollection,
+        StakeEntry,
+        WalletIndex
+    );
+
+    let event_schema = MarketplaceEvent::schema_container();
+    types.add(&event_schema.definitions, &event_schema.declaration);
+    // returned by the Fetch* instructions
+    let page_schema = ItemPage::schema_container();
+    types.add(&page_schema.definitions, &page_schema.declaration);
+
+    let errors: Vec<Value> = (0..)
+        .map_while(GachaError::from_u32)
+        .map(|error| {
+            json!({
+                "code": error as u32,
+                "name": format!("{error:?}"),
+                "msg": error.to_string(),
+            })
+        })
+        .collect();
+
+    json!({
+        "address": address,
+        "metadata": {
+            "name": env!("CARGO_PKG_NAME").replace('-', "_"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "spec": "0.1.0",
+        },
+        "instructions": instructions,
+        "accounts": accounts,
+        "events": [{
+            "name": event_schema.declaration,
+            "discriminator": EVENT_DISCRIMINATOR,
+        }],
+        "errors": errors,
+        "types": types.types.into_values().collect::<Vec<_>>(),
+    })
+}
+
+fn account_discriminator<T: ProgramAccount>() -> Vec<u8> {
+    let mut discriminator = T::DISCRIMINATOR.to_vec();
+    discriminator.push(T::VERSION);
+    discriminator
+}
+
+fn account_json(account: &IdlAccount) -> Value {
+    let mut value = json!({ "name": account.name });
+    for (flag, set) in [
+        ("writable", account.writable),
+        ("signer", account.signer),
+        ("optional", account.optional),
+    ] {
+        if set {
+            value[flag] = json!(true);
+        }
+    }
+    value
+}
+
+/// Named types referenced so far, by name, as IDL type definitions.
+#[derive(Default)]
+struct TypeCollector {
+    types: BTreeMap<String, Value>,
+}
+
+impl TypeCollector {
+    /// The IDL type of `declaration`, adding the named types it uses.
+    fn idl_type(
+        &mut self,
+        definitions: &HashMap<Declaration, Definition>,
+        declaration: &Declaration,
+    ) -> Value {
+        match declaration.as_str() {
+            "Pubkey" => return json!("pubkey"),
+            "Vec<u8>" => return json!("bytes"),
+            primitive if definitions.get(primitive).is_none() => return json!(primitive),
+            _ => {}
+        }
+        match &definitions[declaration] {
+            Definition::Enum { variants } if declaration.starts_with("Option<") => {
+                json!({ "option": self.idl_type(definitions, &variants[1].1) })
+            }
+            Definition::Array { length, elements } => {
+                json!({ "array": [self.idl_type(definitions, elements), length] })
+            }
+            Definition::Sequence { elements } => {
+                json!({ "vec": self.idl_type(definitions, elements) })
+            }
+            _ => {
+                self.add(definitions, declaration);
+                json!({ "defined": { "name": type_name(declaration) } })
+            }
+        }
+    }
+
+    /// Adds the named type `declaration` and everything it references.
+    fn add(&mut self, definitions: &HashMap<Declaration, Definition>, declaration: &Declaration) {
+        let name = type_name(declaration);
+        if self.types.contains_key(&name) {
+            return;
+        }
+        // reserve the name first so recursive types terminate
+        self.types.insert(name.clone(), Value::Null);
+        let ty = match &definitions[declaration] {
+            Definition::Enum { variants } => {
+                let variants: Vec<Value> = variants
+                    .iter()
+                    .map(|(variant, fields)| {
+                        let mut value = json!({ "name": variant });
+                        let fields = self.fields(definitions, fields);
+                        if fields.as_array().is_some_and(|fields| !fields.is_empty()) {
+                            value["fields"] = fields;
+                        }
+                        value
+                    })
+                    .collect();
+                json!({ "kind": "enum", "variants": variants })
+            }
+            Definition::Tuple { elements } => {
+                let fields: Vec<Value> = elements
+                    .iter()
+                    .map(|element| self.idl_type(definitions, element))
+                    .collect();
+                json!({ "kind": "struct", "fields": fields })
+            }
+            _ => json!({ "kind": "struct", "fields": self.fields(definitions, declaration) }),
+        };
+        self.types
+            .insert(name.clone(), json!({ "name": name, "type": ty }));
+    }
+
+    /// The fields of the struct `declaration`, named or positional.
+    fn fields(
+        &mut self,
+        definitions: &HashMap<Declaration, Definition>,
+        declaration: &Declaration,
+    ) -> Value {
+        match definitions.get(declaration) {
+            Some(Definition::Struct {
+                fields: Fields::NamedFields(fields),
+            }) => fields
+                .iter()
+                .map(|(name, field)| {
+                    json!({ "name": name, "type": self.idl_type(definitions, field) })
+                })
+                .collect(),
+            Some(Definition::Struct {
+                fields: Fields::UnnamedFields(fields),
+            }) => fields
+                .iter()
+                .map(|field| self.idl_type(definitions, field))
+                .collect(),
+            _ => Value::Array(vec![]),
+        }
+    }
+}
+
+/// A valid identifier for a declaration like `Tuple<u128, MarketItem>`.
+fn type_name(declaration: &str) -> String {
+    let mut name = String::new();
+    let mut upper = true;
+    for c in declaration.chars() {
+        if c.is_ascii_alphanumeric() {
+            if upper {
+                name.extend(c.to_uppercase());
+            } else {
+                name.push(c);
+            }
+            upper = false;
+        } else {
+            upper = true;
+        }
+    }
+    name
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        // only after a lowercase letter, so FetchMyNFTs becomes fetch_my_nfts
+        if c.is_uppercase() && previous_lower {
+            snake.push('_');
+        }
+        previous_lower = c.is_lowercase();
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
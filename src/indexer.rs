@@ -0,0 +1,533 @@
+//! Builds a SQLite database of listings, sales, gacha draws and per-wallet
+//! history from the program's event logs and state snapshots.
+//!
+//! Transactions are applied at most once, keyed by signature, so the same
+//! input can be replayed into an existing database. A fixture directory
+//! holds `<slot>-<signature>.log` files, one log message per line, and
+//! `<name>.state` files with raw state account data; they are applied in
+//! file name order.
+
+use std::{error::Error, fs, path::Path};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use solana_program::pubkey::Pubkey;
+use solana_rpc_client::rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient};
+use solana_rpc_client_api::config::RpcTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_transaction_status::UiTransactionEncoding;
+
+use crate::{
+    event::{self, MarketplaceEvent},
+    state::{MarketItem, ProgramAccount, State},
+};
+
+pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS processed (
+        signature TEXT PRIMARY KEY,
+        slot INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS listings (
+        item_id INTEGER PRIMARY KEY,
+        seller TEXT NOT NULL,
+        mint TEXT NOT NULL,
+        price INTEGER NOT NULL,
+        amount INTEGER NOT NULL,
+        remaining INTEGER NOT NULL,
+        owner TEXT,
+        gacha INTEGER NOT NULL DEFAULT 0,
+        cancelled INTEGER NOT NULL DEFAULT 0,
+        file_name TEXT,
+        description TEXT,
+        collection TEXT
+    );
+    CREATE TABLE IF NOT EXISTS sales (
+        signature TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        item_id INTEGER NOT NULL,
+        buyer TEXT NOT NULL,
+        seller TEXT NOT NULL,
+        price INTEGER NOT NULL,
+        amount INTEGER NOT NULL,
+        PRIMARY KEY (signature, seq)
+    );
+    CREATE TABLE IF NOT EXISTS gacha_draws (
+        signature TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        item_id INTEGER NOT NULL,
+        buyer TEXT NOT NULL,
+        seller TEXT NOT NULL,
+        price INTEGER NOT NULL,
+        PRIMARY KEY (signature, seq)
+    );
+    CREATE TABLE IF NOT EXISTS wallet_history (
+        wallet TEXT NOT NULL,
+        signature TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        slot INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        item_id INTEGER,
+        amount INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS wallet_history_wallet ON wallet_history (wallet, slot);
+";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listing {
+    pub item_id: u128,
+    pub seller: Pubkey,
+    pub mint: Pubkey,
+    pub price: u64,
+    pub amount: u64,
+    pub remaining: u64,
+    pub owner: Option<Pubkey>,
+    pub gacha: bool,
+    pub cancelled: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub signature: String,
+    pub slot: u64,
+    pub kind: String,
+    pub item_id: Option<u128>,
+    pub amount: Option<u64>,
+}
+
+pub struct Indexer {
+    conn: Connection,
+    program_id: Pubkey,
+}
+
+impl Indexer {
+    pub fn open(path: impl AsRef<Path>, program_id: Pubkey) -> Result<Self> {
+        Self::new(Connection::open(path)?, program_id)
+    }
+
+    pub fn open_in_memory(program_id: Pubkey) -> Result<Self> {
+        Self::new(Connection::open_in_memory()?, program_id)
+    }
+
+    fn new(conn: Connection, program_id: Pubkey) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn, program_id })
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// The most recently applied signature, where an RPC sync resumes.
+    pub fn last_signature(&self) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT signature FROM processed ORDER BY slot DESC, rowid DESC LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Applies the events in one successful transaction's logs. Returns
+    /// false if the signature was already applied.
+    pub fn apply_transaction(
+        &mut self,
+        signature: &str,
+        slot: u64,
+        logs: &[String],
+    ) -> Result<bool> {
+        let events = event::parse_logs(&self.program_id, logs);
+        let tx = self.conn.transaction()?;
+        let inserted = tx.execute(
+            "INSERT OR IGNORE INTO processed (signature, slot) VALUES (?1, ?2)",
+            params![signature, int(slot)?],
+        )?;
+        if inserted == 0 {
+            return Ok(false);
+        }
+        for (seq, event) in events.iter().enumerate() {
+            apply_event(&tx, signature, slot, seq, event)?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Reconciles listings with a state account snapshot, which is
+    /// authoritative: items missing from its map were cancelled.
+    pub fn apply_state_snapshot(&mut self, data: &[u8]) -> Result<()> {
+        let state = State::unpack_account(data)?;
+        let tx = self.conn.transaction()?;
+        for item in state.map.values() {
+            upsert_item(&tx, item)?;
+        }
+        let listed: Vec<i64> = state.map.keys().map(|id| int(*id)).collect::<Result<_>>()?;
+        let mut stale = tx.prepare("SELECT item_id FROM listings WHERE cancelled = 0")?;
+        let ids: Vec<i64> = stale
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        drop(stale);
+        for id in ids.into_iter().filter(|id| !listed.contains(id)) {
+            tx.execute("UPDATE listings SET cancelled = 1 WHERE item_id = ?1", [id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Applies every fixture in `dir` in file name order, returning how
+    /// many files were read.
+    pub fn replay_dir(&mut self, dir: impl AsRef<Path>) -> Result<usize> {
+        let mut paths: Vec<_> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()?;
+        paths.sort();
+
+        let mut applied = 0;
+        for path in paths {
+            let stem = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("log") => {
+                    let (slot, signature) = stem.split_once('-').ok_or_else(|| {
+                        format!("{} is not <slot>-<signature>.log", path.display())
+                    })?;
+                    let logs: Vec<String> = fs::read_to_string(&path)?
+                        .lines()
+                        .map(str::to_string)
+                        .collect();
+                    self.apply_transaction(signature, slot.parse()?, &logs)?;
+                }
+                Some("state") => self.apply_state_snapshot(&fs::read(&path)?)?,
+                _ => continue,
+            }
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Applies the program's transactions newer than the last applied one,
+    /// oldest first, then reconciles with the current state account.
+    /// Returns how many transactions were applied.
+    pub fn sync_rpc(&mut self, rpc: &RpcClient) -> Result<usize> {
+        let until = self
+            .last_signature()?
+            .map(|signature| signature.parse())
+            .transpose()?;
+        let mut pending = vec![];
+        let mut before = None;
+        loop {
+            let page = rpc.get_signatures_for_address_with_config(
+                &self.program_id,
+                GetConfirmedSignaturesForAddress2Config {
+                    before,
+                    until,
+                    limit: None,
+                    commitment: Some(CommitmentConfig::confirmed()),
+                },
+            )?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            before = Some(last.signature.parse()?);
+            pending.extend(page.into_iter().filter(|status| status.err.is_none()));
+        }
+
+        let mut applied = 0;
+        for status in pending.iter().rev() {
+            let transaction = rpc.get_transaction_with_config(
+                &status.signature.parse()?,
+                RpcTransactionConfig {
+                    encoding: Some(UiTransactionEncoding::Base64),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                    max_supported_transaction_version: Some(0),
+                },
+            )?;
+            let logs = transaction
+                .transaction
+                .meta
+                .and_then(|meta| Option::<Vec<String>>::from(meta.log_messages))
+                .unwrap_or_default();
+            if self.apply_transaction(&status.signature, status.slot, &logs)? {
+                applied += 1;
+            }
+        }
+
+        let (state_address, _) = State::find_address(&self.program_id);
+        self.apply_state_snapshot(&rpc.get_account_data(&state_address)?)?;
+        Ok(applied)
+    }
+
+    pub fn listings(&self, unsold_only: bool) -> Result<Vec<Listing>> {
+        let mut statement = self.conn.prepare(
+            "SELECT item_id, seller, mint, price, amount, remaining, owner, gacha, cancelled
+             FROM listings WHERE ?1 = 0 OR (remaining > 0 AND cancelled = 0)
+             ORDER BY item_id",
+        )?;
+        let rows = statement.query_map([unsold_only], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, i64>(5)?,
+                row.get::<_, Option<String>>(6)?,
+                row.get::<_, bool>(7)?,
+                row.get::<_, bool>(8)?,
+            ))
+        })?;
+        rows.map(|row| {
+            let (item_id, seller, mint, price, amount, remaining, owner, gacha, cancelled) = row?;
+            Ok(Listing {
+                item_id: item_id.try_into()?,
+                seller: seller.parse()?,
+                mint: mint.parse()?,
+                price: price.try_into()?,
+                amount: amount.try_into()?,
+                remaining: remaining.try_into()?,
+                owner: owner.map(|owner| owner.parse()).transpose()?,
+                gacha,
+                cancelled,
+            })
+        })
+        .collect()
+    }
+
+    /// Everything that happened to `wallet`, oldest first.
+    pub fn wallet_history(&self, wallet: &Pubkey) -> Result<Vec<HistoryEntry>> {
+        let mut statement = self.conn.prepare(
+            "SELECT signature, slot, kind, item_id, amount FROM wallet_history
+             WHERE wallet = ?1 ORDER BY slot, rowid",
+        )?;
+        let rows = statement.query_map([wallet.to_string()], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        })?;
+        rows.map(|row| {
+            let (signature, slot, kind, item_id, amount) = row?;
+            Ok(HistoryEntry {
+                signature,
+                slot: slot.try_into()?,
+                kind,
+                item_id: item_id.map(u128::try_from).transpose()?,
+                amount: amount.map(u64::try_from).transpose()?,
+            })
+        })
+        .collect()
+    }
+}
+
+fn apply_event(
+    tx: &Transaction,
+    signature: &str,
+    slot: u64,
+    seq: usize,
+    event: &MarketplaceEvent,
+) -> Result<()> {
+    let seq = int(seq)?;
+    let history = |wallet: &Pubkey, kind: &str, item_id: Option<u128>, amount: Option<u64>| {
+        tx.execute(
+            "INSERT INTO wallet_history (wallet, signature, seq, slot, kind, item_id, amount)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                wallet.to_string(),
+                signature,
+                seq,
+                int(slot)?,
+                kind,
+                item_id.map(int).transpose()?,
+                amount.map(int).transpose()?,
+            ],
+        )?;
+        Ok::<_, Box<dyn Error>>(())
+    };
+
+    match event {
+        MarketplaceEvent::ItemListed {
+            item_id,
+            seller,
+            mint,
+            price,
+            amount,
+        } => {
+            tx.execute(
+                "INSERT OR REPLACE INTO listings (item_id, seller, mint, price, amount, remaining)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+                params![
+                    int(*item_id)?,
+                    seller.to_string(),
+                    mint.to_string(),
+                    int(*price)?,
+                    int(*amount)?,
+                ],
+            )?;
+            history(seller, "listed", Some(*item_id), Some(*amount))?;
+        }
+        MarketplaceEvent::ItemSold {
+            item_id,
+            buyer,
+            seller,
+            price,
+            amount,
+        } => {
+            tx.execute(
+                "INSERT INTO sales (signature, seq, item_id, buyer, seller, price, amount)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    signature,
+                    seq,
+                    int(*item_id)?,
+                    buyer.to_string(),
+                    seller.to_string(),
+                    int(*price)?,
+                    int(*amount)?,
+                ],
+            )?;
+            take_units(tx, *item_id, buyer, *amount)?;
+            history(buyer, "bought", Some(*item_id), Some(*amount))?;
+            history(seller, "sold", Some(*item_id), Some(*amount))?;
+        }
+        MarketplaceEvent::GachaDrawn {
+            item_id,
+            buyer,
+            seller,
+            price,
+        } => {
+            tx.execute(
+                "INSERT INTO gacha_draws (signature, seq, item_id, buyer, seller, price)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    signature,
+                    seq,
+                    int(*item_id)?,
+                    buyer.to_string(),
+                    seller.to_string(),
+                    int(*price)?,
+                ],
+            )?;
+            take_units(tx, *item_id, buyer, 1)?;
+            history(buyer, "drew", Some(*item_id), Some(1))?;
+            history(seller, "sold", Some(*item_id), Some(1))?;
+        }
+        MarketplaceEvent::ListingCancelled {
+            item_id,
+            seller,
+            returned,
+        } => {
+            tx.execute(
+                "UPDATE listings SET cancelled = 1 WHERE item_id = ?1",
+                [int(*item_id)?],
+            )?;
+            history(seller, "cancelled", Some(*item_id), Some(*returned))?;
+        }
+        MarketplaceEvent::GachaPoolFilled { item_ids } => {
+            for item_id in item_ids {
+                tx.execute(
+                    "UPDATE listings SET gacha = 1 WHERE item_id = ?1",
+                    [int(*item_id)?],
+                )?;
+            }
+        }
+        MarketplaceEvent::FeesCollected {
+            payer,
+            amount,
+            referrer,
+            referral_amount,
+            ..
+        } => {
+            history(payer, "fee_paid", None, Some(*amount))?;
+            if let Some(referrer) = referrer {
+                history(referrer, "referral_earned", None, Some(*referral_amount))?;
+            }
+        }
+        MarketplaceEvent::SwapProposed {
+            proposer,
+            counterparty,
+            ..
+        } => {
+            history(proposer, "swap_proposed", None, None)?;
+            history(counterparty, "swap_offered", None, None)?;
+        }
+        MarketplaceEvent::SwapAccepted {
+            proposer,
+            counterparty,
+            ..
+        } => {
+            history(proposer, "swapped", None, None)?;
+            history(counterparty, "swapped", None, None)?;
+        }
+        MarketplaceEvent::SwapCancelled { proposer, .. } => {
+            history(proposer, "swap_cancelled", None, None)?;
+        }
+        MarketplaceEvent::ItemRented { renter, paid, .. } => {
+            history(renter, "rented", None, Some(*paid))?;
+        }
+        MarketplaceEvent::NftStaked { owner, .. } => history(owner, "staked", None, None)?,
+        MarketplaceEvent::NftUnstaked { owner, .. } => history(owner, "unstaked", None, None)?,
+        MarketplaceEvent::StakeRewardsPaid { owner, amount, .. } => {
+            history(owner, "stake_rewards", None, Some(*amount))?;
+        }
+        MarketplaceEvent::LoyaltyPointsRedeemed { wallet, points, .. } => {
+            history(wallet, "points_redeemed", None, Some(*points))?;
+        }
+        MarketplaceEvent::ReferralEarningsClaimed { wallet, amount } => {
+            history(wallet, "referral_claimed", None, Some(*amount))?;
+        }
+        // configuration and governance events do not touch the tables
+        _ => {}
+    }
+    Ok(())
+}
+
+fn take_units(tx: &Transaction, item_id: u128, buyer: &Pubkey, amount: u64) -> Result<()> {
+    tx.execute(
+        "UPDATE listings SET remaining = MAX(remaining - ?2, 0),
+             owner = CASE WHEN amount = 1 AND remaining <= ?2 THEN ?3 ELSE owner END
+         WHERE item_id = ?1",
+        params![int(item_id)?, int(amount)?, buyer.to_string()],
+    )?;
+    Ok(())
+}
+
+fn upsert_item(tx: &Transaction, item: &MarketItem) -> Result<()> {
+    tx.execute(
+        "INSERT INTO listings
+             (item_id, seller, mint, price, amount, remaining, owner, gacha, cancelled,
+              file_name, description, collection)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 0, ?9, ?10, ?11)
+         ON CONFLICT (item_id) DO UPDATE SET
+             price = excluded.price, remaining = excluded.remaining, owner = excluded.owner,
+             gacha = excluded.gacha, cancelled = 0, file_name = excluded.file_name,
+             description = excluded.description, collection = excluded.collection",
+        params![
+            int(item.item_id)?,
+            item.seller.to_string(),
+            item.mint_address.to_string(),
+            int(item.price)?,
+            int(item.amount)?,
+            int(item.remaining)?,
+            item.owner.map(|owner| owner.to_string()),
+            item.gacha,
+            item.file_name,
+            item.description,
+            item.collection.map(|collection| collection.to_string()),
+        ],
+    )?;
+    Ok(())
+}
+
+/// SQLite integers are signed 64 bit.
+fn int<T: TryInto<i64>>(value: T) -> Result<i64>
+where
+    T::Error: Error + 'static,
+{
+    Ok(value.try_into()?)
+}
@@ -1,103 +1,189 @@
 #[cfg(feature = "client")]
 pub mod factory;
 
-use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
-use solana_program::borsh::try_from_slice_unchecked;
-use solana_program::instruction::{AccountMeta, Instruction};
+#[cfg(feature = "idl")]
+use borsh::BorshSchema;
+use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::program_error::ProgramError;
 use solana_program::pubkey::Pubkey;
 
-// NOTE could hold a reference to description and metadata args
-// to avoid cloning them, in the factory, but performance is not
-// crucial in that part of the code.
-#[allow(clippy::large_enum_variant)]
-#[repr(C)]
-#[derive(BorshDeserialize, BorshSerialize, Debug)]
-pub enum GachaMarketplaceInstruction {
-    CreateMarketItem {
-        token_program_id: Pubkey, // program id,
-        mint_address: Pubkey,     // ATA
-        price: u128,
-        file_name: String,
-        description: String,
-        cash_back: u8,
-    },
-    PurchaseSale {
-        token_program_id: Pubkey, // program id
-        price: u128,
-        item_id: u128,
-    },
-    Gacha {
-        token_program_id: Pubkey,
-        qty: u8,
-        price: u128,
-        fee: u128,
-    },
-    CreateGacha {
-        token_program_id: Pubkey,
-        qty: u8,
-    },
-    InitState {
-        listing_price: u128
-    },
-    // FetchMarketItems {},
-    // FetchMyNFTs {},
-    // FetchItemsCreated {}
-}
+use crate::query::{ItemFilter, Page};
+use crate::state::Role;
+
+pub use wire::{GachaMarketplaceInstruction, LoyaltyReward};
+
+// The BorshSchema derive, only needed for the IDL, copies each variant's
+// fields into a struct that is never read, next to the enum it derives.
+#[cfg_attr(feature = "idl", allow(dead_code))]
+mod wire {
+    use super::*;
+
+    // NOTE could hold a reference to description and metadata args
+    // to avoid cloning them, in the factory, but performance is not
+    // crucial in that part of the code.
+    //
+    // Instruction data is the borsh encoding of this enum, so the variant
+    // order is part of the wire format. Releases up to this one reordered and
+    // removed variants; the order is frozen from this release onward, so only
+    // ever append new variants.
+    #[allow(clippy::large_enum_variant)]
+    #[repr(C)]
+    #[derive(BorshDeserialize, BorshSerialize, Debug)]
+    #[cfg_attr(feature = "idl", derive(BorshSchema))]
+    pub enum GachaMarketplaceInstruction {
+        CreateMarketItem {
+            token_program_id: Pubkey, // program id,
+            mint_address: Pubkey,
+            price: u64, // lamports per unit
+            file_name: String,
+            description: String,
+            amount: u64, // units to escrow
+        },
+        PurchaseSale {
+            token_program_id: Pubkey, // program id
+            price: u64, // lamports per unit
+            item_id: u128,
+            amount: u64, // units to buy
+            referrer: Option<Pubkey>,
+        },
+        CreateGacha {
+            token_program_id: Pubkey,
+            qty: u8,
+        },
+        Gacha {
+            token_program_id: Pubkey,
+            qty: u8,
+            price: u64,
+            referrer: Option<Pubkey>,
+        },
+        InitState {
+            listing_price: u64
+        },
+        CancelMarketItem {
+            token_program_id: Pubkey,
+            item_id: u128,
+        },
+        ProposeSwap {
+            swap_id: u64,
+            counterparty: Pubkey,
+            offered_mints: Vec<Pubkey>,
+            offered_lamports: u64,
+            requested_mints: Vec<Pubkey>,
+            requested_lamports: u64,
+        },
+        AcceptSwap {
+            proposer: Pubkey,
+            swap_id: u64,
+        },
+        CancelSwap {
+            proposer: Pubkey,
+            swap_id: u64,
+        },
+        CreateRental {
+            price_per_period: u64, // lamports
+            period_secs: i64,
+            max_periods: u32,
+        },
+        RentItem {
+            periods: u32,
+        },
+        ReclaimRental {},
+        InitStakePool {
+            emission_rates: Vec<u64>, // reward units per second, indexed by tier
+        },
+        FundStakePool {
+            amount: u64,
+        },
+        SetEmissionRates {
+            emission_rates: Vec<u64>,
+        },
+        SetRarityTier {
+            tier: u8,
+        },
+        StakeNft {},
+        UnstakeNft {},
+        ClaimStakeRewards {},
+        SetLoyaltyConfig {
+            lamports_per_point: u64,
+            point_value: u64,
+            points_per_free_draw: u64,
+        },
+        SetCollectionMultiplier {
+            collection: Pubkey,
+            multiplier_bps: u16, // 10_000 resets to the base rate
+        },
+        RedeemLoyaltyPoints {
+            reward: LoyaltyReward,
+        },
+        RegisterReferrer {},
+        SetReferralBps {
+            referral_bps: u16,
+        },
+        ClaimReferralEarnings {},
+        SetRole {
+            role: Role,
+            holder: Pubkey,
+        },
+        TransferOwnership {
+            new_owner: Pubkey,
+        },
+        AcceptOwnership {},
+        CreateMultisig {
+            seed: Pubkey, // any unique key, picks the multisig address
+            signers: Vec<Pubkey>,
+            threshold: u8,
+        },
+        ProposeMultisigAction {
+            accounts: Vec<Pubkey>,
+            data: Vec<u8>, // a packed GachaMarketplaceInstruction
+        },
+        ApproveMultisigAction {},
+        ExecuteMultisigAction {},
+        SetPaused {
+            paused: u32, // State::PAUSE_* flags, replaces the current set
+        },
+        // fields left as None keep their current value
+        UpdateConfig {
+            listing_price: Option<u64>,
+            fee_bps: Option<u16>,
+            max_gacha_qty: Option<u8>,
+            treasury: Option<Pubkey>,
+            gacha_fee: Option<u64>, // lamports per draw
+        },
+        MigrateState {},
+        // the Fetch* variants only read the state and answer through the return
+        // data, an ItemPage; send them with simulateTransaction
+        FetchMarketItems {
+            filter: ItemFilter,
+            page: Page,
+        },
+        FetchMyNFTs {
+            wallet: Pubkey,
+            page: Page,
+        },
+        FetchItemsCreated {
+            seller: Pubkey,
+            page: Page,
+        },
+        SetMintCollection {
+            collection: Option<Pubkey>, // None clears it
+        },
+    }
 
-#[derive(BorshDeserialize, Debug)]
-struct GachaMarketplacePayload {
-    token_program_id: Pubkey, // program id,
-    mint_address: Pubkey,     // ATA
-    price: u128,
-    file_name: String,
-    description: String,
-    cash_back: u8,
-    qty: u8,
-    fee: u128,
-    item_id: u128,
-    listing_price: u128,
-    variant: u8,
+    #[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "idl", derive(BorshSchema))]
+    pub enum LoyaltyReward {
+        FeeCredit { points: u64 },
+        FreeDraws { draws: u32 },
+    }
 }
 
 impl GachaMarketplaceInstruction {
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let payload = GachaMarketplacePayload::try_from_slice(input).unwrap();
-        // Match the variant to determine which data struct is expected by
-        Ok(match payload.variant {
-            0 => {
-                println!("??????????????");
-                Self::CreateMarketItem {
-                    token_program_id: payload.token_program_id,
-                    mint_address: payload.mint_address,
-                    price: payload.price,
-                    file_name: payload.file_name,
-                    description: payload.description,
-                    cash_back: payload.cash_back,
-                }
-            },
-            1 => Self::PurchaseSale {
-                token_program_id: payload.token_program_id,
-                price: payload.price,
-                item_id: payload.item_id,
-            },
-            2 => Self::CreateGacha {
-                token_program_id: payload.token_program_id,
-                qty: payload.qty,
-            },
-            3 => Self::Gacha {
-                token_program_id: payload.token_program_id,
-                qty: payload.qty,
-                price: payload.price,
-                fee: payload.fee,
-            },
-            4 => Self::InitState{
-                listing_price: payload.listing_price
-            },
-            // 5 => Self::FetchMarketItems {},
-            // 6 => Self::FetchMyNFTs {},
-            // 7 => Self::FetchItemsCreated {},
-            _ => return Err(ProgramError::InvalidInstructionData),
-        })
+        Self::try_from_slice(input).map_err(|_| ProgramError::InvalidInstructionData)
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,1037 @@
+//! Instruction builders for clients. Account order mirrors the processor.
+
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::pubkey::Pubkey;
+use solana_program::system_program;
+use spl_associated_token_account::{
+    get_associated_token_address, get_associated_token_address_with_program_id,
+};
+
+use super::{GachaMarketplaceInstruction, LoyaltyReward};
+use crate::query::{ItemFilter, Page};
+use crate::state::{
+    IndexKind, LoyaltyAccount, MintCollection, Multisig, MultisigProposal, RarityTier,
+    ReferrerAccount, RentalListing, Role, StakeEntry, StakePool, State, SwapOffer, WalletIndex,
+};
+
+/// Escrow token account holding listed units of `mint`.
+pub fn escrow_address(program_id: &Pubkey, mint: &Pubkey, token_program_id: &Pubkey) -> Pubkey {
+    let (state_pda, _) = State::find_address(program_id);
+    get_associated_token_address_with_program_id(&state_pda, mint, token_program_id)
+}
+
+pub fn init_state(program_id: &Pubkey, authority: &Pubkey, listing_price: u64) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::InitState { listing_price }.pack(),
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(state_pda, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_market_item(
+    program_id: &Pubkey,
+    seller: &Pubkey,
+    mint: &Pubkey,
+    seller_token_account: &Pubkey,
+    price: u64,
+    amount: u64,
+    file_name: &str,
+    description: &str,
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (state_pda, _) = State::find_address(program_id);
+    let (collection_pda, _) = MintCollection::find_address(program_id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::CreateMarketItem {
+            token_program_id,
+            mint_address: *mint,
+            price,
+            file_name: file_name.to_string(),
+            description: description.to_string(),
+            amount,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*seller, true),
+            AccountMeta::new(state_pda, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(*seller_token_account, false),
+            AccountMeta::new(escrow_address(program_id, mint, &token_program_id), false),
+            AccountMeta::new_readonly(token_program_id, false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(
+                wallet_index_address(program_id, seller, IndexKind::Listed),
+                false,
+            ),
+            AccountMeta::new_readonly(collection_pda, false),
+        ],
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn purchase_sale(
+    program_id: &Pubkey,
+    buyer: &Pubkey,
+    buyer_token_account: &Pubkey,
+    seller: &Pubkey,
+    treasury: &Pubkey,
+    mint: &Pubkey,
+    item_id: u128,
+    price: u64,
+    amount: u64,
+    referrer: Option<&Pubkey>,
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::PurchaseSale {
+            token_program_id,
+            price,
+            item_id,
+            amount,
+            referrer: referrer.copied(),
+        }
+        .pack(),
+        referral_accounts(
+            program_id,
+            referrer,
+            vec![
+                AccountMeta::new(*buyer, true),
+                AccountMeta::new(state_pda, false),
+                AccountMeta::new(*seller, false),
+                AccountMeta::new(*treasury, false),
+                AccountMeta::new_readonly(*mint, false),
+                AccountMeta::new(escrow_address(program_id, mint, &token_program_id), false),
+                AccountMeta::new(*buyer_token_account, false),
+                AccountMeta::new_readonly(token_program_id, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new(loyalty_address(program_id, buyer), false),
+                AccountMeta::new(
+                    wallet_index_address(program_id, buyer, IndexKind::Owned),
+                    false,
+                ),
+            ],
+        ),
+    )
+}
+
+pub fn cancel_market_item(
+    program_id: &Pubkey,
+    seller: &Pubkey,
+    seller_token_account: &Pubkey,
+    mint: &Pubkey,
+    item_id: u128,
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::CancelMarketItem {
+            token_program_id,
+            item_id,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*seller, true),
+            AccountMeta::new(state_pda, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(escrow_address(program_id, mint, &token_program_id), false),
+            AccountMeta::new(*seller_token_account, false),
+            AccountMeta::new_readonly(token_program_id, false),
+            AccountMeta::new(
+                wallet_index_address(program_id, seller, IndexKind::Listed),
+                false,
+            ),
+        ],
+    )
+}
+
+pub fn create_gacha(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    treasury: &Pubkey,
+    qty: u8,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::CreateGacha {
+            token_program_id: spl_token::id(),
+            qty,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(state_pda, false),
+            AccountMeta::new(*treasury, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// `candidates` lists `(seller, mint, buyer token account)` for every item
+/// that may be drawn; the program picks among them on chain.
+pub fn gacha(
+    program_id: &Pubkey,
+    buyer: &Pubkey,
+    treasury: &Pubkey,
+    qty: u8,
+    price: u64,
+    referrer: Option<&Pubkey>,
+    candidates: &[(Pubkey, Pubkey, Pubkey)],
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (state_pda, _) = State::find_address(program_id);
+    let mut accounts = referral_accounts(
+        program_id,
+        referrer,
+        vec![
+            AccountMeta::new(*buyer, true),
+            AccountMeta::new(state_pda, false),
+            AccountMeta::new(*treasury, false),
+            AccountMeta::new_readonly(token_program_id, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(loyalty_address(program_id, buyer), false),
+            AccountMeta::new(
+                wallet_index_address(program_id, buyer, IndexKind::Owned),
+                false,
+            ),
+        ],
+    );
+    for (seller, mint, buyer_token_account) in candidates {
+        accounts.push(AccountMeta::new(*seller, false));
+        accounts.push(AccountMeta::new_readonly(*mint, false));
+        accounts.push(AccountMeta::new(
+            escrow_address(program_id, mint, &token_program_id),
+            false,
+        ));
+        accounts.push(AccountMeta::new(*buyer_token_account, false));
+    }
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::Gacha {
+            token_program_id,
+            qty,
+            price,
+            referrer: referrer.copied(),
+        }
+        .pack(),
+        accounts,
+    )
+}
+
+/// `offered` lists `(mint, proposer token account)` for every NFT put in escrow.
+#[allow(clippy::too_many_arguments)]
+pub fn propose_swap(
+    program_id: &Pubkey,
+    proposer: &Pubkey,
+    swap_id: u64,
+    counterparty: &Pubkey,
+    offered: &[(Pubkey, Pubkey)],
+    offered_lamports: u64,
+    requested_mints: &[Pubkey],
+    requested_lamports: u64,
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (swap_pda, _) = SwapOffer::find_address(program_id, proposer, swap_id);
+    let mut accounts = vec![
+        AccountMeta::new(*proposer, true),
+        AccountMeta::new_readonly(State::find_address(program_id).0, false),
+        AccountMeta::new(swap_pda, false),
+        AccountMeta::new_readonly(token_program_id, false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for (mint, token_account) in offered {
+        accounts.push(AccountMeta::new_readonly(*mint, false));
+        accounts.push(AccountMeta::new(*token_account, false));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address_with_program_id(&swap_pda, mint, &token_program_id),
+            false,
+        ));
+    }
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::ProposeSwap {
+            swap_id,
+            counterparty: *counterparty,
+            offered_mints: offered.iter().map(|(mint, _)| *mint).collect(),
+            offered_lamports,
+            requested_mints: requested_mints.to_vec(),
+            requested_lamports,
+        }
+        .pack(),
+        accounts,
+    )
+}
+
+/// `requested` lists `(mint, counterparty token account)` for every NFT the
+/// proposer asked for; offered NFTs land in the counterparty's associated
+/// token accounts.
+pub fn accept_swap(
+    program_id: &Pubkey,
+    counterparty: &Pubkey,
+    proposer: &Pubkey,
+    swap_id: u64,
+    offered_mints: &[Pubkey],
+    requested: &[(Pubkey, Pubkey)],
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (swap_pda, _) = SwapOffer::find_address(program_id, proposer, swap_id);
+    let mut accounts = vec![
+        AccountMeta::new(*counterparty, true),
+        AccountMeta::new_readonly(State::find_address(program_id).0, false),
+        AccountMeta::new(*proposer, false),
+        AccountMeta::new(swap_pda, false),
+        AccountMeta::new_readonly(token_program_id, false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for mint in offered_mints {
+        accounts.push(AccountMeta::new_readonly(*mint, false));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address_with_program_id(&swap_pda, mint, &token_program_id),
+            false,
+        ));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address(counterparty, mint),
+            false,
+        ));
+    }
+    for (mint, token_account) in requested {
+        accounts.push(AccountMeta::new_readonly(*mint, false));
+        accounts.push(AccountMeta::new(*token_account, false));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address(proposer, mint),
+            false,
+        ));
+    }
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::AcceptSwap {
+            proposer: *proposer,
+            swap_id,
+        }
+        .pack(),
+        accounts,
+    )
+}
+
+/// Refunds the escrowed NFTs to the proposer's associated token accounts,
+/// creating any that are missing at the authority's expense.
+pub fn cancel_swap(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    proposer: &Pubkey,
+    swap_id: u64,
+    offered_mints: &[Pubkey],
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (swap_pda, _) = SwapOffer::find_address(program_id, proposer, swap_id);
+    let mut accounts = vec![
+        AccountMeta::new(*authority, true),
+        AccountMeta::new(*proposer, false),
+        AccountMeta::new(swap_pda, false),
+        AccountMeta::new_readonly(token_program_id, false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for mint in offered_mints {
+        accounts.push(AccountMeta::new_readonly(*mint, false));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address_with_program_id(&swap_pda, mint, &token_program_id),
+            false,
+        ));
+        accounts.push(AccountMeta::new(
+            get_associated_token_address(proposer, mint),
+            false,
+        ));
+    }
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::CancelSwap {
+            proposer: *proposer,
+            swap_id,
+        }
+        .pack(),
+        accounts,
+    )
+}
+
+pub fn create_rental(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    owner_token_account: &Pubkey,
+    price_per_period: u64,
+    period_secs: i64,
+    max_periods: u32,
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (rental_pda, _) = RentalListing::find_address(program_id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::CreateRental {
+            price_per_period,
+            period_secs,
+            max_periods,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new_readonly(State::find_address(program_id).0, false),
+            AccountMeta::new(rental_pda, false),
+            AccountMeta::new(*mint, false),
+            AccountMeta::new(*owner_token_account, false),
+            AccountMeta::new(
+                get_associated_token_address_with_program_id(&rental_pda, mint, &token_program_id),
+                false,
+            ),
+            AccountMeta::new_readonly(token_program_id, false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// `last_renter` is the listing's `renter`, whose expired rental still
+/// holds the NFT; `None` if it was never rented.
+pub fn rent_item(
+    program_id: &Pubkey,
+    renter: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    periods: u32,
+    last_renter: Option<&Pubkey>,
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (rental_pda, _) = RentalListing::find_address(program_id, mint);
+    let mut accounts = vec![
+        AccountMeta::new(*renter, true),
+        AccountMeta::new_readonly(State::find_address(program_id).0, false),
+        AccountMeta::new(*owner, false),
+        AccountMeta::new(rental_pda, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new(
+            get_associated_token_address_with_program_id(&rental_pda, mint, &token_program_id),
+            false,
+        ),
+        AccountMeta::new(get_associated_token_address(renter, mint), false),
+        AccountMeta::new_readonly(token_program_id, false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some(last_renter) = last_renter {
+        accounts.push(AccountMeta::new(
+            get_associated_token_address(last_renter, mint),
+            false,
+        ));
+    }
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::RentItem { periods }.pack(),
+        accounts,
+    )
+}
+
+/// Returns the NFT to the owner's associated token account, which must exist.
+/// `last_renter` is as for `rent_item`.
+pub fn reclaim_rental(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    last_renter: Option<&Pubkey>,
+) -> Instruction {
+    let token_program_id = spl_token::id();
+    let (rental_pda, _) = RentalListing::find_address(program_id, mint);
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*authority, true),
+        AccountMeta::new(*owner, false),
+        AccountMeta::new(rental_pda, false),
+        AccountMeta::new(*mint, false),
+        AccountMeta::new(
+            get_associated_token_address_with_program_id(&rental_pda, mint, &token_program_id),
+            false,
+        ),
+        AccountMeta::new(get_associated_token_address(owner, mint), false),
+        AccountMeta::new_readonly(token_program_id, false),
+    ];
+    if let Some(last_renter) = last_renter {
+        accounts.push(AccountMeta::new(
+            get_associated_token_address(last_renter, mint),
+            false,
+        ));
+    }
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::ReclaimRental {}.pack(),
+        accounts,
+    )
+}
+
+/// The stake pool's token account for `mint`, used both as the reward vault
+/// and to hold staked NFTs.
+pub fn stake_vault_address(program_id: &Pubkey, mint: &Pubkey) -> Pubkey {
+    let (pool_pda, _) = StakePool::find_address(program_id);
+    get_associated_token_address_with_program_id(&pool_pda, mint, &spl_token::id())
+}
+
+pub fn init_stake_pool(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    reward_mint: &Pubkey,
+    emission_rates: Vec<u64>,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    let (pool_pda, _) = StakePool::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::InitStakePool { emission_rates }.pack(),
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new_readonly(state_pda, false),
+            AccountMeta::new(pool_pda, false),
+            AccountMeta::new_readonly(*reward_mint, false),
+            AccountMeta::new(stake_vault_address(program_id, reward_mint), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn fund_stake_pool(
+    program_id: &Pubkey,
+    funder: &Pubkey,
+    reward_mint: &Pubkey,
+    funder_token_account: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (pool_pda, _) = StakePool::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::FundStakePool { amount }.pack(),
+        vec![
+            AccountMeta::new_readonly(*funder, true),
+            AccountMeta::new_readonly(pool_pda, false),
+            AccountMeta::new_readonly(*reward_mint, false),
+            AccountMeta::new(*funder_token_account, false),
+            AccountMeta::new(stake_vault_address(program_id, reward_mint), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+pub fn set_emission_rates(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    emission_rates: Vec<u64>,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    let (pool_pda, _) = StakePool::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetEmissionRates { emission_rates }.pack(),
+        vec![
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new_readonly(state_pda, false),
+            AccountMeta::new(pool_pda, false),
+        ],
+    )
+}
+
+pub fn set_rarity_tier(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    mint: &Pubkey,
+    tier: u8,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    let (rarity_pda, _) = RarityTier::find_address(program_id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetRarityTier { tier }.pack(),
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new_readonly(state_pda, false),
+            AccountMeta::new(rarity_pda, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// `collection` of `None` clears the mint's collection.
+pub fn set_mint_collection(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    mint: &Pubkey,
+    collection: Option<Pubkey>,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    let (collection_pda, _) = MintCollection::find_address(program_id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetMintCollection { collection }.pack(),
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new_readonly(state_pda, false),
+            AccountMeta::new(collection_pda, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// The mint must have a rarity tier, see `set_rarity_tier`.
+pub fn stake_nft(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    owner_token_account: &Pubkey,
+) -> Instruction {
+    let (pool_pda, _) = StakePool::find_address(program_id);
+    let (entry_pda, _) = StakeEntry::find_address(program_id, mint);
+    let (rarity_pda, _) = RarityTier::find_address(program_id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::StakeNft {}.pack(),
+        vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new_readonly(State::find_address(program_id).0, false),
+            AccountMeta::new(pool_pda, false),
+            AccountMeta::new(entry_pda, false),
+            AccountMeta::new_readonly(rarity_pda, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(*owner_token_account, false),
+            AccountMeta::new(stake_vault_address(program_id, mint), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn unstake_nft(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    owner_token_account: &Pubkey,
+    reward_mint: &Pubkey,
+    reward_account: &Pubkey,
+) -> Instruction {
+    let (pool_pda, _) = StakePool::find_address(program_id);
+    let (entry_pda, _) = StakeEntry::find_address(program_id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::UnstakeNft {}.pack(),
+        vec![
+            AccountMeta::new(*owner, true),
+            AccountMeta::new(pool_pda, false),
+            AccountMeta::new(entry_pda, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(stake_vault_address(program_id, mint), false),
+            AccountMeta::new(*owner_token_account, false),
+            AccountMeta::new_readonly(*reward_mint, false),
+            AccountMeta::new(stake_vault_address(program_id, reward_mint), false),
+            AccountMeta::new(*reward_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+pub fn claim_stake_rewards(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    reward_mint: &Pubkey,
+    reward_account: &Pubkey,
+) -> Instruction {
+    let (pool_pda, _) = StakePool::find_address(program_id);
+    let (entry_pda, _) = StakeEntry::find_address(program_id, mint);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::ClaimStakeRewards {}.pack(),
+        vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(pool_pda, false),
+            AccountMeta::new(entry_pda, false),
+            AccountMeta::new_readonly(*reward_mint, false),
+            AccountMeta::new(stake_vault_address(program_id, reward_mint), false),
+            AccountMeta::new(*reward_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+pub fn loyalty_address(program_id: &Pubkey, wallet: &Pubkey) -> Pubkey {
+    LoyaltyAccount::find_address(program_id, wallet).0
+}
+
+/// The account listing `wallet`'s listed or owned item ids.
+pub fn wallet_index_address(program_id: &Pubkey, wallet: &Pubkey, kind: IndexKind) -> Pubkey {
+    WalletIndex::find_address(program_id, wallet, kind).0
+}
+
+pub fn set_loyalty_config(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    lamports_per_point: u64,
+    point_value: u64,
+    points_per_free_draw: u64,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetLoyaltyConfig {
+            lamports_per_point,
+            point_value,
+            points_per_free_draw,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(state_pda, false),
+        ],
+    )
+}
+
+pub fn set_collection_multiplier(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    collection: &Pubkey,
+    multiplier_bps: u16,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetCollectionMultiplier {
+            collection: *collection,
+            multiplier_bps,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(state_pda, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn redeem_loyalty_points(
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    reward: LoyaltyReward,
+) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::RedeemLoyaltyPoints { reward }.pack(),
+        vec![
+            AccountMeta::new_readonly(*wallet, true),
+            AccountMeta::new_readonly(state_pda, false),
+            AccountMeta::new(loyalty_address(program_id, wallet), false),
+        ],
+    )
+}
+
+pub fn referrer_address(program_id: &Pubkey, wallet: &Pubkey) -> Pubkey {
+    ReferrerAccount::find_address(program_id, wallet).0
+}
+
+/// Appends the referrer's PDA when purchases name a referrer.
+fn referral_accounts(
+    program_id: &Pubkey,
+    referrer: Option<&Pubkey>,
+    mut accounts: Vec<AccountMeta>,
+) -> Vec<AccountMeta> {
+    if let Some(referrer) = referrer {
+        accounts.push(AccountMeta::new(
+            referrer_address(program_id, referrer),
+            false,
+        ));
+    }
+    accounts
+}
+
+pub fn register_referrer(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::RegisterReferrer {}.pack(),
+        vec![
+            AccountMeta::new(*wallet, true),
+            AccountMeta::new_readonly(State::find_address(program_id).0, false),
+            AccountMeta::new(referrer_address(program_id, wallet), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn set_referral_bps(program_id: &Pubkey, authority: &Pubkey, referral_bps: u16) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetReferralBps { referral_bps }.pack(),
+        vec![
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(state_pda, false),
+        ],
+    )
+}
+
+pub fn claim_referral_earnings(program_id: &Pubkey, wallet: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::ClaimReferralEarnings {}.pack(),
+        vec![
+            AccountMeta::new(*wallet, true),
+            AccountMeta::new(referrer_address(program_id, wallet), false),
+        ],
+    )
+}
+
+pub fn set_role(program_id: &Pubkey, owner: &Pubkey, role: Role, holder: &Pubkey) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetRole {
+            role,
+            holder: *holder,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(state_pda, false),
+        ],
+    )
+}
+
+pub fn transfer_ownership(program_id: &Pubkey, owner: &Pubkey, new_owner: &Pubkey) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::TransferOwnership {
+            new_owner: *new_owner,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(state_pda, false),
+        ],
+    )
+}
+
+pub fn accept_ownership(program_id: &Pubkey, new_owner: &Pubkey) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::AcceptOwnership {}.pack(),
+        vec![
+            AccountMeta::new_readonly(*new_owner, true),
+            AccountMeta::new(state_pda, false),
+        ],
+    )
+}
+
+pub fn multisig_address(program_id: &Pubkey, seed: &Pubkey) -> Pubkey {
+    Multisig::find_address(program_id, seed).0
+}
+
+pub fn proposal_address(program_id: &Pubkey, multisig: &Pubkey, index: u64) -> Pubkey {
+    MultisigProposal::find_address(program_id, multisig, index).0
+}
+
+pub fn create_multisig(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    seed: &Pubkey,
+    signers: Vec<Pubkey>,
+    threshold: u8,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::CreateMultisig {
+            seed: *seed,
+            signers,
+            threshold,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(multisig_address(program_id, seed), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Proposes running `action`, an instruction built for this program with the
+/// multisig as its authority. `index` is the multisig's current proposal count.
+pub fn propose_multisig_action(
+    program_id: &Pubkey,
+    proposer: &Pubkey,
+    multisig: &Pubkey,
+    index: u64,
+    action: &Instruction,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::ProposeMultisigAction {
+            accounts: action.accounts.iter().map(|meta| meta.pubkey).collect(),
+            data: action.data.clone(),
+        }
+        .pack(),
+        vec![
+            AccountMeta::new(*proposer, true),
+            AccountMeta::new(*multisig, false),
+            AccountMeta::new(proposal_address(program_id, multisig, index), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn approve_multisig_action(
+    program_id: &Pubkey,
+    signer: &Pubkey,
+    multisig: &Pubkey,
+    index: u64,
+) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::ApproveMultisigAction {}.pack(),
+        vec![
+            AccountMeta::new_readonly(*signer, true),
+            AccountMeta::new_readonly(*multisig, false),
+            AccountMeta::new(proposal_address(program_id, multisig, index), false),
+        ],
+    )
+}
+
+/// Executes the proposal; `action` must be the instruction that was proposed.
+pub fn execute_multisig_action(
+    program_id: &Pubkey,
+    executor: &Pubkey,
+    multisig: &Pubkey,
+    index: u64,
+    proposer: &Pubkey,
+    action: &Instruction,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*executor, true),
+        AccountMeta::new_readonly(*multisig, false),
+        AccountMeta::new(proposal_address(program_id, multisig, index), false),
+        AccountMeta::new(*proposer, false),
+        AccountMeta::new_readonly(*program_id, false),
+    ];
+    // the multisig PDA cannot sign the outer transaction, the program signs for it
+    accounts.extend(action.accounts.iter().map(|meta| AccountMeta {
+        is_signer: meta.is_signer && meta.pubkey != *multisig,
+        ..meta.clone()
+    }));
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::ExecuteMultisigAction {}.pack(),
+        accounts,
+    )
+}
+
+pub fn set_paused(program_id: &Pubkey, pauser: &Pubkey, paused: u32) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::SetPaused { paused }.pack(),
+        vec![
+            AccountMeta::new_readonly(*pauser, true),
+            AccountMeta::new(state_pda, false),
+        ],
+    )
+}
+
+/// Parameters for `update_config`; `None` keeps the current value.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigUpdate {
+    pub listing_price: Option<u64>,
+    pub fee_bps: Option<u16>,
+    pub max_gacha_qty: Option<u8>,
+    pub treasury: Option<Pubkey>,
+    pub gacha_fee: Option<u64>,
+}
+
+pub fn update_config(program_id: &Pubkey, authority: &Pubkey, update: ConfigUpdate) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::UpdateConfig {
+            listing_price: update.listing_price,
+            fee_bps: update.fee_bps,
+            max_gacha_qty: update.max_gacha_qty,
+            treasury: update.treasury,
+            gacha_fee: update.gacha_fee,
+        }
+        .pack(),
+        vec![
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(state_pda, false),
+        ],
+    )
+}
+
+/// Rewrites a state left by an older program version in the current layout.
+pub fn migrate_state(program_id: &Pubkey, upgrade_authority: &Pubkey) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &GachaMarketplaceInstruction::MigrateState {}.pack(),
+        vec![
+            AccountMeta::new(*upgrade_authority, true),
+            AccountMeta::new(state_pda, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Simulate it and read the `ItemPage` from the return data.
+pub fn fetch_market_items(program_id: &Pubkey, filter: ItemFilter, page: Page) -> Instruction {
+    fetch(
+        program_id,
+        GachaMarketplaceInstruction::FetchMarketItems { filter, page },
+    )
+}
+
+pub fn fetch_my_nfts(program_id: &Pubkey, wallet: &Pubkey, page: Page) -> Instruction {
+    fetch(
+        program_id,
+        GachaMarketplaceInstruction::FetchMyNFTs {
+            wallet: *wallet,
+            page,
+        },
+    )
+}
+
+pub fn fetch_items_created(program_id: &Pubkey, seller: &Pubkey, page: Page) -> Instruction {
+    fetch(
+        program_id,
+        GachaMarketplaceInstruction::FetchItemsCreated {
+            seller: *seller,
+            page,
+        },
+    )
+}
+
+fn fetch(program_id: &Pubkey, query: GachaMarketplaceInstruction) -> Instruction {
+    let (state_pda, _) = State::find_address(program_id);
+    Instruction::new_with_bytes(
+        *program_id,
+        &query.pack(),
+        vec![AccountMeta::new_readonly(state_pda, false)],
+    )
+}
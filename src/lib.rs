@@ -1,6 +1,17 @@
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod error;
+pub mod event;
+#[cfg(feature = "idl")]
+pub mod idl;
+#[cfg(feature = "indexer")]
+pub mod indexer;
 pub mod instruction;
+mod log;
 pub mod processor;
+pub mod query;
 pub mod state;
 pub mod entrypoint;
 
@@ -0,0 +1,29 @@
+//! Debug tracing that costs nothing unless the `debug-logs` feature is on.
+//!
+//! By default the program only logs its events and errors. Builds with
+//! `debug-logs` also log the decoded instruction, handler traces and
+//! compute unit checkpoints, which is useful on devnet but too expensive
+//! to ship.
+
+/// `msg!` when built with `debug-logs`. The arguments are not evaluated
+/// otherwise.
+macro_rules! debug_msg {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "debug-logs")]
+        ::solana_program::msg!($($arg)*);
+    };
+}
+
+/// Logs `label` and the remaining compute units when built with
+/// `debug-logs`.
+macro_rules! compute_checkpoint {
+    ($label:expr) => {
+        #[cfg(feature = "debug-logs")]
+        {
+            ::solana_program::msg!($label);
+            ::solana_program::log::sol_log_compute_units();
+        }
+    };
+}
+
+pub(crate) use {compute_checkpoint, debug_msg};
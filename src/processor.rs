@@ -1,33 +1,45 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    mem,
+    cell::{Ref, RefMut},
+    collections::BTreeMap,
 };
 
+use borsh::BorshSerialize;
+
 use crate::{
     error,
-    instruction::{self, GachaMarketplaceInstruction},
-    state::{MarketItem, State},
+    event::MarketplaceEvent,
+    instruction::GachaMarketplaceInstruction,
+    log::{compute_checkpoint, debug_msg},
+    query::{self, ItemPage},
+    state::{IndexKind, MarketItem, ProgramAccount, Role, Roles, State, StateHeader},
 };
-use borsh::{BorshDeserialize, BorshSerialize};
 use nanorand::{ChaCha, RNG};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    borsh::try_from_slice_unchecked,
-    entrypoint::ProgramResult,
-    feature, msg,
-    program::{self, invoke, invoke_signed},
+    clock::Clock,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    hash::hashv,
+    program::{invoke, invoke_signed, set_return_data, MAX_RETURN_DATA},
     program_error::ProgramError,
+    program_pack::Pack,
     pubkey::Pubkey,
-    stake_history::Epoch,
     system_instruction::{self, transfer},
     sysvar::{rent::Rent, Sysvar},
 };
 use spl_associated_token_account::{
-    get_associated_token_address, get_associated_token_address_with_program_id,
-    instruction::create_associated_token_account,
+    get_associated_token_address_with_program_id,
+    instruction::create_associated_token_account_idempotent,
 };
 
-use spl_token::instruction::{self as token_instruction, mint_to};
+use spl_token::state::Mint;
+
+mod admin;
+mod loyalty;
+mod referral;
+mod rental;
+mod staking;
+mod swap;
+mod wallet_index;
 
 pub struct Processor;
 impl Processor {
@@ -37,34 +49,43 @@ impl Processor {
         instruction_data: &[u8],
     ) -> ProgramResult {
         let instruction = GachaMarketplaceInstruction::unpack(instruction_data)?;
-        println!("//////////////////");
-        println!("{:?}", instruction);
-        println!("//////////////////");
-        match instruction {
+        debug_msg!("Instruction: {:?}", instruction);
+        compute_checkpoint!("Handler start");
+        let result = match instruction {
             GachaMarketplaceInstruction::CreateMarketItem {
                 token_program_id, // program id,
-                mint_address,     // ATA
+                mint_address,
                 price,
                 file_name,
                 description,
-                cash_back,
+                amount,
             } => {
                 Self::create_market_item(
                     accounts,
                     program_id,
                     token_program_id, // program id,
-                    mint_address,     // ATA
+                    mint_address,
                     price,
                     file_name,
                     description,
-                    cash_back,
+                    amount,
                 )
             },
             GachaMarketplaceInstruction::PurchaseSale {
                 token_program_id,
                 price,
                 item_id,
-            } => Self::purchase_sale(accounts, program_id, token_program_id, price, item_id),
+                amount,
+                referrer,
+            } => Self::purchase_sale(
+                accounts,
+                program_id,
+                token_program_id,
+                price,
+                item_id,
+                amount,
+                referrer,
+            ),
             GachaMarketplaceInstruction::CreateGacha {
                 token_program_id,
                 qty,
@@ -73,20 +94,165 @@ impl Processor {
                 token_program_id,
                 qty,
                 price,
-                fee,
-            } => Self::gacha(accounts, program_id, token_program_id, qty, price, fee),
+                referrer,
+            } => Self::gacha(accounts, program_id, token_program_id, qty, price, referrer),
             GachaMarketplaceInstruction::InitState { listing_price } => {
                 Self::init_state(accounts, program_id, listing_price)
             }
-        }
+            GachaMarketplaceInstruction::CancelMarketItem {
+                token_program_id,
+                item_id,
+            } => Self::cancel_market_item(accounts, program_id, token_program_id, item_id),
+            GachaMarketplaceInstruction::ProposeSwap {
+                swap_id,
+                counterparty,
+                offered_mints,
+                offered_lamports,
+                requested_mints,
+                requested_lamports,
+            } => Self::propose_swap(
+                accounts,
+                program_id,
+                swap_id,
+                counterparty,
+                offered_mints,
+                offered_lamports,
+                requested_mints,
+                requested_lamports,
+            ),
+            GachaMarketplaceInstruction::AcceptSwap { proposer, swap_id } => {
+                Self::accept_swap(accounts, program_id, proposer, swap_id)
+            }
+            GachaMarketplaceInstruction::CancelSwap { proposer, swap_id } => {
+                Self::cancel_swap(accounts, program_id, proposer, swap_id)
+            }
+            GachaMarketplaceInstruction::CreateRental {
+                price_per_period,
+                period_secs,
+                max_periods,
+            } => Self::create_rental(accounts, program_id, price_per_period, period_secs, max_periods),
+            GachaMarketplaceInstruction::RentItem { periods } => {
+                Self::rent_item(accounts, program_id, periods)
+            }
+            GachaMarketplaceInstruction::ReclaimRental {} => {
+                Self::reclaim_rental(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::InitStakePool { emission_rates } => {
+                Self::init_stake_pool(accounts, program_id, emission_rates)
+            }
+            GachaMarketplaceInstruction::FundStakePool { amount } => {
+                Self::fund_stake_pool(accounts, program_id, amount)
+            }
+            GachaMarketplaceInstruction::SetEmissionRates { emission_rates } => {
+                Self::set_emission_rates(accounts, program_id, emission_rates)
+            }
+            GachaMarketplaceInstruction::SetRarityTier { tier } => {
+                Self::set_rarity_tier(accounts, program_id, tier)
+            }
+            GachaMarketplaceInstruction::StakeNft {} => Self::stake_nft(accounts, program_id),
+            GachaMarketplaceInstruction::UnstakeNft {} => Self::unstake_nft(accounts, program_id),
+            GachaMarketplaceInstruction::ClaimStakeRewards {} => {
+                Self::claim_stake_rewards(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::SetLoyaltyConfig {
+                lamports_per_point,
+                point_value,
+                points_per_free_draw,
+            } => Self::set_loyalty_config(
+                accounts,
+                program_id,
+                lamports_per_point,
+                point_value,
+                points_per_free_draw,
+            ),
+            GachaMarketplaceInstruction::SetCollectionMultiplier {
+                collection,
+                multiplier_bps,
+            } => Self::set_collection_multiplier(accounts, program_id, collection, multiplier_bps),
+            GachaMarketplaceInstruction::RedeemLoyaltyPoints { reward } => {
+                Self::redeem_loyalty_points(accounts, program_id, reward)
+            }
+            GachaMarketplaceInstruction::RegisterReferrer {} => {
+                Self::register_referrer(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::SetReferralBps { referral_bps } => {
+                Self::set_referral_bps(accounts, program_id, referral_bps)
+            }
+            GachaMarketplaceInstruction::ClaimReferralEarnings {} => {
+                Self::claim_referral_earnings(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::SetRole { role, holder } => {
+                Self::set_role(accounts, program_id, role, holder)
+            }
+            GachaMarketplaceInstruction::TransferOwnership { new_owner } => {
+                Self::transfer_ownership(accounts, program_id, new_owner)
+            }
+            GachaMarketplaceInstruction::AcceptOwnership {} => {
+                Self::accept_ownership(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::CreateMultisig {
+                seed,
+                signers,
+                threshold,
+            } => Self::create_multisig(accounts, program_id, seed, signers, threshold),
+            GachaMarketplaceInstruction::ProposeMultisigAction { accounts: action_accounts, data } => {
+                Self::propose_multisig_action(accounts, program_id, action_accounts, data)
+            }
+            GachaMarketplaceInstruction::ApproveMultisigAction {} => {
+                Self::approve_multisig_action(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::ExecuteMultisigAction {} => {
+                Self::execute_multisig_action(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::SetPaused { paused } => {
+                Self::set_paused(accounts, program_id, paused)
+            }
+            GachaMarketplaceInstruction::UpdateConfig {
+                listing_price,
+                fee_bps,
+                max_gacha_qty,
+                treasury,
+                gacha_fee,
+            } => Self::update_config(
+                accounts,
+                program_id,
+                listing_price,
+                fee_bps,
+                max_gacha_qty,
+                treasury,
+                gacha_fee,
+            ),
+            GachaMarketplaceInstruction::MigrateState {} => {
+                Self::migrate_state(accounts, program_id)
+            }
+            GachaMarketplaceInstruction::FetchMarketItems { filter, page } => {
+                Self::fetch_items(accounts, program_id, |state| {
+                    query::market_items(state, &filter, page)
+                })
+            }
+            GachaMarketplaceInstruction::FetchMyNFTs { wallet, page } => {
+                Self::fetch_items(accounts, program_id, |state| {
+                    query::my_nfts(state, &wallet, page)
+                })
+            }
+            GachaMarketplaceInstruction::FetchItemsCreated { seller, page } => {
+                Self::fetch_items(accounts, program_id, |state| {
+                    query::items_created(state, &seller, page)
+                })
+            }
+            GachaMarketplaceInstruction::SetMintCollection { collection } => {
+                Self::set_mint_collection(accounts, program_id, collection)
+            }
+        };
+        compute_checkpoint!("Handler end");
+        result
     }
 
     fn init_state(
         accounts: &[AccountInfo],
         program_id: &Pubkey,
-        _listing_price: u128,
+        listing_price: u64,
     ) -> ProgramResult {
-        println!("111111111111111111");
         let account_info_iter = &mut accounts.iter();
 
         let authority_account = next_account_info(account_info_iter)?;
@@ -97,8 +263,7 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let (state_pda, state_bump) =
-            Pubkey::find_program_address(&[b"state".as_ref()], program_id);
+        let (state_pda, state_bump) = State::find_address(program_id);
 
         if state_pda != *state_account.key
             || !state_account.is_writable
@@ -114,11 +279,11 @@ impl Processor {
             authority_account.key,
             state_account.key,
             rent_lamports,
-            State::LEN.try_into().unwrap(),
+            State::LEN as u64,
             program_id,
         );
 
-        // msg!("Creating MapAccount account");
+        debug_msg!("Creating state account");
         invoke_signed(
             create_map_ix,
             &[
@@ -126,14 +291,10 @@ impl Processor {
                 state_account.clone(),
                 system_program.clone(),
             ],
-            &[&[b"state".as_ref(), &[state_bump]]],
+            &[&[State::SEED, &[state_bump]]],
         )?;
 
-        // msg!("Deserializing MapAccount account");
-        let mut state = try_from_slice_unchecked::<State>(&state_account.data.borrow()).unwrap();
-        if state.initialized == true {
-            return Err(error::GachaError::StateAlreadyInitialized.into());
-        }
+        let mut state = State::default();
 
         let empty_map: BTreeMap<u128, MarketItem> = BTreeMap::new();
 
@@ -141,177 +302,374 @@ impl Processor {
         state.item_ids = 0;
         state.item_sold = 0;
         state.owner = *authority_account.key;
-        state.listing_price = _listing_price;
+        state.roles = Roles::all(*authority_account.key);
+        state.treasury = *authority_account.key;
+        state.max_gacha_qty = State::DEFAULT_MAX_GACHA_QTY;
+        state.listing_price = listing_price;
         state.initialized = true;
 
-        // msg!("Serializing MapAccount account");
-        state.serialize(&mut &mut state_account.data.borrow_mut()[..])?;
+        state.save(state_account)?;
 
+        MarketplaceEvent::StateInitialized {
+            owner: state.owner,
+            listing_price: state.listing_price,
+        }
+        .emit();
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn create_market_item(
         accounts: &[AccountInfo],
         program_id: &Pubkey,
-        _token_program_id: Pubkey, // program id,
-        _mint_address: Pubkey,     // ATA
-        _price: u128,
-        _file_name: String,
-        _description: String,
-        _cash_back: u8,
+        token_program_id: Pubkey, // program id,
+        mint_address: Pubkey,
+        price: u64,
+        file_name: String,
+        description: String,
+        amount: u64,
     ) -> ProgramResult {
-        println!("222222222222222");
-        if _cash_back >= 100 {
-            return Err(error::GachaError::CashbackMax.into());
+        if price < 1 {
+            return Err(error::GachaError::InvalidPrice.into());
+        }
+        if amount < 1 {
+            return Err(error::GachaError::InvalidAmount.into());
+        }
+        if file_name.len() > MarketItem::MAX_FILE_NAME_LEN
+            || description.len() > MarketItem::MAX_DESCRIPTION_LEN
+        {
+            return Err(error::GachaError::ListingTextTooLong.into());
         }
         let account_info_iter = &mut accounts.iter();
 
         let authority_account = next_account_info(account_info_iter)?;
         let state_account = next_account_info(account_info_iter)?;
-        // let nft_account = next_account_info(account_info_iter)?;
         let mint = next_account_info(account_info_iter)?;
         let token_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
         let associated_token_program = next_account_info(account_info_iter)?;
-        // let system_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let listed_index = next_account_info(account_info_iter)?;
+        let collection_account = next_account_info(account_info_iter)?;
 
+        Self::check_token_program(token_program)?;
         if !authority_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
+        if *mint.key != mint_address {
+            return Err(error::GachaError::WrongMint.into());
+        }
+        if *token_program.key != token_program_id {
+            return Err(error::GachaError::InvalidTokenProgram.into());
+        }
 
-        let mut state = try_from_slice_unchecked::<State>(&state_account.data.borrow())?;
+        let (state_pda, _) = State::find_address(program_id);
+        if *escrow_account.key
+            != get_associated_token_address_with_program_id(&state_pda, mint.key, token_program.key)
+        {
+            return Err(error::GachaError::InvalidEscrowAccount.into());
+        }
 
-        state.item_ids += 1;
+        let collection = Self::load_mint_collection(program_id, mint, collection_account)?;
+        let decimals = Mint::unpack(&mint.data.borrow())?.decimals;
+        let mut state = Self::load_state(program_id, state_account)?;
+        if state.is_paused(State::PAUSE_LISTINGS) {
+            return Err(error::GachaError::Paused.into());
+        }
+
+        state.item_ids = state
+            .item_ids
+            .checked_add(1)
+            .ok_or(error::GachaError::MathOverflow)?;
 
         let item: MarketItem = MarketItem {
             item_id: state.item_ids,
-            token_program_id: _token_program_id,
-            mint_address: _mint_address,
+            token_program_id,
+            mint_address,
             seller: *authority_account.key,
             owner: None,
-            price: _price,
-            file_name: _file_name,
-            description: _description,
-            cash_back: _cash_back,
+            price,
+            file_name,
+            description,
+            collection,
             sold: false,
             gacha: false,
+            amount,
+            remaining: amount,
+            decimals,
         };
 
-        state.map.insert(state.item_ids, item.clone());
-        state.serialize(&mut &mut state_account.data.borrow_mut()[..])?;
+        state.map.insert(state.item_ids, item);
+        Self::save_state(&state, state_account, authority_account, system_program)?;
+        Self::add_to_wallet_index(
+            program_id,
+            authority_account.key,
+            IndexKind::Listed,
+            listed_index,
+            authority_account,
+            system_program,
+            state.item_ids,
+        )?;
 
-        println!("mmmmmmm");
-        invoke(
-            &create_associated_token_account(
-                &authority_account.key,
-                &authority_account.key,
-                &mint.key,
-                &_token_program_id,
-            ),
-            &[
-                mint.clone(),
-                token_account.clone(),
-                authority_account.clone(),
-                token_program.clone(),
-                associated_token_program.clone()
-            ],
+        // the escrow is the state PDA's associated token account for this mint
+        Self::create_token_account(
+            authority_account,
+            escrow_account,
+            state_account,
+            mint,
+            system_program,
+            token_program,
+            associated_token_program,
         )?;
-        println!("mmmmmmm");
-        // transfer nft from sender to this contract
-        invoke(
-            &spl_token::instruction::transfer_checked(
-                &_token_program_id,
-                &authority_account.key,
-                &_mint_address,
-                &token_account.key,
-                authority_account.key,
-                &[authority_account.key],
-                1,
-                0,
-            )
-            .unwrap(),
-            &[
-                authority_account.clone(),
-                mint.clone(),
-                token_account.clone(),
-                token_program.clone(),
-            ],
+        // transfer the listed units from the seller to the escrow
+        Self::transfer_tokens(
+            token_program,
+            token_account,
+            mint,
+            escrow_account,
+            authority_account,
+            amount,
+            &[],
         )?;
+
+        MarketplaceEvent::ItemListed {
+            item_id: state.item_ids,
+            seller: *authority_account.key,
+            mint: mint_address,
+            price,
+            amount,
+        }
+        .emit();
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn purchase_sale(
         accounts: &[AccountInfo],
         program_id: &Pubkey,
         _nft_contract: Pubkey, // program id
-        _price: u128,
-        _item_id: u128,
+        price: u64,
+        item_id: u128,
+        amount: u64,
+        referrer: Option<Pubkey>,
     ) -> ProgramResult {
-        println!("33333333333333");
         let account_info_iter = &mut accounts.iter();
 
         let authority_account = next_account_info(account_info_iter)?;
         let state_account = next_account_info(account_info_iter)?;
         let item_seller = next_account_info(account_info_iter)?;
-        let owner_account = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let buyer_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
+        let loyalty_account = next_account_info(account_info_iter)?;
+        let owned_index = next_account_info(account_info_iter)?;
+        // the referrer's PDA follows only when a referrer is named
+        let referrer = match referrer {
+            Some(referrer) => Some((next_account_info(account_info_iter)?, referrer)),
+            None => None,
+        };
 
+        Self::check_token_program(token_program)?;
         if !authority_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
-        let mut state = try_from_slice_unchecked::<State>(&state_account.data.borrow())?;
+        let mut state = Self::load_state(program_id, state_account)?;
+        if state.is_paused(State::PAUSE_PURCHASES) {
+            return Err(error::GachaError::Paused.into());
+        }
+        let mut loyalty = Self::load_loyalty(program_id, authority_account, loyalty_account)?;
 
-        let mut item = state.map.get(&_item_id).unwrap().to_owned();
-        let price = item.price;
-        let mint_address = item.mint_address;
+        let mut item = state
+            .map
+            .get(&item_id)
+            .ok_or(error::GachaError::ItemNotFound)?
+            .to_owned();
 
-        if _price != price {
+        if price != item.price {
             return Err(error::GachaError::InvalidPayment.into());
         }
+        if amount < 1 {
+            return Err(error::GachaError::InvalidAmount.into());
+        }
+        if item.sold {
+            return Err(error::GachaError::ItemAlreadySold.into());
+        }
+        if item.gacha {
+            return Err(error::GachaError::ItemInGachaPool.into());
+        }
+        if amount > item.remaining {
+            return Err(error::GachaError::InsufficientQuantity.into());
+        }
+        if *item_seller.key != item.seller {
+            return Err(error::GachaError::InvalidSellerAccount.into());
+        }
+        if *treasury_account.key != state.treasury {
+            return Err(error::GachaError::InvalidTreasuryAccount.into());
+        }
+
+        let total = item
+            .price
+            .checked_mul(amount)
+            .ok_or(error::GachaError::MathOverflow)?;
 
-        // transfer price to seller
-        // need item.seller accountInfo
+        // transfer price of the purchased units to seller, less the marketplace cut
+        let cut = state.sale_fee(total);
+        let proceeds = total
+            .checked_sub(cut)
+            .ok_or(error::GachaError::MathOverflow)?;
         invoke(
-            &transfer(
-                authority_account.key,
-                &item.seller,
-                price.try_into().unwrap(),
-            ),
-            &[authority_account.to_owned(), item_seller.to_owned()],
+            &transfer(authority_account.key, &item.seller, proceeds),
+            &[
+                authority_account.to_owned(),
+                item_seller.to_owned(),
+                system_program.to_owned(),
+            ],
         )?;
-        println!("zxczxczczxczxczxc");
-        // transfer nft from contract to sender
-        invoke(
-            &spl_token::instruction::transfer_checked(
-                &item.token_program_id,
-                &system_program.key,
-                &mint_address,
-                authority_account.key,
-                system_program.key,
-                &[system_program.key],
-                1,
-                0,
-            )
-            .unwrap(),
-            &[authority_account.clone(), system_program.clone()],
+        // transfer units from escrow to buyer
+        Self::transfer_from_escrow(
+            program_id,
+            &item,
+            state_account,
+            mint,
+            escrow_account,
+            buyer_token_account,
+            token_program,
+            amount,
         )?;
 
-        item.owner = Some(*authority_account.key);
-        item.sold = true;
+        // transfer listing price and cut to the treasury, less any loyalty fee credit
+        let fee = loyalty.apply_fee_credit(
+            state
+                .listing_price
+                .checked_add(cut)
+                .ok_or(error::GachaError::MathOverflow)?,
+        );
+        Self::pay_marketplace_fee(
+            program_id,
+            &state,
+            authority_account,
+            treasury_account,
+            referrer,
+            system_program,
+            fee,
+        )?;
 
-        // transfer listing price to owner
-        // need owner accountInfo
-        invoke(
-            &transfer(
-                authority_account.key,
-                &owner_account.key,
-                price.try_into().unwrap(),
-            ),
-            &[authority_account.to_owned(), owner_account.to_owned()],
+        let points = state
+            .loyalty
+            .points_for(total, item.collection.as_ref())
+            .saturating_add(state.loyalty.points_for(fee.saturating_sub(cut), None));
+        loyalty.points = loyalty.points.saturating_add(points);
+        Self::save_loyalty(
+            program_id,
+            authority_account,
+            loyalty_account,
+            system_program,
+            &loyalty,
         )?;
 
-        state.item_sold += 1;
-        state.serialize(&mut &mut state_account.data.borrow_mut()[..])?;
+        MarketplaceEvent::ItemSold {
+            item_id,
+            buyer: *authority_account.key,
+            seller: item.seller,
+            price: item.price,
+            amount,
+        }
+        .emit();
+        item.remaining = item
+            .remaining
+            .checked_sub(amount)
+            .ok_or(error::GachaError::MathOverflow)?;
+        if item.remaining == 0 {
+            // a multi-unit listing has no single owner, its buyers are in the wallet indexes
+            if item.amount == 1 {
+                item.owner = Some(*authority_account.key);
+            }
+            item.sold = true;
+            state.item_sold = state
+                .item_sold
+                .checked_add(1)
+                .ok_or(error::GachaError::MathOverflow)?;
+        }
+        state.map.insert(item_id, item);
+
+        Self::save_state(&state, state_account, authority_account, system_program)?;
+        Self::add_to_wallet_index(
+            program_id,
+            authority_account.key,
+            IndexKind::Owned,
+            owned_index,
+            authority_account,
+            system_program,
+            item_id,
+        )?;
+        Ok(())
+    }
+
+    fn cancel_market_item(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        _nft_contract: Pubkey,
+        item_id: u128,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let listed_index = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !authority_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let mut state = Self::load_state(program_id, state_account)?;
+
+        let item = state
+            .map
+            .get(&item_id)
+            .ok_or(error::GachaError::ItemNotFound)?
+            .to_owned();
+        if item.seller != *authority_account.key {
+            return Err(error::GachaError::NotSeller.into());
+        }
+        if item.sold {
+            return Err(error::GachaError::ItemAlreadySold.into());
+        }
+
+        // return the unsold units to the seller
+        Self::transfer_from_escrow(
+            program_id,
+            &item,
+            state_account,
+            mint,
+            escrow_account,
+            token_account,
+            token_program,
+            item.remaining,
+        )?;
+
+        state.map.remove(&item_id);
+        state.save(state_account)?;
+        MarketplaceEvent::ListingCancelled {
+            item_id,
+            seller: item.seller,
+            returned: item.remaining,
+        }
+        .emit();
+        Self::remove_from_wallet_index(
+            program_id,
+            authority_account.key,
+            IndexKind::Listed,
+            listed_index,
+            item_id,
+        )?;
         Ok(())
     }
 
@@ -319,87 +677,198 @@ impl Processor {
         accounts: &[AccountInfo],
         program_id: &Pubkey,
         _nft_contract: Pubkey,
-        _qty: u8,
-        _price: u128,
-        _fee: u128,
+        qty: u8,
+        price: u64,
+        referrer: Option<Pubkey>,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
         let authority_account = next_account_info(account_info_iter)?;
         let state_account = next_account_info(account_info_iter)?;
-        let seller_0th_item_account = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
+        let loyalty_account = next_account_info(account_info_iter)?;
+        let owned_index = next_account_info(account_info_iter)?;
+        let referrer = match referrer {
+            Some(referrer) => Some((next_account_info(account_info_iter)?, referrer)),
+            None => None,
+        };
+        // followed by [seller, mint, escrow, buyer token account] for every candidate item
+        let candidate_accounts = account_info_iter.as_slice();
 
+        Self::check_token_program(token_program)?;
         if !authority_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let mut state = try_from_slice_unchecked::<State>(&state_account.data.borrow())?;
-
-        let item_count = state.item_ids;
-        let item_sold = state.item_sold;
-        let unsold_item_count = item_count - item_sold;
-        let mut current_index = 0;
+        let mut state = Self::load_state(program_id, state_account)?;
+        if state.is_paused(State::PAUSE_GACHA) {
+            return Err(error::GachaError::Paused.into());
+        }
+        let mut loyalty = Self::load_loyalty(program_id, authority_account, loyalty_account)?;
+        if qty < 1 || qty > state.max_gacha_qty {
+            return Err(error::GachaError::InvalidAmount.into());
+        }
+        let mut points: u64 = 0;
+        let mut sale_fees: u64 = 0;
+        if *treasury_account.key != state.treasury {
+            return Err(error::GachaError::InvalidTreasuryAccount.into());
+        }
 
-        let mut items = HashMap::new();
-        for i in 0..item_count {
-            let current_id = i + 1;
-            let item = state.map.get(&current_id).unwrap().to_owned();
-            if item.owner != None && item.price == _price {
-                items.insert(current_index, item);
-                current_index += 1;
+        let mut items: Vec<u128> = state
+            .map
+            .values()
+            .filter(|item| item.gacha && !item.sold && item.remaining > 0 && item.price == price)
+            .map(|item| item.item_id)
+            .collect();
+        if items.len() < qty as usize {
+            return Err(error::GachaError::PoolEmpty.into());
+        }
+        // the accounts of every eligible item are required before drawing, so
+        // leaving out an unwanted item cannot turn its draw into a retry
+        for item_id in &items {
+            let item = &state.map[item_id];
+            if !candidate_accounts.chunks_exact(4).any(|group| {
+                *group[0].key == item.seller && *group[1].key == item.mint_address
+            }) {
+                return Err(error::GachaError::MissingGachaAccounts.into());
             }
         }
 
-        let mut gacha_items = HashMap::new();
-        for i in 0.._qty {
-            let mut rng = ChaCha::new(254);
-            let gacha_index = rng.generate_range(0, (unsold_item_count - 1) as u64) as u128;
-            let item = state.map.get(&gacha_index).unwrap().to_owned();
-            gacha_items.insert(i, item);
-            items.remove(&gacha_index);
-        }
+        let mut rng = Self::gacha_rng(authority_account.key, state.item_ids, state.item_sold)?;
+        let mut drawn = Vec::with_capacity(qty as usize);
+        for _ in 0..qty {
+            let gacha_index = rng.generate_range(0, items.len() as u64) as usize;
+            let item_id = items.swap_remove(gacha_index);
+            debug_msg!("Drew item {} out of {}", item_id, items.len() + 1);
+            let mut selected_item = state
+                .map
+                .get(&item_id)
+                .ok_or(error::GachaError::ItemNotFound)?
+                .to_owned();
 
-        for i in 0.._qty {
-            let item_id = gacha_items.get(&i).unwrap().item_id;
-            let mut selected_item = state.map.get(&item_id).unwrap().to_owned();
-            let mint_address = selected_item.mint_address;
+            let group = candidate_accounts
+                .chunks_exact(4)
+                .find(|group| {
+                    *group[0].key == selected_item.seller
+                        && *group[1].key == selected_item.mint_address
+                })
+                .ok_or(error::GachaError::MissingGachaAccounts)?;
+            let (item_seller, mint, escrow_account, buyer_token_account) =
+                (&group[0], &group[1], &group[2], &group[3]);
 
-            // transfer nft
+            // pay the seller for one unit, less the marketplace cut
+            let price = selected_item.price;
+            points = points.saturating_add(
+                state
+                    .loyalty
+                    .points_for(price, selected_item.collection.as_ref()),
+            );
+            let cut = state.sale_fee(price);
+            sale_fees = sale_fees
+                .checked_add(cut)
+                .ok_or(error::GachaError::MathOverflow)?;
+            let proceeds = price
+                .checked_sub(cut)
+                .ok_or(error::GachaError::MathOverflow)?;
             invoke(
-                &spl_token::instruction::transfer_checked(
-                    &selected_item.token_program_id,
-                    &system_program.key,
-                    &mint_address,
-                    authority_account.key,
-                    system_program.key,
-                    &[system_program.key],
-                    1,
-                    0,
-                )
-                .unwrap(),
-                &[authority_account.clone(), system_program.clone()],
+                &transfer(authority_account.key, item_seller.key, proceeds),
+                &[
+                    authority_account.to_owned(),
+                    item_seller.to_owned(),
+                    system_program.to_owned(),
+                ],
+            )?;
+
+            // transfer one unit
+            Self::transfer_from_escrow(
+                program_id,
+                &selected_item,
+                state_account,
+                mint,
+                escrow_account,
+                buyer_token_account,
+                token_program,
+                1,
             )?;
+            MarketplaceEvent::GachaDrawn {
+                item_id,
+                buyer: *authority_account.key,
+                seller: selected_item.seller,
+                price,
+            }
+            .emit();
 
-            selected_item.owner = Some(*authority_account.key);
-            selected_item.gacha = true;
+            selected_item.remaining = selected_item
+                .remaining
+                .checked_sub(1)
+                .ok_or(error::GachaError::MathOverflow)?;
+            if selected_item.remaining == 0 {
+                if selected_item.amount == 1 {
+                    selected_item.owner = Some(*authority_account.key);
+                }
+                selected_item.sold = true;
+                state.item_sold = state
+                    .item_sold
+                    .checked_add(1)
+                    .ok_or(error::GachaError::MathOverflow)?;
+            }
+            state.map.insert(item_id, selected_item);
+            drawn.push(item_id);
         }
 
-        // transfer fee to seller map[0] // accountInfo receiver
-        invoke(
-            &transfer(
-                authority_account.key,
-                &seller_0th_item_account.key,
-                _fee.try_into().unwrap(),
-            ),
-            &[
-                authority_account.to_owned(),
-                seller_0th_item_account.to_owned(),
-            ],
+        // transfer fee and cuts to the treasury, each free draw waives the
+        // fee of one draw
+        let free_draws = if state.gacha_fee > 0 {
+            loyalty.free_draws.min(qty as u32)
+        } else {
+            0
+        };
+        loyalty.free_draws -= free_draws;
+        let draw_fee = state
+            .gacha_fee
+            .checked_mul(u64::from(qty as u32 - free_draws))
+            .ok_or(error::GachaError::MathOverflow)?;
+        let fee = loyalty.apply_fee_credit(
+            draw_fee
+                .checked_add(sale_fees)
+                .ok_or(error::GachaError::MathOverflow)?,
+        );
+        Self::pay_marketplace_fee(
+            program_id,
+            &state,
+            authority_account,
+            treasury_account,
+            referrer,
+            system_program,
+            fee,
         )?;
 
-        state.item_sold += 1;
-        state.serialize(&mut &mut state_account.data.borrow_mut()[..])?;
+        loyalty.points = loyalty
+            .points
+            .saturating_add(points)
+            .saturating_add(state.loyalty.points_for(fee.saturating_sub(sale_fees), None));
+        Self::save_loyalty(
+            program_id,
+            authority_account,
+            loyalty_account,
+            system_program,
+            &loyalty,
+        )?;
+
+        Self::save_state(&state, state_account, authority_account, system_program)?;
+        for item_id in drawn {
+            Self::add_to_wallet_index(
+                program_id,
+                authority_account.key,
+                IndexKind::Owned,
+                owned_index,
+                authority_account,
+                system_program,
+                item_id,
+            )?;
+        }
         Ok(())
     }
 
@@ -407,84 +876,401 @@ impl Processor {
         accounts: &[AccountInfo],
         program_id: &Pubkey,
         _nft_contract: Pubkey,
-        _qty: u8,
+        qty: u8,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
         let authority_account = next_account_info(account_info_iter)?;
         let state_account = next_account_info(account_info_iter)?;
-        let owner_account = next_account_info(account_info_iter)?;
+        let treasury_account = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
 
-        if !authority_account.is_signer {
-            return Err(ProgramError::MissingRequiredSignature);
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::PoolCurator,
+        )?;
+        let mut state = Self::load_state(program_id, state_account)?;
+        if state.is_paused(State::PAUSE_GACHA) {
+            return Err(error::GachaError::Paused.into());
         }
-
-        let mut state = try_from_slice_unchecked::<State>(&state_account.data.borrow())?;
-
-        let item_count = state.item_ids;
-        let item_sold = state.item_sold;
-        let unsold_item_count = item_count - item_sold;
-        let mut current_index = 0;
-
-        let mut items = HashMap::new();
-        for i in 0..item_count {
-            let current_id = i + 1;
-            let item = state.map.get(&current_id).unwrap().to_owned();
-            if item.owner != None {
-                items.insert(current_index, item);
-                current_index += 1;
-            }
+        if *treasury_account.key != state.treasury {
+            return Err(error::GachaError::InvalidTreasuryAccount.into());
         }
 
-        let mut gacha_items = HashMap::new();
-        let mut len = items.len();
-        for i in 0.._qty {
-            let mut rng = ChaCha::new(254);
-            let index = rng.generate_range(0, len - 1);
-            let item = items.get(&index).unwrap().to_owned();
-            gacha_items.insert(i, item);
-            items.remove(&index);
-            len -= 1;
+        let mut items: Vec<u128> = state
+            .map
+            .values()
+            .filter(|item| !item.gacha && !item.sold && item.remaining > 0)
+            .map(|item| item.item_id)
+            .collect();
+        if items.len() < qty as usize {
+            return Err(error::GachaError::PoolEmpty.into());
         }
 
-        for i in 0.._qty {
-            let item = gacha_items.get(&i).unwrap().to_owned();
-            let item_id = item.item_id;
-            let mint_address = item.mint_address;
+        // move random listings into the gacha pool, their units stay in escrow
+        let mut rng = Self::gacha_rng(authority_account.key, state.item_ids, state.item_sold)?;
+        let mut pooled = Vec::with_capacity(qty as usize);
+        for _ in 0..qty {
+            let index = rng.generate_range(0, items.len() as u64) as usize;
+            let item_id = items.swap_remove(index);
+            debug_msg!("Pooled item {}", item_id);
+            pooled.push(item_id);
 
-            // transfer nft
-            invoke(
-                &spl_token::instruction::transfer_checked(
-                    &item.token_program_id,
-                    &system_program.key,
-                    &mint_address,
-                    authority_account.key,
-                    system_program.key,
-                    &[system_program.key],
-                    1,
-                    0,
-                )
-                .unwrap(),
-                &[authority_account.clone(), system_program.clone()],
-            )?;
-
-            let mut item = state.map.get(&item_id).unwrap().to_owned();
-            item.owner = Some(state.owner);
+            let mut item = state
+                .map
+                .get(&item_id)
+                .ok_or(error::GachaError::ItemNotFound)?
+                .to_owned();
             item.gacha = true;
+            state.map.insert(item_id, item);
 
-            state.item_sold += 1;
             invoke(
                 &transfer(
                     authority_account.key,
-                    &owner_account.key,
-                    state.listing_price.try_into().unwrap(),
+                    treasury_account.key,
+                    state.listing_price,
                 ),
-                &[authority_account.to_owned(), owner_account.to_owned()],
+                &[
+                    authority_account.to_owned(),
+                    treasury_account.to_owned(),
+                    system_program.to_owned(),
+                ],
             )?;
         }
 
-        state.serialize(&mut &mut state_account.data.borrow_mut()[..])?;
+        state.save(state_account)?;
+        MarketplaceEvent::GachaPoolFilled { item_ids: pooled }.emit();
+        Ok(())
+    }
+
+    /// Answers a Fetch* query with the resulting page as return data, cut
+    /// short where needed to fit, in which case `next` resumes at the cut.
+    fn fetch_items(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        query: impl FnOnce(&State) -> ItemPage,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let state_account = next_account_info(account_info_iter)?;
+
+        let state = Self::load_state(program_id, state_account)?;
+        let mut page = query(&state);
+        page.truncate_to(MAX_RETURN_DATA)?;
+        debug_msg!("Returning {} items", page.items.len());
+        set_return_data(&page.try_to_vec()?);
         Ok(())
     }
+
+    /// Moves `amount` units of `item` out of the escrow, signed by the state PDA.
+    #[allow(clippy::too_many_arguments)]
+    fn transfer_from_escrow<'a>(
+        program_id: &Pubkey,
+        item: &MarketItem,
+        state_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        escrow_account: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        let (state_pda, state_bump) = State::find_address(program_id);
+        if *state_account.key != state_pda {
+            return Err(error::GachaError::InvalidStateAccount.into());
+        }
+        if *mint.key != item.mint_address {
+            return Err(error::GachaError::WrongMint.into());
+        }
+        if *token_program.key != item.token_program_id {
+            return Err(error::GachaError::InvalidTokenProgram.into());
+        }
+        if *escrow_account.key
+            != get_associated_token_address_with_program_id(&state_pda, mint.key, token_program.key)
+        {
+            return Err(error::GachaError::InvalidEscrowAccount.into());
+        }
+
+        Self::transfer_tokens(
+            token_program,
+            escrow_account,
+            mint,
+            destination,
+            state_account,
+            amount,
+            &[&[State::SEED, &[state_bump]]],
+        )
+    }
+
+    /// `transfer_checked` of `amount` units, using the decimals of `mint`.
+    /// Pass `signer_seeds` when `authority` is one of our PDAs.
+    fn transfer_tokens<'a>(
+        token_program: &AccountInfo<'a>,
+        source: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        amount: u64,
+        signer_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let decimals = Mint::unpack(&mint.data.borrow())?.decimals;
+        invoke_signed(
+            &spl_token::instruction::transfer_checked(
+                token_program.key,
+                source.key,
+                mint.key,
+                destination.key,
+                authority.key,
+                &[],
+                amount,
+                decimals,
+            )?,
+            &[
+                source.clone(),
+                mint.clone(),
+                destination.clone(),
+                authority.clone(),
+                token_program.clone(),
+            ],
+            signer_seeds,
+        )
+    }
+
+    /// Creates `wallet`'s associated token account for `mint` unless it exists.
+    fn create_token_account<'a>(
+        payer: &AccountInfo<'a>,
+        token_account: &AccountInfo<'a>,
+        wallet: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        associated_token_program: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        invoke(
+            &create_associated_token_account_idempotent(
+                payer.key,
+                wallet.key,
+                mint.key,
+                token_program.key,
+            ),
+            &[
+                payer.clone(),
+                token_account.clone(),
+                wallet.clone(),
+                mint.clone(),
+                system_program.clone(),
+                token_program.clone(),
+                associated_token_program.clone(),
+            ],
+        )
+    }
+
+    /// Allocates a rent exempt account of `space` bytes at a PDA of this program.
+    fn create_program_account<'a>(
+        program_id: &Pubkey,
+        payer: &AccountInfo<'a>,
+        account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        space: usize,
+        extra_lamports: u64,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let lamports = Rent::get()?
+            .minimum_balance(space)
+            .checked_add(extra_lamports)
+            .ok_or(error::GachaError::MathOverflow)?;
+        invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                account.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[payer.clone(), account.clone(), system_program.clone()],
+            &[signer_seeds],
+        )
+    }
+
+    /// Reads the whole marketplace state, which must be this program's state PDA.
+    fn load_state(program_id: &Pubkey, state_account: &AccountInfo) -> Result<State, ProgramError> {
+        let (state_pda, _) = State::find_address(program_id);
+        if *state_account.key != state_pda {
+            return Err(error::GachaError::InvalidStateAccount.into());
+        }
+        State::load(state_account, program_id)
+    }
+
+    /// Borrows just the counters and configuration of the state, in place.
+    fn load_state_header<'a>(
+        program_id: &Pubkey,
+        state_account: &'a AccountInfo,
+    ) -> Result<Ref<'a, StateHeader>, ProgramError> {
+        Self::check_state_account(program_id, state_account)?;
+        let mut error = ProgramError::InvalidAccountData;
+        Ref::filter_map(state_account.try_borrow_data()?, |data| {
+            StateHeader::from_account_data(data)
+                .map_err(|err| error = err)
+                .ok()
+        })
+        .map_err(|_| error)
+    }
+
+    /// Like `load_state_header`, for handlers that update the header.
+    fn load_state_header_mut<'a>(
+        program_id: &Pubkey,
+        state_account: &'a AccountInfo,
+    ) -> Result<RefMut<'a, StateHeader>, ProgramError> {
+        Self::check_state_account(program_id, state_account)?;
+        if !state_account.is_writable {
+            return Err(error::GachaError::AccountNotWritable.into());
+        }
+        let mut error = ProgramError::InvalidAccountData;
+        RefMut::filter_map(state_account.try_borrow_mut_data()?, |data| {
+            StateHeader::from_account_data_mut(data)
+                .map_err(|err| error = err)
+                .ok()
+        })
+        .map_err(|_| error)
+    }
+
+    fn check_state_account(program_id: &Pubkey, state_account: &AccountInfo) -> ProgramResult {
+        let (state_pda, _) = State::find_address(program_id);
+        if *state_account.key != state_pda {
+            return Err(error::GachaError::InvalidStateAccount.into());
+        }
+        if state_account.owner != program_id {
+            return Err(error::GachaError::InvalidAccountOwner.into());
+        }
+        Ok(())
+    }
+
+    /// Saves `state`, first growing the account when the data no longer fits.
+    /// `payer` tops the account up so it stays rent exempt.
+    fn save_state<'a>(
+        state: &State,
+        state_account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        let required = state.packed_len()?;
+        let current = state_account.data_len();
+        if required > current {
+            if required > State::MAX_LEN || required - current > MAX_PERMITTED_DATA_INCREASE {
+                return Err(error::GachaError::StateTooLarge.into());
+            }
+            debug_msg!("Growing state from {} to {} bytes", current, required);
+            Self::grow_account(state_account, payer, system_program, required)?;
+        }
+        state.save(state_account)
+    }
+
+    /// Reallocs `account` to `len` bytes, with `payer` topping up its rent.
+    fn grow_account<'a>(
+        account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        len: usize,
+    ) -> ProgramResult {
+        let lamports = Rent::get()?
+            .minimum_balance(len)
+            .saturating_sub(account.lamports());
+        if lamports > 0 {
+            invoke(
+                &transfer(payer.key, account.key, lamports),
+                &[payer.clone(), account.clone(), system_program.clone()],
+            )?;
+        }
+        account.realloc(len, true)
+    }
+
+    /// Rejects any token program other than SPL Token before it is invoked.
+    fn check_token_program(token_program: &AccountInfo) -> ProgramResult {
+        if *token_program.key != spl_token::id() {
+            return Err(error::GachaError::InvalidTokenProgram.into());
+        }
+        Ok(())
+    }
+
+    /// Fails unless `mint` is an SPL Token mint of a single, indivisible unit.
+    fn check_nft(mint: &AccountInfo) -> ProgramResult {
+        if *mint.owner != spl_token::id() {
+            return Err(error::GachaError::InvalidAccountOwner.into());
+        }
+        let mint = Mint::unpack(&mint.data.borrow())?;
+        if mint.supply != 1 || mint.decimals != 0 {
+            return Err(error::GachaError::NotAnNft.into());
+        }
+        Ok(())
+    }
+
+    /// Fails unless `authority` signed and holds `role`. A multisig holding
+    /// the role signs through `execute_multisig_action`.
+    fn check_role(
+        program_id: &Pubkey,
+        state_account: &AccountInfo,
+        authority: &AccountInfo,
+        role: Role,
+    ) -> ProgramResult {
+        let holder = Self::load_state_header(program_id, state_account)?.role_holder(role);
+        if !authority.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if holder != *authority.key {
+            return Err(match role {
+                Role::Owner => error::GachaError::NotMarketplaceOwner,
+                _ => error::GachaError::MissingRole,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Fails with `Paused` while any of `feature` is paused.
+    fn check_not_paused(
+        program_id: &Pubkey,
+        state_account: &AccountInfo,
+        feature: u32,
+    ) -> ProgramResult {
+        if Self::load_state_header(program_id, state_account)?.is_paused(feature) {
+            return Err(error::GachaError::Paused.into());
+        }
+        Ok(())
+    }
+
+    /// Drains a program owned account into `destination` so the runtime reclaims it.
+    fn close_program_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+        Self::move_lamports(account, destination, account.lamports())?;
+        account.try_borrow_mut_data()?.fill(0);
+        Ok(())
+    }
+
+    /// Moves lamports out of an account owned by this program without a CPI.
+    fn move_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> ProgramResult {
+        let from_balance = from
+            .lamports()
+            .checked_sub(amount)
+            .ok_or(error::GachaError::MathOverflow)?;
+        let to_balance = to
+            .lamports()
+            .checked_add(amount)
+            .ok_or(error::GachaError::MathOverflow)?;
+        **from.try_borrow_mut_lamports()? = from_balance;
+        **to.try_borrow_mut_lamports()? = to_balance;
+        Ok(())
+    }
+
+    /// Seeds the draw from the clock and the caller so that repeated draws differ.
+    fn gacha_rng(authority: &Pubkey, item_ids: u128, item_sold: u128) -> Result<ChaCha, ProgramError> {
+        let clock = Clock::get()?;
+        let key = hashv(&[
+            &clock.slot.to_le_bytes(),
+            &clock.unix_timestamp.to_le_bytes(),
+            authority.as_ref(),
+            &item_ids.to_le_bytes(),
+            &item_sold.to_le_bytes(),
+        ])
+        .to_bytes();
+        Ok(ChaCha::new_key(20, key, clock.slot.to_le_bytes()))
+    }
 }
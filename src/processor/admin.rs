@@ -0,0 +1,476 @@
+use std::collections::BTreeSet;
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use super::Processor;
+use crate::{
+    error::GachaError,
+    event::{ConfigChange, MarketplaceEvent},
+    state::{Multisig, MultisigProposal, ProgramAccount, Role, State},
+};
+
+impl Processor {
+    /// Hands one of the delegated roles to `holder`. Ownership itself moves
+    /// through `transfer_ownership`.
+    pub(super) fn set_role(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        role: Role,
+        holder: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+
+        Self::check_role(program_id, state_account, authority_account, Role::Owner)?;
+        let mut header = Self::load_state_header_mut(program_id, state_account)?;
+
+        match role {
+            Role::Owner => return Err(ProgramError::InvalidInstructionData),
+            Role::FeeManager => header.roles.fee_manager = holder,
+            Role::Pauser => header.roles.pauser = holder,
+            Role::PoolCurator => header.roles.pool_curator = holder,
+            Role::UpgradeAuthority => header.roles.upgrade_authority = holder,
+        }
+        MarketplaceEvent::RoleSet { role, holder }.emit();
+        Ok(())
+    }
+
+    /// Replaces the set of paused features, see `State::PAUSE_*`.
+    pub(super) fn set_paused(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        paused: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+
+        Self::check_role(program_id, state_account, authority_account, Role::Pauser)?;
+        Self::load_state_header_mut(program_id, state_account)?.paused = paused.into();
+        MarketplaceEvent::PausedSet { paused }.emit();
+        Ok(())
+    }
+
+    /// Retunes marketplace parameters in place. Fee settings need the fee
+    /// manager, moving the treasury needs the owner. Every change is emitted
+    /// as a `ConfigUpdated` event.
+    pub(super) fn update_config(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        listing_price: Option<u64>,
+        fee_bps: Option<u16>,
+        max_gacha_qty: Option<u8>,
+        treasury: Option<Pubkey>,
+        gacha_fee: Option<u64>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+
+        if listing_price.is_some()
+            || fee_bps.is_some()
+            || max_gacha_qty.is_some()
+            || gacha_fee.is_some()
+        {
+            Self::check_role(
+                program_id,
+                state_account,
+                authority_account,
+                Role::FeeManager,
+            )?;
+        }
+        if treasury.is_some() {
+            Self::check_role(program_id, state_account, authority_account, Role::Owner)?;
+        }
+        let mut header = Self::load_state_header_mut(program_id, state_account)?;
+
+        if let Some(listing_price) = listing_price {
+            Self::emit_config_change(ConfigChange::ListingPrice {
+                old: header.listing_price.into(),
+                new: listing_price,
+            });
+            header.listing_price = listing_price.into();
+        }
+        if let Some(fee_bps) = fee_bps {
+            if fee_bps > State::MAX_FEE_BPS {
+                return Err(GachaError::InvalidAmount.into());
+            }
+            Self::emit_config_change(ConfigChange::FeeBps {
+                old: header.fee_bps.into(),
+                new: fee_bps,
+            });
+            header.fee_bps = fee_bps.into();
+        }
+        if let Some(max_gacha_qty) = max_gacha_qty {
+            if max_gacha_qty < 1 {
+                return Err(GachaError::InvalidAmount.into());
+            }
+            Self::emit_config_change(ConfigChange::MaxGachaQty {
+                old: header.max_gacha_qty,
+                new: max_gacha_qty,
+            });
+            header.max_gacha_qty = max_gacha_qty;
+        }
+        if let Some(treasury) = treasury {
+            Self::emit_config_change(ConfigChange::Treasury {
+                old: header.treasury,
+                new: treasury,
+            });
+            header.treasury = treasury;
+        }
+        if let Some(gacha_fee) = gacha_fee {
+            Self::emit_config_change(ConfigChange::GachaFee {
+                old: header.gacha_fee.into(),
+                new: gacha_fee,
+            });
+            header.gacha_fee = gacha_fee.into();
+        }
+        Ok(())
+    }
+
+    fn emit_config_change(change: ConfigChange) {
+        MarketplaceEvent::ConfigUpdated { change }.emit();
+    }
+
+    /// Rewrites an older state in the current layout, growing the account
+    /// as needed with the upgrade authority paying the extra rent. A state that
+    /// is already current is left untouched.
+    pub(super) fn migrate_state(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        // the header view only understands the current layout, so the role is
+        // checked against the fully decoded (possibly legacy) state instead
+        if !authority_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let state = Self::load_state(program_id, state_account)?;
+        if *authority_account.key != state.role_holder(Role::UpgradeAuthority) {
+            return Err(GachaError::MissingRole.into());
+        }
+        let version = State::account_version(&state_account.try_borrow_data()?)?;
+        if version == State::VERSION {
+            return Ok(());
+        }
+
+        Self::save_state(&state, state_account, authority_account, system_program)?;
+        MarketplaceEvent::StateMigrated {
+            from_version: version,
+            to_version: State::VERSION,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// First step of an ownership transfer, `new_owner` still has to accept.
+    pub(super) fn transfer_ownership(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        new_owner: Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+
+        Self::check_role(program_id, state_account, authority_account, Role::Owner)?;
+        Self::load_state_header_mut(program_id, state_account)?.pending_owner = new_owner;
+        MarketplaceEvent::OwnershipTransferStarted {
+            owner: *authority_account.key,
+            pending_owner: new_owner,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn accept_ownership(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let new_owner_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+
+        if !new_owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let mut header = Self::load_state_header_mut(program_id, state_account)?;
+        if header.pending_owner() != Some(*new_owner_account.key) {
+            return Err(GachaError::NotPendingOwner.into());
+        }
+
+        let previous_owner = header.owner;
+        header.owner = *new_owner_account.key;
+        header.pending_owner = Pubkey::default();
+        MarketplaceEvent::OwnershipTransferred {
+            previous_owner,
+            new_owner: header.owner,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn create_multisig(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        seed: Pubkey,
+        signers: Vec<Pubkey>,
+        threshold: u8,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let payer_account = next_account_info(account_info_iter)?;
+        let multisig_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        if !payer_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if signers.len() > Multisig::MAX_SIGNERS
+            || threshold < 1
+            || threshold as usize > signers.len()
+            || signers.iter().collect::<BTreeSet<_>>().len() != signers.len()
+        {
+            return Err(GachaError::InvalidMultisig.into());
+        }
+
+        let (multisig_pda, multisig_bump) = Multisig::find_address(program_id, &seed);
+        if multisig_pda != *multisig_account.key || !multisig_account.data_is_empty() {
+            return Err(GachaError::InvalidMultisig.into());
+        }
+
+        Self::create_program_account(
+            program_id,
+            payer_account,
+            multisig_account,
+            system_program,
+            Multisig::space(signers.len()),
+            0,
+            &[Multisig::SEED, seed.as_ref(), &[multisig_bump]],
+        )?;
+
+        let multisig = Multisig {
+            seed,
+            signers,
+            threshold,
+            proposal_count: 0,
+            bump: multisig_bump,
+        };
+        multisig.save(multisig_account)?;
+        MarketplaceEvent::MultisigCreated {
+            multisig: *multisig_account.key,
+            signers: multisig.signers,
+            threshold,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Records an instruction for the multisig to run, approved by the proposer.
+    pub(super) fn propose_multisig_action(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        action_accounts: Vec<Pubkey>,
+        data: Vec<u8>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let proposer_account = next_account_info(account_info_iter)?;
+        let multisig_account = next_account_info(account_info_iter)?;
+        let proposal_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let mut multisig = Self::load_multisig(program_id, multisig_account)?;
+        Self::check_multisig_signer(&multisig, proposer_account)?;
+        if action_accounts.len() > MultisigProposal::MAX_ACCOUNTS
+            || data.len() > MultisigProposal::MAX_DATA
+        {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+
+        let index = multisig.proposal_count;
+        let (proposal_pda, proposal_bump) =
+            MultisigProposal::find_address(program_id, multisig_account.key, index);
+        if proposal_pda != *proposal_account.key || !proposal_account.data_is_empty() {
+            return Err(GachaError::InvalidMultisig.into());
+        }
+
+        Self::create_program_account(
+            program_id,
+            proposer_account,
+            proposal_account,
+            system_program,
+            MultisigProposal::space(action_accounts.len(), data.len(), multisig.signers.len()),
+            0,
+            &[
+                MultisigProposal::SEED,
+                multisig_account.key.as_ref(),
+                &index.to_le_bytes(),
+                &[proposal_bump],
+            ],
+        )?;
+
+        let proposal = MultisigProposal {
+            multisig: *multisig_account.key,
+            index,
+            proposer: *proposer_account.key,
+            accounts: action_accounts,
+            data,
+            approvals: vec![*proposer_account.key],
+            bump: proposal_bump,
+        };
+        proposal.save(proposal_account)?;
+
+        multisig.proposal_count = multisig
+            .proposal_count
+            .checked_add(1)
+            .ok_or(GachaError::MathOverflow)?;
+        multisig.save(multisig_account)?;
+        MarketplaceEvent::MultisigActionProposed {
+            multisig: *multisig_account.key,
+            index,
+            proposer: *proposer_account.key,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn approve_multisig_action(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let signer_account = next_account_info(account_info_iter)?;
+        let multisig_account = next_account_info(account_info_iter)?;
+        let proposal_account = next_account_info(account_info_iter)?;
+
+        let multisig = Self::load_multisig(program_id, multisig_account)?;
+        Self::check_multisig_signer(&multisig, signer_account)?;
+        let mut proposal = Self::load_proposal(program_id, multisig_account, proposal_account)?;
+
+        if !proposal.approvals.contains(signer_account.key) {
+            proposal.approvals.push(*signer_account.key);
+        }
+        proposal.save(proposal_account)?;
+        MarketplaceEvent::MultisigActionApproved {
+            multisig: *multisig_account.key,
+            index: proposal.index,
+            signer: *signer_account.key,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Runs an approved proposal by calling back into this program with the
+    /// multisig PDA as signer, then closes the proposal. Anyone may execute.
+    pub(super) fn execute_multisig_action(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let _executor_account = next_account_info(account_info_iter)?;
+        let multisig_account = next_account_info(account_info_iter)?;
+        let proposal_account = next_account_info(account_info_iter)?;
+        let proposer_account = next_account_info(account_info_iter)?;
+        let program_account = next_account_info(account_info_iter)?;
+        // followed by the proposal's accounts, in order
+        let action_accounts = account_info_iter.as_slice();
+
+        let multisig = Self::load_multisig(program_id, multisig_account)?;
+        let proposal = Self::load_proposal(program_id, multisig_account, proposal_account)?;
+        if *proposer_account.key != proposal.proposer || program_account.key != program_id {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if proposal.approvals.len() < multisig.threshold as usize {
+            return Err(GachaError::ProposalNotApproved.into());
+        }
+
+        if action_accounts.len() != proposal.accounts.len()
+            || action_accounts
+                .iter()
+                .zip(proposal.accounts.iter())
+                .any(|(account, expected)| account.key != expected)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let instruction = Instruction {
+            program_id: *program_id,
+            accounts: action_accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer || account.key == multisig_account.key,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: proposal.data,
+        };
+        let mut account_infos = action_accounts.to_vec();
+        account_infos.push(program_account.clone());
+        invoke_signed(
+            &instruction,
+            &account_infos,
+            &[&[Multisig::SEED, multisig.seed.as_ref(), &[multisig.bump]]],
+        )?;
+
+        Self::close_program_account(proposal_account, proposer_account)?;
+        MarketplaceEvent::MultisigActionExecuted {
+            multisig: *multisig_account.key,
+            index: proposal.index,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn load_multisig(
+        program_id: &Pubkey,
+        multisig_account: &AccountInfo,
+    ) -> Result<Multisig, ProgramError> {
+        let multisig = Multisig::load(multisig_account, program_id)?;
+        let (multisig_pda, _) = Multisig::find_address(program_id, &multisig.seed);
+        if multisig_pda != *multisig_account.key {
+            return Err(GachaError::InvalidMultisig.into());
+        }
+        Ok(multisig)
+    }
+
+    fn load_proposal(
+        program_id: &Pubkey,
+        multisig_account: &AccountInfo,
+        proposal_account: &AccountInfo,
+    ) -> Result<MultisigProposal, ProgramError> {
+        let proposal = MultisigProposal::load(proposal_account, program_id)?;
+        let (proposal_pda, _) =
+            MultisigProposal::find_address(program_id, multisig_account.key, proposal.index);
+        if proposal_pda != *proposal_account.key || proposal.multisig != *multisig_account.key {
+            return Err(GachaError::InvalidMultisig.into());
+        }
+        Ok(proposal)
+    }
+
+    fn check_multisig_signer(multisig: &Multisig, signer_account: &AccountInfo) -> ProgramResult {
+        if !signer_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !multisig.signers.contains(signer_account.key) {
+            return Err(GachaError::NotMultisigSigner.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use super::Processor;
+use crate::{
+    error::GachaError,
+    event::MarketplaceEvent,
+    instruction::LoyaltyReward,
+    state::{LoyaltyAccount, LoyaltyConfig, MintCollection, ProgramAccount, Role, State},
+};
+
+impl Processor {
+    pub(super) fn set_loyalty_config(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        lamports_per_point: u64,
+        point_value: u64,
+        points_per_free_draw: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::FeeManager,
+        )?;
+        let mut header = Self::load_state_header_mut(program_id, state_account)?;
+
+        header.lamports_per_point = lamports_per_point.into();
+        header.point_value = point_value.into();
+        header.points_per_free_draw = points_per_free_draw.into();
+        MarketplaceEvent::LoyaltyConfigSet {
+            lamports_per_point,
+            point_value,
+            points_per_free_draw,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Sets the points multiplier of `collection`, growing the state with
+    /// the authority paying the extra rent.
+    pub(super) fn set_collection_multiplier(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        collection: Pubkey,
+        multiplier_bps: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::FeeManager,
+        )?;
+        let mut state = Self::load_state(program_id, state_account)?;
+
+        let multipliers = &mut state.loyalty.collection_multipliers;
+        if multiplier_bps == LoyaltyConfig::BASE_MULTIPLIER {
+            multipliers.remove(&collection);
+        } else {
+            multipliers.insert(collection, multiplier_bps);
+            if multipliers.len() > LoyaltyConfig::MAX_COLLECTIONS {
+                return Err(GachaError::TooManyCollections.into());
+            }
+        }
+        Self::save_state(&state, state_account, authority_account, system_program)?;
+        MarketplaceEvent::CollectionMultiplierSet {
+            collection,
+            multiplier_bps,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Registers the collection of `mint`, which its later listings earn
+    /// the multiplier of. Existing listings keep the collection they have.
+    pub(super) fn set_mint_collection(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        collection: Option<Pubkey>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let collection_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::FeeManager,
+        )?;
+
+        let (collection_pda, collection_bump) = MintCollection::find_address(program_id, mint.key);
+        if collection_pda != *collection_account.key {
+            return Err(GachaError::InvalidCollectionAccount.into());
+        }
+        if collection_account.data_is_empty() {
+            Self::create_program_account(
+                program_id,
+                authority_account,
+                collection_account,
+                system_program,
+                MintCollection::LEN,
+                0,
+                &[MintCollection::SEED, mint.key.as_ref(), &[collection_bump]],
+            )?;
+        }
+
+        let entry = MintCollection {
+            mint: *mint.key,
+            collection,
+        };
+        entry.save(collection_account)?;
+        MarketplaceEvent::MintCollectionSet {
+            mint: *mint.key,
+            collection,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// The collection registered for `mint`, if any.
+    pub(super) fn load_mint_collection(
+        program_id: &Pubkey,
+        mint: &AccountInfo,
+        collection_account: &AccountInfo,
+    ) -> Result<Option<Pubkey>, ProgramError> {
+        let (collection_pda, _) = MintCollection::find_address(program_id, mint.key);
+        if collection_pda != *collection_account.key {
+            return Err(GachaError::InvalidCollectionAccount.into());
+        }
+        if collection_account.owner != program_id {
+            return Ok(None);
+        }
+        Ok(MintCollection::load(collection_account, program_id)?.collection)
+    }
+
+    /// Turns points into fee credit or free gacha draws.
+    pub(super) fn redeem_loyalty_points(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        reward: LoyaltyReward,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let wallet_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let loyalty_account = next_account_info(account_info_iter)?;
+
+        if !wallet_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let header = *Self::load_state_header(program_id, state_account)?;
+        if header.is_paused(State::PAUSE_LOYALTY) {
+            return Err(GachaError::Paused.into());
+        }
+        let point_value = u64::from(header.point_value);
+        let points_per_free_draw = u64::from(header.points_per_free_draw);
+        let mut loyalty = Self::load_loyalty(program_id, wallet_account, loyalty_account)?;
+        if loyalty_account.data_is_empty() {
+            return Err(GachaError::InsufficientPoints.into());
+        }
+
+        let cost = match reward {
+            LoyaltyReward::FeeCredit { points } => {
+                if point_value == 0 {
+                    return Err(GachaError::InvalidAmount.into());
+                }
+                loyalty.fee_credit = points
+                    .checked_mul(point_value)
+                    .and_then(|credit| loyalty.fee_credit.checked_add(credit))
+                    .ok_or(GachaError::MathOverflow)?;
+                points
+            }
+            LoyaltyReward::FreeDraws { draws } => {
+                if points_per_free_draw == 0 {
+                    return Err(GachaError::InvalidAmount.into());
+                }
+                loyalty.free_draws = loyalty
+                    .free_draws
+                    .checked_add(draws)
+                    .ok_or(GachaError::MathOverflow)?;
+                points_per_free_draw
+                    .checked_mul(draws as u64)
+                    .ok_or(GachaError::InsufficientPoints)?
+            }
+        };
+        if cost < 1 {
+            return Err(GachaError::InvalidAmount.into());
+        }
+        loyalty.points = loyalty
+            .points
+            .checked_sub(cost)
+            .ok_or(GachaError::InsufficientPoints)?;
+
+        loyalty.save(loyalty_account)?;
+        MarketplaceEvent::LoyaltyPointsRedeemed {
+            wallet: *wallet_account.key,
+            points: cost,
+            reward,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Reads `wallet`'s loyalty account, or a zeroed one if it does not exist yet.
+    pub(super) fn load_loyalty(
+        program_id: &Pubkey,
+        wallet_account: &AccountInfo,
+        loyalty_account: &AccountInfo,
+    ) -> Result<LoyaltyAccount, ProgramError> {
+        let (loyalty_pda, loyalty_bump) =
+            LoyaltyAccount::find_address(program_id, wallet_account.key);
+        if loyalty_pda != *loyalty_account.key {
+            return Err(GachaError::InvalidLoyaltyAccount.into());
+        }
+        if loyalty_account.data_is_empty() {
+            return Ok(LoyaltyAccount {
+                wallet: *wallet_account.key,
+                bump: loyalty_bump,
+                ..LoyaltyAccount::default()
+            });
+        }
+        LoyaltyAccount::load(loyalty_account, program_id)
+    }
+
+    /// Writes `loyalty` back, creating the account on the wallet's first points.
+    pub(super) fn save_loyalty<'a>(
+        program_id: &Pubkey,
+        wallet_account: &AccountInfo<'a>,
+        loyalty_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        loyalty: &LoyaltyAccount,
+    ) -> ProgramResult {
+        if loyalty_account.data_is_empty() {
+            if loyalty.points == 0 {
+                return Ok(());
+            }
+            Self::create_program_account(
+                program_id,
+                wallet_account,
+                loyalty_account,
+                system_program,
+                LoyaltyAccount::LEN,
+                0,
+                &[
+                    LoyaltyAccount::SEED,
+                    wallet_account.key.as_ref(),
+                    &[loyalty.bump],
+                ],
+            )?;
+        }
+        loyalty.save(loyalty_account)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program::invoke,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_instruction::transfer,
+};
+
+use super::Processor;
+use crate::{
+    error::GachaError,
+    event::MarketplaceEvent,
+    state::{ProgramAccount, ReferrerAccount, Role, State},
+};
+
+impl Processor {
+    pub(super) fn register_referrer(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let wallet_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let referrer_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        if !wallet_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_not_paused(program_id, state_account, State::PAUSE_REFERRALS)?;
+        let (referrer_pda, referrer_bump) =
+            ReferrerAccount::find_address(program_id, wallet_account.key);
+        if referrer_pda != *referrer_account.key || !referrer_account.data_is_empty() {
+            return Err(GachaError::InvalidReferrerAccount.into());
+        }
+
+        Self::create_program_account(
+            program_id,
+            wallet_account,
+            referrer_account,
+            system_program,
+            ReferrerAccount::LEN,
+            0,
+            &[
+                ReferrerAccount::SEED,
+                wallet_account.key.as_ref(),
+                &[referrer_bump],
+            ],
+        )?;
+
+        let referrer = ReferrerAccount {
+            wallet: *wallet_account.key,
+            total_earned: 0,
+            unclaimed: 0,
+            bump: referrer_bump,
+        };
+        referrer.save(referrer_account)?;
+        MarketplaceEvent::ReferrerRegistered {
+            wallet: *wallet_account.key,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn set_referral_bps(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        referral_bps: u16,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::FeeManager,
+        )?;
+        if referral_bps > ReferrerAccount::MAX_BPS {
+            return Err(GachaError::InvalidAmount.into());
+        }
+        Self::load_state_header_mut(program_id, state_account)?.referral_bps = referral_bps.into();
+        MarketplaceEvent::ReferralBpsSet { referral_bps }.emit();
+        Ok(())
+    }
+
+    /// Sends everything the referrer has earned so far to their wallet.
+    pub(super) fn claim_referral_earnings(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let wallet_account = next_account_info(account_info_iter)?;
+        let referrer_account = next_account_info(account_info_iter)?;
+
+        if !wallet_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let mut referrer = Self::load_referrer(program_id, referrer_account, wallet_account.key)?;
+        if referrer.unclaimed == 0 {
+            return Err(GachaError::InvalidAmount.into());
+        }
+
+        Self::move_lamports(referrer_account, wallet_account, referrer.unclaimed)?;
+        MarketplaceEvent::ReferralEarningsClaimed {
+            wallet: *wallet_account.key,
+            amount: referrer.unclaimed,
+        }
+        .emit();
+        referrer.unclaimed = 0;
+        referrer.save(referrer_account)?;
+        Ok(())
+    }
+
+    /// Pays the marketplace `fee` from the buyer, routing the configured
+    /// referral slice to the referrer's PDA and the rest to the owner. While
+    /// referrals are paused the whole fee goes to the owner.
+    pub(super) fn pay_marketplace_fee<'a>(
+        program_id: &Pubkey,
+        state: &State,
+        buyer_account: &AccountInfo<'a>,
+        owner_account: &AccountInfo<'a>,
+        referrer: Option<(&AccountInfo<'a>, Pubkey)>,
+        system_program: &AccountInfo<'a>,
+        fee: u64,
+    ) -> ProgramResult {
+        let mut owner_fee = fee;
+        let mut referral_amount = 0;
+        let referrer = referrer.filter(|_| !state.is_paused(State::PAUSE_REFERRALS));
+
+        if let Some((referrer_account, referrer_wallet)) = referrer {
+            if referrer_wallet == *buyer_account.key {
+                return Err(GachaError::SelfReferral.into());
+            }
+            let mut referrer = Self::load_referrer(program_id, referrer_account, &referrer_wallet)?;
+            let cut = (fee as u128 * state.referral_bps as u128 / ReferrerAccount::MAX_BPS as u128)
+                as u64;
+            if cut > 0 {
+                invoke(
+                    &transfer(buyer_account.key, referrer_account.key, cut),
+                    &[
+                        buyer_account.clone(),
+                        referrer_account.clone(),
+                        system_program.clone(),
+                    ],
+                )?;
+                referrer.total_earned = referrer.total_earned.saturating_add(cut);
+                referrer.unclaimed = referrer
+                    .unclaimed
+                    .checked_add(cut)
+                    .ok_or(GachaError::MathOverflow)?;
+                referrer.save(referrer_account)?;
+                owner_fee = owner_fee.checked_sub(cut).ok_or(GachaError::MathOverflow)?;
+                referral_amount = cut;
+            }
+        }
+
+        if owner_fee > 0 {
+            invoke(
+                &transfer(buyer_account.key, owner_account.key, owner_fee),
+                &[
+                    buyer_account.clone(),
+                    owner_account.clone(),
+                    system_program.clone(),
+                ],
+            )?;
+        }
+        if fee > 0 {
+            MarketplaceEvent::FeesCollected {
+                payer: *buyer_account.key,
+                treasury: *owner_account.key,
+                amount: fee,
+                referrer: referrer.map(|(_, wallet)| wallet),
+                referral_amount,
+            }
+            .emit();
+        }
+        Ok(())
+    }
+
+    fn load_referrer(
+        program_id: &Pubkey,
+        referrer_account: &AccountInfo,
+        wallet: &Pubkey,
+    ) -> Result<ReferrerAccount, ProgramError> {
+        let (referrer_pda, _) = ReferrerAccount::find_address(program_id, wallet);
+        if referrer_pda != *referrer_account.key {
+            return Err(GachaError::InvalidReferrerAccount.into());
+        }
+        ReferrerAccount::load(referrer_account, program_id)
+    }
+}
@@ -0,0 +1,425 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    program_option::COption,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_instruction::transfer,
+    sysvar::Sysvar,
+};
+use spl_associated_token_account::{
+    get_associated_token_address, get_associated_token_address_with_program_id,
+};
+use spl_token::{instruction::AuthorityType, state::Mint};
+
+use super::Processor;
+use crate::{
+    error::GachaError,
+    event::MarketplaceEvent,
+    state::{ProgramAccount, RentalListing, State},
+};
+
+impl Processor {
+    pub(super) fn create_rental(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        price_per_period: u64,
+        period_secs: i64,
+        max_periods: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let rental_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let token_account = next_account_info(account_info_iter)?;
+        let vault_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_not_paused(program_id, state_account, State::PAUSE_RENTALS)?;
+        if price_per_period < 1 {
+            return Err(GachaError::InvalidPrice.into());
+        }
+        if period_secs < 1 || max_periods < 1 {
+            return Err(GachaError::InvalidRentalPeriod.into());
+        }
+        Self::check_nft(mint)?;
+        if Mint::unpack(&mint.data.borrow())?.freeze_authority != COption::Some(*owner_account.key)
+        {
+            return Err(GachaError::InvalidFreezeAuthority.into());
+        }
+
+        let (rental_pda, rental_bump) = RentalListing::find_address(program_id, mint.key);
+        if rental_pda != *rental_account.key || !rental_account.data_is_empty() {
+            return Err(GachaError::InvalidRentalAccount.into());
+        }
+
+        Self::create_program_account(
+            program_id,
+            owner_account,
+            rental_account,
+            system_program,
+            RentalListing::LEN,
+            0,
+            &[RentalListing::SEED, mint.key.as_ref(), &[rental_bump]],
+        )?;
+
+        // the NFT waits in the rental PDA's vault between renters, and the
+        // PDA holds the freeze authority until the listing is reclaimed
+        invoke(
+            &spl_token::instruction::set_authority(
+                token_program.key,
+                mint.key,
+                Some(&rental_pda),
+                AuthorityType::FreezeAccount,
+                owner_account.key,
+                &[],
+            )?,
+            &[mint.clone(), owner_account.clone(), token_program.clone()],
+        )?;
+        Self::create_token_account(
+            owner_account,
+            vault_account,
+            rental_account,
+            mint,
+            system_program,
+            token_program,
+            associated_token_program,
+        )?;
+        Self::transfer_tokens(
+            token_program,
+            token_account,
+            mint,
+            vault_account,
+            owner_account,
+            1,
+            &[],
+        )?;
+
+        let rental = RentalListing {
+            owner: *owner_account.key,
+            mint: *mint.key,
+            price_per_period,
+            period_secs,
+            max_periods,
+            renter: None,
+            expires_at: 0,
+            bump: rental_bump,
+        };
+        rental.save(rental_account)?;
+        MarketplaceEvent::RentalCreated {
+            owner: rental.owner,
+            mint: rental.mint,
+            price_per_period,
+            period_secs,
+            max_periods,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Moves the NFT into the renter's associated token account, frozen with
+    /// the rental PDA as delegate so it can be taken back at expiry. After an
+    /// earlier rental it is taken straight from the previous renter.
+    pub(super) fn rent_item(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        periods: u32,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let renter_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let owner_account = next_account_info(account_info_iter)?;
+        let rental_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let vault_account = next_account_info(account_info_iter)?;
+        let renter_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !renter_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_not_paused(program_id, state_account, State::PAUSE_RENTALS)?;
+        let mut rental = Self::load_rental(program_id, rental_account)?;
+        if rental.owner != *owner_account.key {
+            return Err(GachaError::InvalidOwnerAccount.into());
+        }
+        if rental.mint != *mint.key {
+            return Err(GachaError::WrongMint.into());
+        }
+        if *vault_account.key
+            != get_associated_token_address_with_program_id(
+                rental_account.key,
+                mint.key,
+                token_program.key,
+            )
+            || *renter_token_account.key
+                != get_associated_token_address(renter_account.key, mint.key)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if periods < 1 || periods > rental.max_periods {
+            return Err(GachaError::InvalidRentalPeriod.into());
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        if rental.active_renter(now).is_some() {
+            return Err(GachaError::RentalActive.into());
+        }
+
+        let price = rental
+            .price_per_period
+            .checked_mul(periods as u64)
+            .ok_or(GachaError::MathOverflow)?;
+        let expires_at = rental
+            .period_secs
+            .checked_mul(periods as i64)
+            .and_then(|duration| now.checked_add(duration))
+            .ok_or(GachaError::MathOverflow)?;
+
+        invoke(
+            &transfer(renter_account.key, owner_account.key, price),
+            &[
+                renter_account.clone(),
+                owner_account.clone(),
+                system_program.clone(),
+            ],
+        )?;
+
+        let rental_seeds: &[&[u8]] = &[RentalListing::SEED, mint.key.as_ref(), &[rental.bump]];
+        let holder_account = Self::rented_holder(
+            account_info_iter,
+            &rental,
+            mint,
+            vault_account,
+            rental_account,
+            token_program,
+        )?;
+        Self::create_token_account(
+            renter_account,
+            renter_token_account,
+            renter_account,
+            mint,
+            system_program,
+            token_program,
+            associated_token_program,
+        )?;
+        Self::transfer_tokens(
+            token_program,
+            holder_account,
+            mint,
+            renter_token_account,
+            rental_account,
+            1,
+            &[rental_seeds],
+        )?;
+        invoke(
+            &spl_token::instruction::approve(
+                token_program.key,
+                renter_token_account.key,
+                rental_account.key,
+                renter_account.key,
+                &[],
+                1,
+            )?,
+            &[
+                renter_token_account.clone(),
+                rental_account.clone(),
+                renter_account.clone(),
+                token_program.clone(),
+            ],
+        )?;
+        // frozen, the renter can neither move the NFT nor revoke the delegate
+        invoke_signed(
+            &spl_token::instruction::freeze_account(
+                token_program.key,
+                renter_token_account.key,
+                mint.key,
+                rental_account.key,
+                &[],
+            )?,
+            &[
+                renter_token_account.clone(),
+                mint.clone(),
+                rental_account.clone(),
+                token_program.clone(),
+            ],
+            &[rental_seeds],
+        )?;
+
+        rental.renter = Some(*renter_account.key);
+        rental.expires_at = expires_at;
+        rental.save(rental_account)?;
+        MarketplaceEvent::ItemRented {
+            mint: rental.mint,
+            renter: *renter_account.key,
+            paid: price,
+            expires_at,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Ends the listing and returns the NFT and its freeze authority to the
+    /// owner. Anyone may call it once a rental has expired; otherwise only the
+    /// owner may.
+    pub(super) fn reclaim_rental(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let owner_account = next_account_info(account_info_iter)?;
+        let rental_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let vault_account = next_account_info(account_info_iter)?;
+        let token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !authority_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let rental = Self::load_rental(program_id, rental_account)?;
+        if rental.owner != *owner_account.key {
+            return Err(GachaError::InvalidOwnerAccount.into());
+        }
+        if rental.mint != *mint.key {
+            return Err(GachaError::WrongMint.into());
+        }
+        if *vault_account.key
+            != get_associated_token_address_with_program_id(
+                rental_account.key,
+                mint.key,
+                token_program.key,
+            )
+            || *token_account.key != get_associated_token_address(&rental.owner, mint.key)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let now = Clock::get()?.unix_timestamp;
+        if rental.active_renter(now).is_some() {
+            return Err(GachaError::RentalActive.into());
+        }
+        if rental.renter.is_none() && *authority_account.key != rental.owner {
+            return Err(GachaError::NotRentalOwner.into());
+        }
+
+        let rental_seeds: &[&[u8]] = &[RentalListing::SEED, mint.key.as_ref(), &[rental.bump]];
+        let holder_account = Self::rented_holder(
+            account_info_iter,
+            &rental,
+            mint,
+            vault_account,
+            rental_account,
+            token_program,
+        )?;
+        Self::transfer_tokens(
+            token_program,
+            holder_account,
+            mint,
+            token_account,
+            rental_account,
+            1,
+            &[rental_seeds],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::close_account(
+                token_program.key,
+                vault_account.key,
+                owner_account.key,
+                rental_account.key,
+                &[],
+            )?,
+            &[
+                vault_account.clone(),
+                owner_account.clone(),
+                rental_account.clone(),
+                token_program.clone(),
+            ],
+            &[rental_seeds],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::set_authority(
+                token_program.key,
+                mint.key,
+                Some(owner_account.key),
+                AuthorityType::FreezeAccount,
+                rental_account.key,
+                &[],
+            )?,
+            &[mint.clone(), rental_account.clone(), token_program.clone()],
+            &[rental_seeds],
+        )?;
+
+        Self::close_program_account(rental_account, owner_account)?;
+        MarketplaceEvent::RentalReclaimed {
+            owner: rental.owner,
+            mint: rental.mint,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// The token account holding the NFT: the vault, or once it has been
+    /// rented the last renter's account, which follows in `accounts` and is
+    /// thawed so the rental PDA can move the NFT out as its delegate.
+    fn rented_holder<'a, 'b>(
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        rental: &RentalListing,
+        mint: &AccountInfo<'a>,
+        vault_account: &'b AccountInfo<'a>,
+        rental_account: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+    ) -> Result<&'b AccountInfo<'a>, ProgramError> {
+        let Some(renter) = rental.renter else {
+            return Ok(vault_account);
+        };
+        let renter_token_account = next_account_info(account_info_iter)?;
+        if *renter_token_account.key != get_associated_token_address(&renter, mint.key) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        invoke_signed(
+            &spl_token::instruction::thaw_account(
+                token_program.key,
+                renter_token_account.key,
+                mint.key,
+                rental_account.key,
+                &[],
+            )?,
+            &[
+                renter_token_account.clone(),
+                mint.clone(),
+                rental_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[RentalListing::SEED, mint.key.as_ref(), &[rental.bump]]],
+        )?;
+        Ok(renter_token_account)
+    }
+
+    fn load_rental(
+        program_id: &Pubkey,
+        rental_account: &AccountInfo,
+    ) -> Result<RentalListing, ProgramError> {
+        let rental = RentalListing::load(rental_account, program_id)?;
+        let (rental_pda, _) = RentalListing::find_address(program_id, &rental.mint);
+        if rental_pda != *rental_account.key {
+            return Err(GachaError::InvalidRentalAccount.into());
+        }
+        Ok(rental)
+    }
+}
@@ -0,0 +1,547 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    program::invoke_signed,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    sysvar::Sysvar,
+};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token::state::Account as TokenAccount;
+
+use super::Processor;
+use crate::{
+    error::GachaError,
+    event::MarketplaceEvent,
+    state::{ProgramAccount, RarityTier, Role, StakeEntry, StakePool, State},
+};
+
+impl Processor {
+    pub(super) fn init_stake_pool(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        emission_rates: Vec<u64>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+        let reward_mint = next_account_info(account_info_iter)?;
+        let reward_vault = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::PoolCurator,
+        )?;
+
+        let (pool_pda, pool_bump) = StakePool::find_address(program_id);
+        if pool_pda != *pool_account.key || !pool_account.data_is_empty() {
+            return Err(GachaError::InvalidStakeAccount.into());
+        }
+
+        Self::create_program_account(
+            program_id,
+            authority_account,
+            pool_account,
+            system_program,
+            StakePool::LEN,
+            0,
+            &[StakePool::SEED, &[pool_bump]],
+        )?;
+        Self::create_token_account(
+            authority_account,
+            reward_vault,
+            pool_account,
+            reward_mint,
+            system_program,
+            token_program,
+            associated_token_program,
+        )?;
+
+        let pool = StakePool {
+            reward_mint: *reward_mint.key,
+            emission_rates: Self::tier_rates(&emission_rates)?,
+            reward_per_nft: [0; StakePool::MAX_TIERS],
+            last_update: Clock::get()?.unix_timestamp,
+            total_staked: 0,
+            bump: pool_bump,
+        };
+        pool.save(pool_account)?;
+        MarketplaceEvent::StakePoolInitialized {
+            reward_mint: pool.reward_mint,
+            emission_rates: pool.emission_rates.to_vec(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Tops up the reward vault. Anyone may fund it.
+    pub(super) fn fund_stake_pool(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let funder_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+        let reward_mint = next_account_info(account_info_iter)?;
+        let funder_token_account = next_account_info(account_info_iter)?;
+        let reward_vault = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !funder_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if amount < 1 {
+            return Err(GachaError::InvalidAmount.into());
+        }
+        let pool = Self::load_stake_pool(program_id, pool_account)?;
+        Self::check_reward_vault(
+            &pool,
+            pool_account,
+            reward_mint,
+            reward_vault,
+            token_program,
+        )?;
+
+        Self::transfer_tokens(
+            token_program,
+            funder_token_account,
+            reward_mint,
+            reward_vault,
+            funder_account,
+            amount,
+            &[],
+        )?;
+        MarketplaceEvent::StakePoolFunded {
+            funder: *funder_account.key,
+            amount,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn set_emission_rates(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        emission_rates: Vec<u64>,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::PoolCurator,
+        )?;
+        let mut pool = Self::load_stake_pool(program_id, pool_account)?;
+
+        // settle what was earned at the old rates first
+        pool.accrue(Clock::get()?.unix_timestamp);
+        pool.emission_rates = Self::tier_rates(&emission_rates)?;
+        pool.save(pool_account)?;
+        MarketplaceEvent::EmissionRatesSet {
+            emission_rates: pool.emission_rates.to_vec(),
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Only affects NFTs staked afterwards.
+    pub(super) fn set_rarity_tier(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        tier: u8,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let rarity_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_role(
+            program_id,
+            state_account,
+            authority_account,
+            Role::PoolCurator,
+        )?;
+        if tier as usize >= StakePool::MAX_TIERS {
+            return Err(GachaError::InvalidRarityTier.into());
+        }
+
+        let (rarity_pda, rarity_bump) = RarityTier::find_address(program_id, mint.key);
+        if rarity_pda != *rarity_account.key {
+            return Err(GachaError::InvalidStakeAccount.into());
+        }
+        if rarity_account.data_is_empty() {
+            Self::create_program_account(
+                program_id,
+                authority_account,
+                rarity_account,
+                system_program,
+                RarityTier::LEN,
+                0,
+                &[RarityTier::SEED, mint.key.as_ref(), &[rarity_bump]],
+            )?;
+        }
+
+        let rarity = RarityTier {
+            mint: *mint.key,
+            tier,
+        };
+        rarity.save(rarity_account)?;
+        MarketplaceEvent::RarityTierSet {
+            mint: *mint.key,
+            tier,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn stake_nft(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+        let entry_account = next_account_info(account_info_iter)?;
+        let rarity_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let token_account = next_account_info(account_info_iter)?;
+        let vault_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_not_paused(program_id, state_account, State::PAUSE_STAKING)?;
+        let mut pool = Self::load_stake_pool(program_id, pool_account)?;
+        // the reward vault is the pool's ATA for the reward mint
+        if pool.reward_mint == *mint.key {
+            return Err(GachaError::WrongMint.into());
+        }
+
+        let (entry_pda, entry_bump) = StakeEntry::find_address(program_id, mint.key);
+        if entry_pda != *entry_account.key || !entry_account.data_is_empty() {
+            return Err(GachaError::InvalidStakeAccount.into());
+        }
+        let (rarity_pda, _) = RarityTier::find_address(program_id, mint.key);
+        if rarity_pda != *rarity_account.key {
+            return Err(GachaError::InvalidStakeAccount.into());
+        }
+        // only curated NFTs earn rewards, any other mint would farm tier 0
+        if rarity_account.owner != program_id {
+            return Err(GachaError::UnregisteredMint.into());
+        }
+        let tier = RarityTier::load(rarity_account, program_id)?.tier;
+        Self::check_nft(mint)?;
+
+        Self::create_program_account(
+            program_id,
+            owner_account,
+            entry_account,
+            system_program,
+            StakeEntry::LEN,
+            0,
+            &[StakeEntry::SEED, mint.key.as_ref(), &[entry_bump]],
+        )?;
+        Self::create_token_account(
+            owner_account,
+            vault_account,
+            pool_account,
+            mint,
+            system_program,
+            token_program,
+            associated_token_program,
+        )?;
+        Self::transfer_tokens(
+            token_program,
+            token_account,
+            mint,
+            vault_account,
+            owner_account,
+            1,
+            &[],
+        )?;
+
+        let now = Clock::get()?.unix_timestamp;
+        pool.accrue(now);
+        pool.total_staked = pool
+            .total_staked
+            .checked_add(1)
+            .ok_or(GachaError::MathOverflow)?;
+        let entry = StakeEntry {
+            owner: *owner_account.key,
+            mint: *mint.key,
+            tier,
+            reward_index: pool.reward_per_nft[tier as usize],
+            owed: 0,
+            staked_at: now,
+            bump: entry_bump,
+        };
+        entry.save(entry_account)?;
+        pool.save(pool_account)?;
+        MarketplaceEvent::NftStaked {
+            owner: entry.owner,
+            mint: entry.mint,
+            tier,
+        }
+        .emit();
+        Ok(())
+    }
+
+    /// Pays out the pending rewards, returns the NFT and closes the entry.
+    /// Rewards the vault cannot cover are forfeited so exiting never fails.
+    pub(super) fn unstake_nft(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+        let entry_account = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let vault_account = next_account_info(account_info_iter)?;
+        let token_account = next_account_info(account_info_iter)?;
+        let reward_mint = next_account_info(account_info_iter)?;
+        let reward_vault = next_account_info(account_info_iter)?;
+        let reward_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        let mut pool = Self::load_stake_pool(program_id, pool_account)?;
+        let mut entry = Self::load_stake_entry(program_id, entry_account, owner_account)?;
+        if entry.mint != *mint.key {
+            return Err(GachaError::WrongMint.into());
+        }
+        if *vault_account.key
+            != get_associated_token_address_with_program_id(
+                pool_account.key,
+                mint.key,
+                token_program.key,
+            )
+        {
+            return Err(GachaError::InvalidEscrowAccount.into());
+        }
+
+        pool.accrue(Clock::get()?.unix_timestamp);
+        Self::pay_stake_rewards(
+            &pool,
+            &mut entry,
+            pool_account,
+            reward_mint,
+            reward_vault,
+            reward_account,
+            token_program,
+        )?;
+
+        let pool_seeds: &[&[u8]] = &[StakePool::SEED, &[pool.bump]];
+        Self::transfer_tokens(
+            token_program,
+            vault_account,
+            mint,
+            token_account,
+            pool_account,
+            1,
+            &[pool_seeds],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::close_account(
+                token_program.key,
+                vault_account.key,
+                owner_account.key,
+                pool_account.key,
+                &[],
+            )?,
+            &[
+                vault_account.clone(),
+                owner_account.clone(),
+                pool_account.clone(),
+                token_program.clone(),
+            ],
+            &[pool_seeds],
+        )?;
+
+        pool.total_staked = pool.total_staked.saturating_sub(1);
+        pool.save(pool_account)?;
+        Self::close_program_account(entry_account, owner_account)?;
+        MarketplaceEvent::NftUnstaked {
+            owner: entry.owner,
+            mint: entry.mint,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn claim_stake_rewards(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner_account = next_account_info(account_info_iter)?;
+        let pool_account = next_account_info(account_info_iter)?;
+        let entry_account = next_account_info(account_info_iter)?;
+        let reward_mint = next_account_info(account_info_iter)?;
+        let reward_vault = next_account_info(account_info_iter)?;
+        let reward_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        let mut pool = Self::load_stake_pool(program_id, pool_account)?;
+        let mut entry = Self::load_stake_entry(program_id, entry_account, owner_account)?;
+
+        pool.accrue(Clock::get()?.unix_timestamp);
+        Self::pay_stake_rewards(
+            &pool,
+            &mut entry,
+            pool_account,
+            reward_mint,
+            reward_vault,
+            reward_account,
+            token_program,
+        )?;
+
+        entry.save(entry_account)?;
+        pool.save(pool_account)?;
+        Ok(())
+    }
+
+    /// Moves the entry's pending rewards, capped at the vault balance, to
+    /// `reward_account`; whatever is left stays in `entry.owed`.
+    fn pay_stake_rewards<'a>(
+        pool: &StakePool,
+        entry: &mut StakeEntry,
+        pool_account: &AccountInfo<'a>,
+        reward_mint: &AccountInfo<'a>,
+        reward_vault: &AccountInfo<'a>,
+        reward_account: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+    ) -> ProgramResult {
+        Self::check_reward_vault(pool, pool_account, reward_mint, reward_vault, token_program)?;
+
+        let index = pool.reward_per_nft[entry.tier as usize];
+        let earned: u64 = index
+            .saturating_sub(entry.reward_index)
+            .try_into()
+            .map_err(|_| GachaError::MathOverflow)?;
+        let pending = entry
+            .owed
+            .checked_add(earned)
+            .ok_or(GachaError::MathOverflow)?;
+        let available = TokenAccount::unpack(&reward_vault.data.borrow())?.amount;
+        let payout = pending.min(available);
+
+        entry.reward_index = index;
+        entry.owed = pending - payout;
+        if payout == 0 {
+            return Ok(());
+        }
+        MarketplaceEvent::StakeRewardsPaid {
+            owner: entry.owner,
+            mint: entry.mint,
+            amount: payout,
+        }
+        .emit();
+
+        invoke_signed(
+            &spl_token::instruction::transfer_checked(
+                token_program.key,
+                reward_vault.key,
+                reward_mint.key,
+                reward_account.key,
+                pool_account.key,
+                &[],
+                payout,
+                spl_token::state::Mint::unpack(&reward_mint.data.borrow())?.decimals,
+            )?,
+            &[
+                reward_vault.clone(),
+                reward_mint.clone(),
+                reward_account.clone(),
+                pool_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[StakePool::SEED, &[pool.bump]]],
+        )
+    }
+
+    fn load_stake_pool(
+        program_id: &Pubkey,
+        pool_account: &AccountInfo,
+    ) -> Result<StakePool, ProgramError> {
+        let (pool_pda, _) = StakePool::find_address(program_id);
+        if pool_pda != *pool_account.key {
+            return Err(GachaError::InvalidStakeAccount.into());
+        }
+        StakePool::load(pool_account, program_id)
+    }
+
+    fn load_stake_entry(
+        program_id: &Pubkey,
+        entry_account: &AccountInfo,
+        owner_account: &AccountInfo,
+    ) -> Result<StakeEntry, ProgramError> {
+        if !owner_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let entry = StakeEntry::load(entry_account, program_id)?;
+        let (entry_pda, _) = StakeEntry::find_address(program_id, &entry.mint);
+        if entry_pda != *entry_account.key {
+            return Err(GachaError::InvalidStakeAccount.into());
+        }
+        if entry.owner != *owner_account.key {
+            return Err(GachaError::NotStakeOwner.into());
+        }
+        Ok(entry)
+    }
+
+    fn check_reward_vault(
+        pool: &StakePool,
+        pool_account: &AccountInfo,
+        reward_mint: &AccountInfo,
+        reward_vault: &AccountInfo,
+        token_program: &AccountInfo,
+    ) -> ProgramResult {
+        if pool.reward_mint != *reward_mint.key {
+            return Err(GachaError::WrongMint.into());
+        }
+        if *reward_vault.key
+            != get_associated_token_address_with_program_id(
+                pool_account.key,
+                reward_mint.key,
+                token_program.key,
+            )
+        {
+            return Err(GachaError::InvalidEscrowAccount.into());
+        }
+        Ok(())
+    }
+
+    fn tier_rates(emission_rates: &[u64]) -> Result<[u64; StakePool::MAX_TIERS], ProgramError> {
+        if emission_rates.len() > StakePool::MAX_TIERS {
+            return Err(GachaError::InvalidRarityTier.into());
+        }
+        let mut rates = [0; StakePool::MAX_TIERS];
+        rates[..emission_rates.len()].copy_from_slice(emission_rates);
+        Ok(rates)
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::BTreeSet;
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_instruction::transfer,
+};
+use spl_associated_token_account::{
+    get_associated_token_address, get_associated_token_address_with_program_id,
+};
+use spl_token::state::Account as TokenAccount;
+
+use super::Processor;
+use crate::{
+    error::GachaError,
+    event::MarketplaceEvent,
+    state::{ProgramAccount, State, SwapOffer},
+};
+
+impl Processor {
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn propose_swap(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        swap_id: u64,
+        counterparty: Pubkey,
+        offered_mints: Vec<Pubkey>,
+        offered_lamports: u64,
+        requested_mints: Vec<Pubkey>,
+        requested_lamports: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let proposer_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let swap_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !proposer_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_not_paused(program_id, state_account, State::PAUSE_SWAPS)?;
+        if offered_mints.is_empty() || (requested_mints.is_empty() && requested_lamports == 0) {
+            return Err(GachaError::InvalidAmount.into());
+        }
+        if offered_mints.len() > SwapOffer::MAX_ITEMS
+            || requested_mints.len() > SwapOffer::MAX_ITEMS
+        {
+            return Err(GachaError::TooManySwapItems.into());
+        }
+        if !Self::all_distinct(&offered_mints) || !Self::all_distinct(&requested_mints) {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        if counterparty == *proposer_account.key {
+            return Err(GachaError::NotSwapCounterparty.into());
+        }
+
+        let (swap_pda, swap_bump) =
+            SwapOffer::find_address(program_id, proposer_account.key, swap_id);
+        if swap_pda != *swap_account.key || !swap_account.data_is_empty() {
+            return Err(GachaError::InvalidSwapAccount.into());
+        }
+
+        // the offered lamports are held on the swap account on top of its rent
+        Self::create_program_account(
+            program_id,
+            proposer_account,
+            swap_account,
+            system_program,
+            SwapOffer::space(offered_mints.len(), requested_mints.len()),
+            offered_lamports,
+            &[
+                SwapOffer::SEED,
+                proposer_account.key.as_ref(),
+                &swap_id.to_le_bytes(),
+                &[swap_bump],
+            ],
+        )?;
+
+        for mint_address in offered_mints.iter() {
+            let mint = next_account_info(account_info_iter)?;
+            let token_account = next_account_info(account_info_iter)?;
+            let escrow_account = next_account_info(account_info_iter)?;
+            if mint.key != mint_address {
+                return Err(GachaError::WrongMint.into());
+            }
+
+            Self::create_token_account(
+                proposer_account,
+                escrow_account,
+                swap_account,
+                mint,
+                system_program,
+                token_program,
+                associated_token_program,
+            )?;
+            Self::transfer_tokens(
+                token_program,
+                token_account,
+                mint,
+                escrow_account,
+                proposer_account,
+                1,
+                &[],
+            )?;
+        }
+
+        let swap = SwapOffer {
+            proposer: *proposer_account.key,
+            counterparty,
+            swap_id,
+            offered_mints,
+            offered_lamports,
+            requested_mints,
+            requested_lamports,
+            bump: swap_bump,
+        };
+        swap.save(swap_account)?;
+        MarketplaceEvent::SwapProposed {
+            proposer: swap.proposer,
+            counterparty,
+            swap_id,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn accept_swap(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        proposer: Pubkey,
+        swap_id: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let counterparty_account = next_account_info(account_info_iter)?;
+        let state_account = next_account_info(account_info_iter)?;
+        let proposer_account = next_account_info(account_info_iter)?;
+        let swap_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !counterparty_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_not_paused(program_id, state_account, State::PAUSE_SWAPS)?;
+        let swap = Self::load_swap(
+            program_id,
+            swap_account,
+            proposer_account,
+            proposer,
+            swap_id,
+        )?;
+        if swap.counterparty != *counterparty_account.key {
+            return Err(GachaError::NotSwapCounterparty.into());
+        }
+        let swap_seeds: &[&[u8]] = &[
+            SwapOffer::SEED,
+            proposer.as_ref(),
+            &swap_id.to_le_bytes(),
+            &[swap.bump],
+        ];
+
+        // the proposer's escrowed NFTs go to the counterparty
+        for mint_address in swap.offered_mints.iter() {
+            let mint = next_account_info(account_info_iter)?;
+            let escrow_account = next_account_info(account_info_iter)?;
+            let token_account = next_account_info(account_info_iter)?;
+            if mint.key != mint_address {
+                return Err(GachaError::WrongMint.into());
+            }
+            Self::check_swap_escrow(swap_account, mint, escrow_account, token_program)?;
+
+            Self::create_token_account(
+                counterparty_account,
+                token_account,
+                counterparty_account,
+                mint,
+                system_program,
+                token_program,
+                associated_token_program,
+            )?;
+            Self::close_swap_escrow(
+                token_program,
+                escrow_account,
+                mint,
+                token_account,
+                proposer_account,
+                swap_account,
+                swap_seeds,
+            )?;
+        }
+
+        // the requested NFTs go straight from the counterparty to the proposer
+        for mint_address in swap.requested_mints.iter() {
+            let mint = next_account_info(account_info_iter)?;
+            let token_account = next_account_info(account_info_iter)?;
+            let proposer_token_account = next_account_info(account_info_iter)?;
+            if mint.key != mint_address {
+                return Err(GachaError::WrongMint.into());
+            }
+
+            Self::create_token_account(
+                counterparty_account,
+                proposer_token_account,
+                proposer_account,
+                mint,
+                system_program,
+                token_program,
+                associated_token_program,
+            )?;
+            Self::transfer_tokens(
+                token_program,
+                token_account,
+                mint,
+                proposer_token_account,
+                counterparty_account,
+                1,
+                &[],
+            )?;
+        }
+
+        if swap.requested_lamports > 0 {
+            invoke(
+                &transfer(
+                    counterparty_account.key,
+                    proposer_account.key,
+                    swap.requested_lamports,
+                ),
+                &[
+                    counterparty_account.clone(),
+                    proposer_account.clone(),
+                    system_program.clone(),
+                ],
+            )?;
+        }
+
+        // release the offered lamports, the rent goes back to the proposer
+        Self::move_lamports(swap_account, counterparty_account, swap.offered_lamports)?;
+        Self::close_program_account(swap_account, proposer_account)?;
+        MarketplaceEvent::SwapAccepted {
+            proposer,
+            counterparty: swap.counterparty,
+            swap_id,
+        }
+        .emit();
+        Ok(())
+    }
+
+    pub(super) fn cancel_swap(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+        proposer: Pubkey,
+        swap_id: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let authority_account = next_account_info(account_info_iter)?;
+        let proposer_account = next_account_info(account_info_iter)?;
+        let swap_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let associated_token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        Self::check_token_program(token_program)?;
+        if !authority_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let swap = Self::load_swap(
+            program_id,
+            swap_account,
+            proposer_account,
+            proposer,
+            swap_id,
+        )?;
+        // either side can call it off, everything is refunded to the proposer
+        if *authority_account.key != swap.proposer && *authority_account.key != swap.counterparty {
+            return Err(GachaError::NotSwapParty.into());
+        }
+        let swap_seeds: &[&[u8]] = &[
+            SwapOffer::SEED,
+            proposer.as_ref(),
+            &swap_id.to_le_bytes(),
+            &[swap.bump],
+        ];
+
+        for mint_address in swap.offered_mints.iter() {
+            let mint = next_account_info(account_info_iter)?;
+            let escrow_account = next_account_info(account_info_iter)?;
+            let token_account = next_account_info(account_info_iter)?;
+            if mint.key != mint_address {
+                return Err(GachaError::WrongMint.into());
+            }
+            Self::check_swap_escrow(swap_account, mint, escrow_account, token_program)?;
+            if *token_account.key != get_associated_token_address(&proposer, mint.key) {
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            // the proposer may have closed the account since proposing
+            Self::create_token_account(
+                authority_account,
+                token_account,
+                proposer_account,
+                mint,
+                system_program,
+                token_program,
+                associated_token_program,
+            )?;
+            Self::close_swap_escrow(
+                token_program,
+                escrow_account,
+                mint,
+                token_account,
+                proposer_account,
+                swap_account,
+                swap_seeds,
+            )?;
+        }
+
+        // offered lamports and rent both return to the proposer
+        Self::close_program_account(swap_account, proposer_account)?;
+        MarketplaceEvent::SwapCancelled {
+            proposer,
+            swap_id,
+            cancelled_by: *authority_account.key,
+        }
+        .emit();
+        Ok(())
+    }
+
+    fn load_swap(
+        program_id: &Pubkey,
+        swap_account: &AccountInfo,
+        proposer_account: &AccountInfo,
+        proposer: Pubkey,
+        swap_id: u64,
+    ) -> Result<SwapOffer, ProgramError> {
+        let (swap_pda, _) = SwapOffer::find_address(program_id, &proposer, swap_id);
+        if swap_pda != *swap_account.key || *proposer_account.key != proposer {
+            return Err(GachaError::InvalidSwapAccount.into());
+        }
+        SwapOffer::load(swap_account, program_id)
+    }
+
+    fn check_swap_escrow(
+        swap_account: &AccountInfo,
+        mint: &AccountInfo,
+        escrow_account: &AccountInfo,
+        token_program: &AccountInfo,
+    ) -> ProgramResult {
+        if *escrow_account.key
+            != get_associated_token_address_with_program_id(
+                swap_account.key,
+                mint.key,
+                token_program.key,
+            )
+        {
+            return Err(GachaError::InvalidEscrowAccount.into());
+        }
+        Ok(())
+    }
+
+    /// Empties the escrow into `token_account` and closes it, the rent going
+    /// to `destination`. Units sent to the escrow on top of the offered one
+    /// go along, a token account only closes once it is empty.
+    fn close_swap_escrow<'a>(
+        token_program: &AccountInfo<'a>,
+        escrow_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        token_account: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        swap_account: &AccountInfo<'a>,
+        swap_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let amount = TokenAccount::unpack(&escrow_account.data.borrow())?.amount;
+        Self::transfer_tokens(
+            token_program,
+            escrow_account,
+            mint,
+            token_account,
+            swap_account,
+            amount,
+            &[swap_seeds],
+        )?;
+        invoke_signed(
+            &spl_token::instruction::close_account(
+                token_program.key,
+                escrow_account.key,
+                destination.key,
+                swap_account.key,
+                &[],
+            )?,
+            &[
+                escrow_account.clone(),
+                destination.clone(),
+                swap_account.clone(),
+                token_program.clone(),
+            ],
+            &[swap_seeds],
+        )
+    }
+
+    fn all_distinct(mints: &[Pubkey]) -> bool {
+        mints.iter().collect::<BTreeSet<_>>().len() == mints.len()
+    }
+}
@@ -0,0 +1,90 @@
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use super::Processor;
+use crate::{
+    error::GachaError,
+    state::{IndexKind, ProgramAccount, WalletIndex},
+};
+
+impl Processor {
+    /// Reads `wallet`'s index of `kind`, or an empty one if it does not exist yet.
+    fn load_wallet_index(
+        program_id: &Pubkey,
+        wallet: &Pubkey,
+        kind: IndexKind,
+        index_account: &AccountInfo,
+    ) -> Result<WalletIndex, ProgramError> {
+        let (index_pda, index_bump) = WalletIndex::find_address(program_id, wallet, kind);
+        if index_pda != *index_account.key {
+            return Err(GachaError::InvalidWalletIndexAccount.into());
+        }
+        if index_account.data_is_empty() {
+            return Ok(WalletIndex {
+                wallet: *wallet,
+                kind,
+                item_ids: vec![],
+                bump: index_bump,
+            });
+        }
+        WalletIndex::load(index_account, program_id)
+    }
+
+    /// Records `item_id` in `wallet`'s index, creating or growing the account
+    /// as needed. `payer` covers the rent.
+    pub(super) fn add_to_wallet_index<'a>(
+        program_id: &Pubkey,
+        wallet: &Pubkey,
+        kind: IndexKind,
+        index_account: &AccountInfo<'a>,
+        payer: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        item_id: u128,
+    ) -> ProgramResult {
+        let mut index = Self::load_wallet_index(program_id, wallet, kind, index_account)?;
+        // repeat partial fills of one listing are recorded once
+        if index.item_ids.contains(&item_id) {
+            return Ok(());
+        }
+        index.item_ids.push(item_id);
+
+        let required = WalletIndex::space(index.item_ids.len());
+        if index_account.data_is_empty() {
+            Self::create_program_account(
+                program_id,
+                payer,
+                index_account,
+                system_program,
+                required,
+                0,
+                &[
+                    WalletIndex::SEED,
+                    wallet.as_ref(),
+                    &[kind as u8],
+                    &[index.bump],
+                ],
+            )?;
+        } else if required > index_account.data_len() {
+            Self::grow_account(index_account, payer, system_program, required)?;
+        }
+        index.save(index_account)
+    }
+
+    /// Drops `item_id` from `wallet`'s index. The account keeps its size.
+    pub(super) fn remove_from_wallet_index(
+        program_id: &Pubkey,
+        wallet: &Pubkey,
+        kind: IndexKind,
+        index_account: &AccountInfo,
+        item_id: u128,
+    ) -> ProgramResult {
+        let mut index = Self::load_wallet_index(program_id, wallet, kind, index_account)?;
+        if index_account.data_is_empty() {
+            return Ok(());
+        }
+        index.item_ids.retain(|id| *id != item_id);
+        index.save(index_account)
+    }
+}
@@ -0,0 +1,133 @@
+//! Read-only queries over the marketplace state, shared by clients and the
+//! simulation-only `Fetch*` instructions.
+
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+use solana_program::{
+    borsh0_10::try_from_slice_unchecked, program::MAX_RETURN_DATA, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::state::{MarketItem, State};
+
+/// Conditions an item must meet to be returned. Unset fields match anything.
+#[derive(BorshDeserialize, BorshSerialize, BorshSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ItemFilter {
+    pub unsold: bool, // only items with units left
+    pub seller: Option<Pubkey>,
+    pub owner: Option<Pubkey>, // the buyer of a single-unit item
+    pub gacha_eligible: bool,  // only items that can still be drawn
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+}
+
+impl ItemFilter {
+    pub fn matches(&self, item: &MarketItem) -> bool {
+        let available = !item.sold && item.remaining > 0;
+        (!self.unsold || available)
+            && self.seller.is_none_or(|seller| item.seller == seller)
+            && self.owner.is_none_or(|owner| item.owner == Some(owner))
+            && (!self.gacha_eligible || (item.gacha && available))
+            && self.min_price.is_none_or(|min| item.price >= min)
+            && self.max_price.is_none_or(|max| item.price <= max)
+    }
+}
+
+/// Which slice of the matching items to return, in item id order.
+#[derive(BorshDeserialize, BorshSerialize, BorshSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Page {
+    pub after: Option<u128>, // `ItemPage::next` of the previous page
+    pub limit: u16,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self {
+            after: None,
+            limit: Self::DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl Page {
+    pub const DEFAULT_LIMIT: u16 = 20;
+}
+
+#[derive(BorshDeserialize, BorshSerialize, BorshSchema, Clone, Debug, Default)]
+pub struct ItemPage {
+    pub items: Vec<MarketItem>,
+    pub next: Option<u128>, // pass as `Page::after` to continue, None at the end
+}
+
+impl ItemPage {
+    /// Decodes a page from `Fetch*` return data, which the runtime hands back
+    /// with its trailing zero bytes stripped.
+    pub fn from_return_data(data: &[u8]) -> Result<Self, ProgramError> {
+        let mut padded = data.to_vec();
+        padded.resize(MAX_RETURN_DATA, 0);
+        Ok(try_from_slice_unchecked(&padded)?)
+    }
+
+    /// Drops items from the end until the encoding fits in `max_len` bytes,
+    /// moving `next` back so the dropped items start the following page. An
+    /// item too large for any page is skipped: the page comes back empty with
+    /// `next` pointing past it.
+    pub fn truncate_to(&mut self, max_len: usize) -> Result<(), ProgramError> {
+        if self.try_to_vec()?.len() <= max_len {
+            return Ok(());
+        }
+        // vec length prefix and a `Some` cursor
+        let mut len = 4 + 1 + 16;
+        let mut fits: usize = 0;
+        for item in &self.items {
+            len += item.try_to_vec()?.len();
+            if len > max_len {
+                break;
+            }
+            fits += 1;
+        }
+        let last = self
+            .items
+            .get(fits.saturating_sub(1))
+            .ok_or(ProgramError::InvalidArgument)?
+            .item_id;
+        self.items.truncate(fits);
+        self.next = Some(last);
+        Ok(())
+    }
+}
+
+/// FetchMarketItems: every item matching `filter`.
+pub fn market_items(state: &State, filter: &ItemFilter, page: Page) -> ItemPage {
+    let start = page.after.map_or(0, |after| after.saturating_add(1));
+    let mut matching = state
+        .map
+        .range(start..)
+        .map(|(_, item)| item)
+        .filter(|item| filter.matches(item));
+
+    let items: Vec<MarketItem> = matching
+        .by_ref()
+        .take(page.limit as usize)
+        .cloned()
+        .collect();
+    let next = matching.next().and(items.last().map(|item| item.item_id));
+    ItemPage { items, next }
+}
+
+/// FetchMyNFTs: single-unit items bought by `wallet`.
+pub fn my_nfts(state: &State, wallet: &Pubkey, page: Page) -> ItemPage {
+    let filter = ItemFilter {
+        owner: Some(*wallet),
+        ..ItemFilter::default()
+    };
+    market_items(state, &filter, page)
+}
+
+/// FetchItemsCreated: items listed by `seller`.
+pub fn items_created(state: &State, seller: &Pubkey, page: Page) -> ItemPage {
+    let filter = ItemFilter {
+        seller: Some(*seller),
+        ..ItemFilter::default()
+    };
+    market_items(state, &filter, page)
+}
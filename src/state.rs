@@ -1,14 +1,456 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    mem::size_of,
+};
 
-use borsh::{BorshSerialize, BorshDeserialize};
-use solana_program::{pubkey::Pubkey, account_info::AccountInfo};
+use borsh::{
+    schema::{Declaration, Definition, Fields},
+    BorshDeserialize, BorshSchema, BorshSerialize,
+};
+use bytemuck::{Pod, Zeroable};
+use solana_program::{
+    account_info::AccountInfo, borsh0_10::try_from_slice_unchecked, entrypoint::ProgramResult,
+    program_error::ProgramError, pubkey::Pubkey, system_instruction::MAX_PERMITTED_DATA_LENGTH,
+};
 
-#[derive(BorshSerialize, BorshDeserialize, Clone, Debug)]
+use crate::error::GachaError;
+
+pub const DISCRIMINATOR_LEN: usize = 8;
+/// Discriminator followed by the layout version byte.
+pub const HEADER_LEN: usize = DISCRIMINATOR_LEN + 1;
+
+/// An account type stored by this program. Its data starts with
+/// `DISCRIMINATOR`, the first 8 bytes of `sha256("account:<TypeName>")`, so
+/// one account type can never be read as another, then the layout version.
+pub trait ProgramAccount: BorshSerialize + BorshDeserialize {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN];
+    /// Layout written by `save`. Bump it when the fields change and teach
+    /// `unpack_legacy` the old layout.
+    const VERSION: u8 = 1;
+
+    /// Decodes raw account data written at an older `version`. It gets the
+    /// whole account, older layouts may not share the current header.
+    fn unpack_legacy(_version: u8, _data: &[u8]) -> Result<Self, ProgramError> {
+        Err(GachaError::UnsupportedVersion.into())
+    }
+
+    /// The layout version of raw account data, checking the discriminator.
+    fn account_version(data: &[u8]) -> Result<u8, ProgramError> {
+        header_version(data, &Self::DISCRIMINATOR)
+    }
+
+    /// Decodes a body written at `VERSION`.
+    fn unpack_body(body: &[u8]) -> Result<Self, ProgramError> {
+        Ok(try_from_slice_unchecked(body)?)
+    }
+
+    /// Encodes `self` as a body at `VERSION` into the front of `body`.
+    fn pack_body(&self, body: &mut [u8]) -> ProgramResult {
+        self.serialize(&mut &mut body[..])
+            .map_err(|_| ProgramError::AccountDataTooSmall)
+    }
+
+    /// Bytes needed to store `self`, header included.
+    fn packed_len(&self) -> Result<usize, ProgramError> {
+        let mut counter = ByteCounter(0);
+        self.serialize(&mut counter)?;
+        Ok(HEADER_LEN + counter.0)
+    }
+
+    /// Decodes raw account data of the current or any older layout.
+    fn unpack_account(data: &[u8]) -> Result<Self, ProgramError> {
+        match Self::account_version(data)? {
+            version if version == Self::VERSION => Self::unpack_body(&data[HEADER_LEN..]),
+            version if version < Self::VERSION => Self::unpack_legacy(version, data),
+            _ => Err(GachaError::UnsupportedVersion.into()),
+        }
+    }
+
+    /// Reads `account`, which must be owned by `program_id`.
+    fn load(account: &AccountInfo, program_id: &Pubkey) -> Result<Self, ProgramError> {
+        if account.owner != program_id {
+            return Err(GachaError::InvalidAccountOwner.into());
+        }
+        Self::unpack_account(&account.try_borrow_data()?)
+    }
+
+    /// Encodes `self` as complete account data, for clients and tests.
+    fn pack_account(&self) -> Result<Vec<u8>, ProgramError> {
+        let mut data = vec![0; self.packed_len()?];
+        data[..DISCRIMINATOR_LEN].copy_from_slice(&Self::DISCRIMINATOR);
+        data[DISCRIMINATOR_LEN] = Self::VERSION;
+        self.pack_body(&mut data[HEADER_LEN..])?;
+        Ok(data)
+    }
+
+    /// Writes the header and `self` into `account` at the current version.
+    fn save(&self, account: &AccountInfo) -> ProgramResult {
+        if !account.is_writable {
+            return Err(GachaError::AccountNotWritable.into());
+        }
+        let mut data = account.try_borrow_mut_data()?;
+        if data.len() < HEADER_LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        data[..DISCRIMINATOR_LEN].copy_from_slice(&Self::DISCRIMINATOR);
+        data[DISCRIMINATOR_LEN] = Self::VERSION;
+        self.pack_body(&mut data[HEADER_LEN..])
+    }
+}
+
+/// The version byte after `discriminator` in versioned account data.
+fn header_version(
+    data: &[u8],
+    discriminator: &[u8; DISCRIMINATOR_LEN],
+) -> Result<u8, ProgramError> {
+    if data.len() < HEADER_LEN || data[..DISCRIMINATOR_LEN] != *discriminator {
+        return Err(GachaError::InvalidDiscriminator.into());
+    }
+    Ok(data[DISCRIMINATOR_LEN])
+}
+
+/// Measures a borsh encoding without allocating it.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Little endian integers with alignment 1, so the zero-copy state header
+/// can sit right after the 9 byte account header.
+macro_rules! pod_int {
+    ($name:ident, $int:ty) => {
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Pod, Zeroable)]
+        pub struct $name([u8; size_of::<$int>()]);
+
+        impl From<$int> for $name {
+            fn from(value: $int) -> Self {
+                Self(value.to_le_bytes())
+            }
+        }
+
+        impl From<$name> for $int {
+            fn from(value: $name) -> Self {
+                <$int>::from_le_bytes(value.0)
+            }
+        }
+
+        // the bytes are the borsh encoding of the integer
+        impl BorshSchema for $name {
+            fn add_definitions_recursively(_definitions: &mut HashMap<Declaration, Definition>) {}
+
+            fn declaration() -> Declaration {
+                <$int>::declaration()
+            }
+        }
+    };
+}
+
+/// borsh 0.10 has no schema for `BTreeMap`, which is encoded like this.
+type MapSchema<K, V> = HashMap<K, V>;
+
+pod_int!(PodU16, u16);
+pod_int!(PodU32, u32);
+pod_int!(PodU64, u64);
+pod_int!(PodU128, u128);
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Debug)]
 pub struct MarketItem {
     pub item_id: u128,
     pub token_program_id: Pubkey, // program id,
     pub mint_address: Pubkey,
     pub seller: Pubkey,
+    pub owner: Option<Pubkey>, // buyer of a single-unit listing
+    pub price: u64, // lamports per unit
+    pub file_name: String,
+    pub description: String,
+    pub collection: Option<Pubkey>, // the mint's MintCollection when listed, drives loyalty multipliers
+    pub sold: bool,
+    pub gacha: bool,
+    pub amount: u64, // units escrowed when listed
+    pub remaining: u64, // units still held in escrow
+    pub decimals: u8,
+}
+
+impl MarketItem {
+    // keeps any single listing well inside one `Fetch*` page of return data
+    pub const MAX_FILE_NAME_LEN: usize = 64;
+    pub const MAX_DESCRIPTION_LEN: usize = 512;
+}
+
+/// The marketplace state. On chain it is stored as a zero-copy
+/// `StateHeader` followed by the borsh encoded listings and loyalty
+/// multipliers. The derived borsh encoding is not stored anywhere, the
+/// unversioned layout is read through its own frozen struct.
+///
+/// Only the header is zero-copy: listing, buying, cancelling and the gacha
+/// still decode and re-encode every listing, so their compute cost keeps
+/// growing with the marketplace.
+#[derive(Clone, Debug, Default, BorshDeserialize, BorshSerialize)]
+pub struct State {
+    pub map: BTreeMap<u128, MarketItem>, // 100
+    pub item_ids: u128,
+    pub item_sold: u128,
+    pub owner: Pubkey,
+    pub listing_price: u64,
+    pub initialized: bool,
+    pub loyalty: LoyaltyConfig,
+    pub referral_bps: u16, // slice of the marketplace fee paid to referrers
+    pub roles: Roles,
+    pub pending_owner: Option<Pubkey>, // set by the owner, becomes owner on acceptance
+    pub paused: u32, // PAUSE_* feature flags
+    pub treasury: Pubkey, // receives every marketplace fee
+    pub fee_bps: u16, // cut of each sale taken for the treasury
+    pub max_gacha_qty: u8,
+    pub gacha_fee: u64, // lamports per draw, on top of the item price
+}
+
+impl ProgramAccount for State {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [216, 146, 107, 94, 104, 75, 182, 177];
+
+    fn unpack_legacy(version: u8, data: &[u8]) -> Result<Self, ProgramError> {
+        match version {
+            0 => try_from_slice_unchecked::<StateV0>(data)?.try_into(),
+            _ => Err(GachaError::UnsupportedVersion.into()),
+        }
+    }
+
+    // The state written before layouts were versioned is told apart by the
+    // fixed size of its account. A versioned state is never that small.
+    fn account_version(data: &[u8]) -> Result<u8, ProgramError> {
+        match data.len() {
+            Self::LEN_V0 => Ok(0),
+            _ => header_version(data, &Self::DISCRIMINATOR),
+        }
+    }
+
+    fn unpack_body(body: &[u8]) -> Result<Self, ProgramError> {
+        let header = StateHeader::from_bytes(body)?;
+        let mut rest = &body[StateHeader::LEN..];
+        let map = BTreeMap::deserialize(&mut rest)?;
+        let collection_multipliers = BTreeMap::deserialize(&mut rest)?;
+        Ok(Self {
+            map,
+            item_ids: header.item_ids.into(),
+            item_sold: header.item_sold.into(),
+            owner: header.owner,
+            listing_price: header.listing_price.into(),
+            initialized: header.initialized != 0,
+            loyalty: LoyaltyConfig {
+                lamports_per_point: header.lamports_per_point.into(),
+                point_value: header.point_value.into(),
+                points_per_free_draw: header.points_per_free_draw.into(),
+                collection_multipliers,
+            },
+            referral_bps: header.referral_bps.into(),
+            roles: header.roles,
+            pending_owner: header.pending_owner(),
+            paused: header.paused.into(),
+            treasury: header.treasury,
+            fee_bps: header.fee_bps.into(),
+            max_gacha_qty: header.max_gacha_qty,
+            gacha_fee: header.gacha_fee.into(),
+        })
+    }
+
+    fn pack_body(&self, body: &mut [u8]) -> ProgramResult {
+        if body.len() < StateHeader::LEN {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let (header, mut rest) = body.split_at_mut(StateHeader::LEN);
+        header.copy_from_slice(bytemuck::bytes_of(&StateHeader::from(self)));
+        self.serialize_tail(&mut rest)
+            .map_err(|_| ProgramError::AccountDataTooSmall)
+    }
+
+    // never below `LEN`, the unversioned state is told apart by its smaller
+    // account size
+    fn packed_len(&self) -> Result<usize, ProgramError> {
+        let mut counter = ByteCounter(0);
+        self.serialize_tail(&mut counter)?;
+        Ok((HEADER_LEN + StateHeader::LEN + counter.0).max(Self::LEN))
+    }
+}
+
+// Describes the stored layout, the `StateHeader` fields followed by the
+// tail from `serialize_tail`, rather than the derived borsh encoding.
+impl BorshSchema for State {
+    fn add_definitions_recursively(definitions: &mut HashMap<Declaration, Definition>) {
+        let header = StateHeader::schema_container();
+        let mut fields = match header.definitions.get(&header.declaration) {
+            Some(Definition::Struct { fields: Fields::NamedFields(fields) }) => fields.clone(),
+            _ => unreachable!("StateHeader has named fields"),
+        };
+        fields.extend([
+            ("map".to_string(), <MapSchema<u128, MarketItem>>::declaration()),
+            ("collection_multipliers".to_string(), <MapSchema<Pubkey, u16>>::declaration()),
+        ]);
+        let definition = Definition::Struct { fields: Fields::NamedFields(fields) };
+        Self::add_definition(Self::declaration(), definition, definitions);
+        definitions.extend(
+            header.definitions.into_iter().filter(|(declaration, _)| *declaration != header.declaration),
+        );
+        <MapSchema<u128, MarketItem>>::add_definitions_recursively(definitions);
+        <MapSchema<Pubkey, u16>>::add_definitions_recursively(definitions);
+    }
+
+    fn declaration() -> Declaration {
+        "State".to_string()
+    }
+}
+
+impl State {
+    // fixed size of the unversioned layout, which has no discriminator
+    pub const LEN_V0: usize = 1 + (4 + (10 * 64)); // 10 user -> blog
+    // initial allocation, the account is grown with realloc as listings are added
+    pub const LEN: usize = HEADER_LEN + Self::LEN_V0 + 8;
+    pub const MAX_LEN: usize = MAX_PERMITTED_DATA_LENGTH as usize;
+    pub const SEED: &'static [u8] = b"state";
+
+    // Pausable features. Delisting, cancelling swaps, reclaiming rentals,
+    // unstaking and claiming earnings always stay open so users can exit.
+    pub const PAUSE_LISTINGS: u32 = 1 << 0;
+    pub const PAUSE_PURCHASES: u32 = 1 << 1;
+    pub const PAUSE_GACHA: u32 = 1 << 2;
+    pub const PAUSE_SWAPS: u32 = 1 << 3;
+    pub const PAUSE_RENTALS: u32 = 1 << 4;
+    pub const PAUSE_STAKING: u32 = 1 << 5;
+    pub const PAUSE_LOYALTY: u32 = 1 << 6;
+    pub const PAUSE_REFERRALS: u32 = 1 << 7;
+    pub const PAUSE_ALL: u32 = u32::MAX;
+
+    pub const MAX_FEE_BPS: u16 = 10_000;
+    pub const DEFAULT_MAX_GACHA_QTY: u8 = 10;
+
+    /// The state PDA, which also owns the escrow token accounts.
+    pub fn find_address(program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED], program_id)
+    }
+
+    /// The treasury's cut of a sale of `amount` lamports.
+    pub fn sale_fee(&self, amount: u64) -> u64 {
+        (amount as u128 * self.fee_bps as u128 / Self::MAX_FEE_BPS as u128) as u64
+    }
+
+    pub fn is_paused(&self, feature: u32) -> bool {
+        self.paused & feature != 0
+    }
+
+    pub fn role_holder(&self, role: Role) -> Pubkey {
+        self.roles.holder(role, self.owner)
+    }
+
+    /// The variable length part stored after the `StateHeader`.
+    fn serialize_tail<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.map.serialize(writer)?;
+        self.loyalty.collection_multipliers.serialize(writer)
+    }
+}
+
+/// Counters and configuration at the front of the state account. Handlers
+/// that only need these, such as role and pause checks and the admin
+/// settings, read or update them in place without decoding the listings.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable, BorshSchema)]
+pub struct StateHeader {
+    pub item_ids: PodU128,
+    pub item_sold: PodU128,
+    pub owner: Pubkey,
+    pub listing_price: PodU64,
+    pub initialized: u8,
+    pub referral_bps: PodU16,
+    pub roles: Roles,
+    pub pending_owner: Pubkey, // Pubkey::default() while no transfer is pending
+    pub paused: PodU32,
+    pub treasury: Pubkey,
+    pub fee_bps: PodU16,
+    pub max_gacha_qty: u8,
+    pub gacha_fee: PodU64,
+    pub lamports_per_point: PodU64,
+    pub point_value: PodU64,
+    pub points_per_free_draw: PodU64,
+}
+
+impl StateHeader {
+    pub const LEN: usize = size_of::<Self>();
+
+    fn from_bytes(body: &[u8]) -> Result<&Self, ProgramError> {
+        let bytes = body.get(..Self::LEN).ok_or(ProgramError::AccountDataTooSmall)?;
+        bytemuck::try_from_bytes(bytes).map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    /// Views the header inside state account data of the current version.
+    pub fn from_account_data(data: &[u8]) -> Result<&Self, ProgramError> {
+        if State::account_version(data)? != State::VERSION {
+            return Err(GachaError::UnsupportedVersion.into());
+        }
+        Self::from_bytes(&data[HEADER_LEN..])
+    }
+
+    pub fn from_account_data_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        Self::from_account_data(data)?;
+        Ok(bytemuck::from_bytes_mut(
+            &mut data[HEADER_LEN..HEADER_LEN + Self::LEN],
+        ))
+    }
+
+    pub fn is_paused(&self, feature: u32) -> bool {
+        u32::from(self.paused) & feature != 0
+    }
+
+    pub fn role_holder(&self, role: Role) -> Pubkey {
+        self.roles.holder(role, self.owner)
+    }
+
+    pub fn pending_owner(&self) -> Option<Pubkey> {
+        Some(self.pending_owner).filter(|owner| *owner != Pubkey::default())
+    }
+}
+
+impl From<&State> for StateHeader {
+    fn from(state: &State) -> Self {
+        Self {
+            item_ids: state.item_ids.into(),
+            item_sold: state.item_sold.into(),
+            owner: state.owner,
+            listing_price: state.listing_price.into(),
+            initialized: state.initialized as u8,
+            referral_bps: state.referral_bps.into(),
+            roles: state.roles,
+            pending_owner: state.pending_owner.unwrap_or_default(),
+            paused: state.paused.into(),
+            treasury: state.treasury,
+            fee_bps: state.fee_bps.into(),
+            max_gacha_qty: state.max_gacha_qty,
+            gacha_fee: state.gacha_fee.into(),
+            lamports_per_point: state.loyalty.lamports_per_point.into(),
+            point_value: state.loyalty.point_value.into(),
+            points_per_free_draw: state.loyalty.points_per_free_draw.into(),
+        }
+    }
+}
+
+/// Lamport amounts of the unversioned layout are `u128`.
+fn legacy_lamports(amount: u128) -> Result<u64, ProgramError> {
+    u64::try_from(amount).map_err(|_| GachaError::MathOverflow.into())
+}
+
+/// A listing of the v0 state, one unit of an NFT.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize)]
+pub struct MarketItemV0 {
+    pub item_id: u128,
+    pub token_program_id: Pubkey,
+    pub mint_address: Pubkey,
+    pub seller: Pubkey,
     pub owner: Option<Pubkey>,
     pub price: u128,
     pub file_name: String,
@@ -18,16 +460,478 @@ pub struct MarketItem {
     pub gacha: bool,
 }
 
+impl TryFrom<MarketItemV0> for MarketItem {
+    type Error = ProgramError;
+
+    fn try_from(item: MarketItemV0) -> Result<Self, Self::Error> {
+        Ok(Self {
+            item_id: item.item_id,
+            token_program_id: item.token_program_id,
+            mint_address: item.mint_address,
+            seller: item.seller,
+            owner: item.owner,
+            price: legacy_lamports(item.price)?,
+            file_name: item.file_name,
+            description: item.description,
+            collection: None,
+            sold: item.sold,
+            gacha: item.gacha,
+            amount: 1,
+            remaining: !item.sold as u64,
+            decimals: 0,
+        })
+    }
+}
+
+/// The first state layout, a bare borsh body with no discriminator in an
+/// account of `State::LEN_V0` bytes.
 #[derive(Clone, Debug, BorshDeserialize, BorshSerialize)]
-pub struct State {
-    pub map: BTreeMap<u128, MarketItem>, // 100
+pub struct StateV0 {
+    pub map: BTreeMap<u128, MarketItemV0>,
     pub item_ids: u128,
     pub item_sold: u128,
     pub owner: Pubkey,
     pub listing_price: u128,
-    pub initialized: bool
+    pub initialized: bool,
 }
 
-impl State {
-    pub const LEN: usize = 1 + (4 + (10 * 64)); // 10 user -> blog
-}
\ No newline at end of file
+impl TryFrom<StateV0> for State {
+    type Error = ProgramError;
+
+    fn try_from(v0: StateV0) -> Result<Self, Self::Error> {
+        Ok(Self {
+            map: v0
+                .map
+                .into_iter()
+                .map(|(id, item)| Ok((id, item.try_into()?)))
+                .collect::<Result<_, ProgramError>>()?,
+            item_ids: v0.item_ids,
+            item_sold: v0.item_sold,
+            owner: v0.owner,
+            listing_price: legacy_lamports(v0.listing_price)?,
+            initialized: v0.initialized,
+            roles: Roles::all(v0.owner),
+            treasury: v0.owner,
+            max_gacha_qty: State::DEFAULT_MAX_GACHA_QTY,
+            ..State::default()
+        })
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Owner,
+    FeeManager,
+    Pauser,
+    PoolCurator,
+    UpgradeAuthority,
+}
+
+/// Keys allowed to act for each role. Any of them may be a multisig PDA.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, BorshDeserialize, BorshSerialize, BorshSchema, Pod, Zeroable)]
+pub struct Roles {
+    pub fee_manager: Pubkey, // fees, loyalty and referral settings
+    pub pauser: Pubkey,
+    pub pool_curator: Pubkey, // staking pool and rarity tiers
+    pub upgrade_authority: Pubkey,
+}
+
+impl Roles {
+    pub fn all(holder: Pubkey) -> Self {
+        Self {
+            fee_manager: holder,
+            pauser: holder,
+            pool_curator: holder,
+            upgrade_authority: holder,
+        }
+    }
+
+    pub fn holder(&self, role: Role, owner: Pubkey) -> Pubkey {
+        match role {
+            Role::Owner => owner,
+            Role::FeeManager => self.fee_manager,
+            Role::Pauser => self.pauser,
+            Role::PoolCurator => self.pool_curator,
+            Role::UpgradeAuthority => self.upgrade_authority,
+        }
+    }
+}
+
+/// An M of N signer set. Its PDA can hold roles and acts through proposals.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct Multisig {
+    pub seed: Pubkey,
+    pub signers: Vec<Pubkey>,
+    pub threshold: u8,
+    pub proposal_count: u64,
+    pub bump: u8,
+}
+
+impl ProgramAccount for Multisig {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [224, 116, 121, 186, 68, 161, 79, 236];
+}
+
+impl Multisig {
+    pub const SEED: &'static [u8] = b"multisig";
+    pub const MAX_SIGNERS: usize = 10;
+
+    pub fn find_address(program_id: &Pubkey, seed: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, seed.as_ref()], program_id)
+    }
+
+    pub fn space(signers: usize) -> usize {
+        HEADER_LEN + 32 + 4 + 32 * signers + 1 + 8 + 1
+    }
+}
+
+/// A marketplace instruction waiting for enough multisig approvals. It is
+/// executed with the multisig PDA signing, against exactly `accounts`.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct MultisigProposal {
+    pub multisig: Pubkey,
+    pub index: u64,
+    pub proposer: Pubkey,
+    pub accounts: Vec<Pubkey>,
+    pub data: Vec<u8>,
+    pub approvals: Vec<Pubkey>,
+    pub bump: u8,
+}
+
+impl ProgramAccount for MultisigProposal {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [13, 15, 144, 55, 252, 164, 83, 208];
+}
+
+impl MultisigProposal {
+    pub const SEED: &'static [u8] = b"proposal";
+    pub const MAX_ACCOUNTS: usize = 16;
+    pub const MAX_DATA: usize = 512;
+
+    pub fn find_address(program_id: &Pubkey, multisig: &Pubkey, index: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[Self::SEED, multisig.as_ref(), &index.to_le_bytes()],
+            program_id,
+        )
+    }
+
+    pub fn space(accounts: usize, data: usize, signers: usize) -> usize {
+        HEADER_LEN
+            + 32
+            + 8
+            + 32
+            + (4 + 32 * accounts)
+            + (4 + data)
+            + (4 + 32 * signers)
+            + 1
+    }
+}
+
+/// How purchases earn loyalty points and what the points are worth.
+/// Earning is off while `lamports_per_point` is 0.
+#[derive(Clone, Debug, Default, BorshDeserialize, BorshSerialize)]
+pub struct LoyaltyConfig {
+    pub lamports_per_point: u64, // lamports spent per point earned
+    pub point_value: u64, // fee credit in lamports per redeemed point
+    pub points_per_free_draw: u64, // 0 disables free draws
+    pub collection_multipliers: BTreeMap<Pubkey, u16>, // in bps, 10_000 is 1x
+}
+
+impl BorshSchema for LoyaltyConfig {
+    fn add_definitions_recursively(definitions: &mut HashMap<Declaration, Definition>) {
+        let fields = Fields::NamedFields(vec![
+            ("lamports_per_point".to_string(), u64::declaration()),
+            ("point_value".to_string(), u64::declaration()),
+            ("points_per_free_draw".to_string(), u64::declaration()),
+            ("collection_multipliers".to_string(), <MapSchema<Pubkey, u16>>::declaration()),
+        ]);
+        Self::add_definition(Self::declaration(), Definition::Struct { fields }, definitions);
+        <MapSchema<Pubkey, u16>>::add_definitions_recursively(definitions);
+    }
+
+    fn declaration() -> Declaration {
+        "LoyaltyConfig".to_string()
+    }
+}
+
+impl LoyaltyConfig {
+    pub const BASE_MULTIPLIER: u16 = 10_000;
+    pub const MAX_COLLECTIONS: usize = 8;
+
+    /// Points earned for spending `lamports` on an item of `collection`.
+    pub fn points_for(&self, lamports: u64, collection: Option<&Pubkey>) -> u64 {
+        if self.lamports_per_point == 0 {
+            return 0;
+        }
+        let multiplier = collection
+            .and_then(|collection| self.collection_multipliers.get(collection))
+            .copied()
+            .unwrap_or(Self::BASE_MULTIPLIER);
+        let points = lamports as u128 * multiplier as u128
+            / Self::BASE_MULTIPLIER as u128
+            / self.lamports_per_point as u128;
+        points.try_into().unwrap_or(u64::MAX)
+    }
+}
+
+/// A wallet's loyalty balance, credited on every purchase and gacha draw.
+#[derive(Clone, Debug, Default, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct LoyaltyAccount {
+    pub wallet: Pubkey,
+    pub points: u64,
+    pub fee_credit: u64, // lamports of marketplace fees that will be waived
+    pub free_draws: u32, // gacha calls with the fee waived
+    pub bump: u8,
+}
+
+impl ProgramAccount for LoyaltyAccount {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [246, 246, 248, 155, 190, 53, 91, 126];
+}
+
+impl LoyaltyAccount {
+    pub const SEED: &'static [u8] = b"loyalty";
+    pub const LEN: usize = HEADER_LEN + 32 + 8 + 8 + 4 + 1;
+
+    pub fn find_address(program_id: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, wallet.as_ref()], program_id)
+    }
+
+    /// Uses up fee credit against `fee` and returns what is still due.
+    pub fn apply_fee_credit(&mut self, fee: u64) -> u64 {
+        let waived = fee.min(self.fee_credit);
+        self.fee_credit -= waived;
+        fee - waived
+    }
+}
+
+/// A registered referrer. Its fee share is held on this PDA until claimed.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct ReferrerAccount {
+    pub wallet: Pubkey,
+    pub total_earned: u64,
+    pub unclaimed: u64,
+    pub bump: u8,
+}
+
+impl ProgramAccount for ReferrerAccount {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [48, 19, 160, 54, 76, 220, 70, 9];
+}
+
+impl ReferrerAccount {
+    pub const SEED: &'static [u8] = b"referrer";
+    pub const LEN: usize = HEADER_LEN + 32 + 8 + 8 + 1;
+    pub const MAX_BPS: u16 = 10_000;
+
+    pub fn find_address(program_id: &Pubkey, wallet: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, wallet.as_ref()], program_id)
+    }
+}
+
+/// An escrowed peer to peer trade. The proposer's NFTs sit in token
+/// accounts owned by this PDA and the offered lamports on the PDA itself.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct SwapOffer {
+    pub proposer: Pubkey,
+    pub counterparty: Pubkey,
+    pub swap_id: u64,
+    pub offered_mints: Vec<Pubkey>,
+    pub offered_lamports: u64,
+    pub requested_mints: Vec<Pubkey>,
+    pub requested_lamports: u64,
+    pub bump: u8,
+}
+
+impl ProgramAccount for SwapOffer {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [7, 43, 1, 115, 121, 33, 172, 68];
+}
+
+impl SwapOffer {
+    pub const SEED: &'static [u8] = b"swap";
+    pub const MAX_ITEMS: usize = 4; // per side, keeps accept within transaction limits
+
+    pub fn find_address(program_id: &Pubkey, proposer: &Pubkey, swap_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[Self::SEED, proposer.as_ref(), &swap_id.to_le_bytes()],
+            program_id,
+        )
+    }
+
+    pub fn space(offered: usize, requested: usize) -> usize {
+        HEADER_LEN + 32 + 32 + 8 + (4 + 32 * offered) + 8 + (4 + 32 * requested) + 8 + 1
+    }
+}
+
+/// A time-bound rental of one NFT. The NFT stays in a token account owned
+/// by this PDA for the whole listing, so the program controls it while a
+/// renter holds the rights; games read `renter`/`expires_at` from here.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct RentalListing {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub price_per_period: u64, // lamports
+    pub period_secs: i64,
+    pub max_periods: u32,
+    pub renter: Option<Pubkey>,
+    pub expires_at: i64, // unix timestamp
+    pub bump: u8,
+}
+
+impl ProgramAccount for RentalListing {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [76, 239, 227, 36, 98, 137, 187, 158];
+}
+
+impl RentalListing {
+    pub const SEED: &'static [u8] = b"rental";
+    pub const LEN: usize = HEADER_LEN + 32 + 32 + 8 + 8 + 4 + (1 + 32) + 8 + 1;
+
+    pub fn find_address(program_id: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, mint.as_ref()], program_id)
+    }
+
+    /// The wallet currently renting the NFT, if the rental has not expired.
+    pub fn active_renter(&self, now: i64) -> Option<Pubkey> {
+        self.renter.filter(|_| now < self.expires_at)
+    }
+}
+
+/// Global staking configuration. Every staked NFT earns
+/// `emission_rates[tier]` reward units per second; `reward_per_nft[tier]`
+/// accumulates those emissions so retuning a rate only affects the future.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct StakePool {
+    pub reward_mint: Pubkey,
+    pub emission_rates: [u64; StakePool::MAX_TIERS],
+    pub reward_per_nft: [u128; StakePool::MAX_TIERS],
+    pub last_update: i64,
+    pub total_staked: u64,
+    pub bump: u8,
+}
+
+impl ProgramAccount for StakePool {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [121, 34, 206, 21, 79, 127, 255, 28];
+}
+
+impl StakePool {
+    pub const SEED: &'static [u8] = b"stake_pool";
+    pub const MAX_TIERS: usize = 8;
+    pub const LEN: usize =
+        HEADER_LEN + 32 + 8 * Self::MAX_TIERS + 16 * Self::MAX_TIERS + 8 + 8 + 1;
+
+    /// The pool PDA, which also owns the reward vault and the staked NFTs.
+    pub fn find_address(program_id: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED], program_id)
+    }
+
+    /// Brings the per tier accumulators up to `now` at the current rates.
+    pub fn accrue(&mut self, now: i64) {
+        let elapsed = now.saturating_sub(self.last_update).max(0) as u128;
+        for (acc, rate) in self.reward_per_nft.iter_mut().zip(self.emission_rates) {
+            *acc = acc.saturating_add(elapsed.saturating_mul(rate as u128));
+        }
+        self.last_update = now.max(self.last_update);
+    }
+}
+
+/// Rarity tier assigned to a mint by a curator; only mints with one can be staked.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct RarityTier {
+    pub mint: Pubkey,
+    pub tier: u8,
+}
+
+impl ProgramAccount for RarityTier {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [1, 186, 134, 16, 183, 35, 12, 0];
+}
+
+impl RarityTier {
+    pub const SEED: &'static [u8] = b"rarity";
+    pub const LEN: usize = HEADER_LEN + 32 + 1;
+
+    pub fn find_address(program_id: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, mint.as_ref()], program_id)
+    }
+}
+
+/// Collection a mint belongs to, registered by the fee manager. Listings
+/// take their collection from here, so sellers cannot claim a boosted one.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct MintCollection {
+    pub mint: Pubkey,
+    pub collection: Option<Pubkey>,
+}
+
+impl ProgramAccount for MintCollection {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [253, 23, 13, 25, 51, 199, 73, 172];
+}
+
+impl MintCollection {
+    pub const SEED: &'static [u8] = b"collection";
+    pub const LEN: usize = HEADER_LEN + 32 + 1 + 32;
+
+    pub fn find_address(program_id: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, mint.as_ref()], program_id)
+    }
+}
+
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct StakeEntry {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub tier: u8,
+    pub reward_index: u128, // reward_per_nft[tier] at the last claim
+    pub owed: u64, // earned but not paid out because the vault ran dry
+    pub staked_at: i64,
+    pub bump: u8,
+}
+
+impl ProgramAccount for StakeEntry {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [187, 127, 9, 35, 155, 68, 86, 40];
+}
+
+impl StakeEntry {
+    pub const SEED: &'static [u8] = b"stake";
+    pub const LEN: usize = HEADER_LEN + 32 + 32 + 1 + 16 + 8 + 8 + 1;
+
+    pub fn find_address(program_id: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, mint.as_ref()], program_id)
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    Listed, // items the wallet put up for sale
+    Owned, // items the wallet bought or drew from the gacha
+}
+
+/// One wallet's item ids, so a client can fetch them with a single read
+/// instead of scanning the state map. Ids of cancelled listings are dropped
+/// from `Listed`; `Owned` keeps every id the wallet ever received, so it
+/// may name listings cancelled after a partial fill. Resolve it with `items`.
+#[derive(Clone, Debug, BorshDeserialize, BorshSerialize, BorshSchema)]
+pub struct WalletIndex {
+    pub wallet: Pubkey,
+    pub kind: IndexKind,
+    pub item_ids: Vec<u128>,
+    pub bump: u8,
+}
+
+impl ProgramAccount for WalletIndex {
+    const DISCRIMINATOR: [u8; DISCRIMINATOR_LEN] = [175, 97, 65, 11, 123, 121, 12, 83];
+}
+
+impl WalletIndex {
+    pub const SEED: &'static [u8] = b"wallet_index";
+
+    pub fn find_address(program_id: &Pubkey, wallet: &Pubkey, kind: IndexKind) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[Self::SEED, wallet.as_ref(), &[kind as u8]], program_id)
+    }
+
+    pub fn space(items: usize) -> usize {
+        HEADER_LEN + 32 + 1 + (4 + 16 * items) + 1
+    }
+
+    /// The indexed items still in `state`, in index order. Ids of items
+    /// that left the map are skipped.
+    pub fn items<'a>(&'a self, state: &'a State) -> impl Iterator<Item = &'a MarketItem> {
+        self.item_ids.iter().filter_map(|item_id| state.map.get(item_id))
+    }
+}
@@ -0,0 +1,549 @@
+//! Compute unit benchmarks for the marketplace instructions, against states
+//! of growing size and gacha pools of various sizes.
+//!
+//! Every instruction is simulated against a seeded state, with the swap,
+//! staking, rental, referral and multisig flows run for real between their
+//! steps, and fails the run when it consumes more than its budget:
+//! `CU_BUDGET_<INSTRUCTION>` (e.g. `CU_BUDGET_GACHA`) if set, else
+//! `CU_BUDGET`, else the default per instruction limit. Run with
+//! `--nocapture` to see the table.
+//!
+//! Native processors are not metered, so the bench loads the BPF build and
+//! only runs under `cargo test-sbf --test bench`, which enables `test-bpf`.
+//! Plain `cargo test` skips it.
+
+use solana_program::{
+    program_option::COption, program_pack::Pack, pubkey::Pubkey, rent::Rent, system_program,
+};
+use solana_program_test::{tokio, BanksClient, ProgramTest};
+use solana_sdk::{
+    account::Account as SolanaAccount, compute_budget::ComputeBudgetInstruction, hash::Hash,
+    instruction::Instruction, signature::Keypair, signer::Signer, transaction::Transaction,
+};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::state::{Account, AccountState, Mint};
+use testsolana::{
+    instruction::factory::{self, ConfigUpdate},
+    query::{ItemFilter, Page},
+    state::{MarketItem, ProgramAccount, Role, Roles, State},
+};
+
+const STATE_SIZES: [u128; 3] = [10, 100, 1_000];
+const POOL_SIZES: [u128; 3] = [1, 10, 100];
+const PRICE: u64 = 10;
+const GACHA_PRICE: u64 = 50;
+const REWARDS: u64 = 1_000;
+// the runtime's compute limit for an instruction that does not request more
+const DEFAULT_BUDGET: u64 = 200_000;
+
+struct Bench {
+    banks_client: BanksClient,
+    blockhash: Hash,
+    program_id: Pubkey,
+    admin: Keypair, // owner, every role, treasury and seller of all items
+    buyer: Keypair,
+    mint: Pubkey,
+    nft: Pubkey,         // a supply of one, held by the admin
+    reward_mint: Pubkey, // the admin holds the whole supply
+    items: u128,
+    pool: u128,
+    results: Vec<(String, u64, u64)>, // (label, units, budget)
+}
+
+impl Bench {
+    /// A marketplace with `items` listings of one unit each, the first
+    /// `pool` of which are in the gacha pool.
+    async fn start(items: u128, pool: u128) -> Self {
+        let program_id = Pubkey::new_unique();
+        let admin = Keypair::new();
+        let buyer = Keypair::new();
+        let mint = Pubkey::new_unique();
+        let nft = Pubkey::new_unique();
+        let reward_mint = Pubkey::new_unique();
+        let (state_pda, _) = State::find_address(&program_id);
+
+        let mut state = State {
+            item_ids: items,
+            owner: admin.pubkey(),
+            initialized: true,
+            roles: Roles::all(admin.pubkey()),
+            treasury: admin.pubkey(),
+            max_gacha_qty: State::DEFAULT_MAX_GACHA_QTY,
+            ..State::default()
+        };
+        for item_id in 1..=items {
+            let gacha = item_id <= pool;
+            state.map.insert(
+                item_id,
+                MarketItem {
+                    item_id,
+                    token_program_id: spl_token::id(),
+                    mint_address: mint,
+                    seller: admin.pubkey(),
+                    owner: None,
+                    price: if gacha { GACHA_PRICE } else { PRICE },
+                    file_name: format!("card-{item_id}.png"),
+                    description: "d".repeat(64),
+                    collection: None,
+                    sold: false,
+                    gacha,
+                    amount: 1,
+                    remaining: 1,
+                    decimals: 0,
+                },
+            );
+        }
+
+        let mut program_test = ProgramTest::new("testsolana", program_id, None);
+        program_test.prefer_bpf(true);
+        let data = state.pack_account().unwrap();
+        program_test.add_account(state_pda, program_account(data, program_id));
+        for wallet in [&admin, &buyer] {
+            program_test.add_account(
+                wallet.pubkey(),
+                SolanaAccount {
+                    lamports: 100_000_000_000,
+                    owner: system_program::id(),
+                    ..SolanaAccount::default()
+                },
+            );
+        }
+        for (address, supply) in [(mint, items as u64 + 1), (nft, 1), (reward_mint, REWARDS)] {
+            let mut mint_data = vec![0; Mint::LEN];
+            Mint {
+                mint_authority: COption::Some(admin.pubkey()),
+                supply,
+                decimals: 0,
+                is_initialized: true,
+                // the rental flow hands the NFT's freeze authority to its PDA
+                freeze_authority: if address == nft {
+                    COption::Some(admin.pubkey())
+                } else {
+                    COption::None
+                },
+            }
+            .pack_into_slice(&mut mint_data);
+            program_test.add_account(address, program_account(mint_data, spl_token::id()));
+        }
+        for (owner, mint, amount) in [
+            (state_pda, mint, items as u64),
+            (admin.pubkey(), mint, 1),
+            (buyer.pubkey(), mint, 0),
+            (admin.pubkey(), nft, 1),
+            (admin.pubkey(), reward_mint, REWARDS),
+        ] {
+            let mut data = vec![0; Account::LEN];
+            Account {
+                mint,
+                owner,
+                amount,
+                state: AccountState::Initialized,
+                ..Account::default()
+            }
+            .pack_into_slice(&mut data);
+            program_test.add_account(
+                get_associated_token_address(&owner, &mint),
+                program_account(data, spl_token::id()),
+            );
+        }
+
+        let (banks_client, _, blockhash) = program_test.start().await;
+        Self {
+            banks_client,
+            blockhash,
+            program_id,
+            admin,
+            buyer,
+            mint,
+            nft,
+            reward_mint,
+            items,
+            pool,
+            results: vec![],
+        }
+    }
+
+    /// Simulates `instruction` signed by `signer` and records its units.
+    async fn measure(&mut self, name: &str, instruction: Instruction, signer: &Keypair) {
+        let mut transaction = Transaction::new_with_payer(
+            &[
+                ComputeBudgetInstruction::set_compute_unit_limit(1_400_000),
+                instruction,
+            ],
+            Some(&signer.pubkey()),
+        );
+        transaction.sign(&[signer], self.blockhash);
+        let simulation = self
+            .banks_client
+            .simulate_transaction(transaction)
+            .await
+            .unwrap();
+        let details = simulation.simulation_details.unwrap();
+        if let Some(Err(err)) = simulation.result {
+            panic!(
+                "{name} failed with {} items: {err}\n{}",
+                self.items,
+                details.logs.join("\n")
+            );
+        }
+        assert_ne!(details.units_consumed, 0, "{name} was not metered");
+        let label = format!("{name} items={} pool={}", self.items, self.pool);
+        println!("{label:<40} {:>9} CU", details.units_consumed);
+        self.results
+            .push((label, details.units_consumed, budget(name)));
+    }
+
+    /// Runs `instruction` for real, setting up the accounts the following
+    /// measurements need.
+    async fn process(&mut self, instruction: Instruction, signer: &Keypair) {
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&signer.pubkey()),
+            &[signer],
+            self.blockhash,
+        );
+        self.banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap();
+    }
+}
+
+fn program_account(data: Vec<u8>, owner: Pubkey) -> SolanaAccount {
+    SolanaAccount {
+        lamports: Rent::default().minimum_balance(data.len()),
+        data,
+        owner,
+        ..SolanaAccount::default()
+    }
+}
+
+fn budget(name: &str) -> u64 {
+    let specific = format!("CU_BUDGET_{}", name.to_uppercase());
+    [specific.as_str(), "CU_BUDGET"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok())
+        .map(|budget| budget.parse().expect("budgets are whole compute units"))
+        .unwrap_or(DEFAULT_BUDGET)
+}
+
+/// Offers the admin's unit of `mint` to the buyer for a lamport.
+async fn bench_swap(bench: &mut Bench) {
+    let program_id = bench.program_id;
+    let admin = bench.admin.insecure_clone();
+    let buyer = bench.buyer.insecure_clone();
+    let mint = bench.mint;
+    let admin_token_account = get_associated_token_address(&admin.pubkey(), &mint);
+
+    let propose = factory::propose_swap(
+        &program_id,
+        &admin.pubkey(),
+        0,
+        &buyer.pubkey(),
+        &[(mint, admin_token_account)],
+        0,
+        &[],
+        1,
+    );
+    bench.measure("propose_swap", propose.clone(), &admin).await;
+    bench.process(propose, &admin).await;
+    let accept = factory::accept_swap(
+        &program_id,
+        &buyer.pubkey(),
+        &admin.pubkey(),
+        0,
+        &[mint],
+        &[],
+    );
+    bench.measure("accept_swap", accept, &buyer).await;
+    let cancel = factory::cancel_swap(&program_id, &admin.pubkey(), &admin.pubkey(), 0, &[mint]);
+    bench.measure("cancel_swap", cancel.clone(), &admin).await;
+    bench.process(cancel, &admin).await;
+}
+
+/// Stakes the admin's `nft` in a pool paying `reward_mint`.
+async fn bench_staking(bench: &mut Bench) {
+    let program_id = bench.program_id;
+    let admin = bench.admin.insecure_clone();
+    let mint = bench.nft;
+    let reward_mint = bench.reward_mint;
+    let admin_token_account = get_associated_token_address(&admin.pubkey(), &mint);
+    let reward_account = get_associated_token_address(&admin.pubkey(), &reward_mint);
+
+    let init = factory::init_stake_pool(&program_id, &admin.pubkey(), &reward_mint, vec![10]);
+    bench.measure("init_stake_pool", init.clone(), &admin).await;
+    bench.process(init, &admin).await;
+    let fund = factory::fund_stake_pool(
+        &program_id,
+        &admin.pubkey(),
+        &reward_mint,
+        &reward_account,
+        REWARDS,
+    );
+    bench.measure("fund_stake_pool", fund.clone(), &admin).await;
+    bench.process(fund, &admin).await;
+    let tier = factory::set_rarity_tier(&program_id, &admin.pubkey(), &mint, 0);
+    bench.measure("set_rarity_tier", tier.clone(), &admin).await;
+    bench.process(tier, &admin).await;
+    let stake = factory::stake_nft(&program_id, &admin.pubkey(), &mint, &admin_token_account);
+    bench.measure("stake_nft", stake.clone(), &admin).await;
+    bench.process(stake, &admin).await;
+    let claim = factory::claim_stake_rewards(
+        &program_id,
+        &admin.pubkey(),
+        &mint,
+        &reward_mint,
+        &reward_account,
+    );
+    bench.measure("claim_stake_rewards", claim, &admin).await;
+    let unstake = factory::unstake_nft(
+        &program_id,
+        &admin.pubkey(),
+        &mint,
+        &admin_token_account,
+        &reward_mint,
+        &reward_account,
+    );
+    bench.measure("unstake_nft", unstake.clone(), &admin).await;
+    bench.process(unstake, &admin).await;
+}
+
+/// Lists the admin's `nft` for rent to the buyer.
+async fn bench_rental(bench: &mut Bench) {
+    let program_id = bench.program_id;
+    let admin = bench.admin.insecure_clone();
+    let buyer = bench.buyer.insecure_clone();
+    let mint = bench.nft;
+    let admin_token_account = get_associated_token_address(&admin.pubkey(), &mint);
+
+    let create = factory::create_rental(
+        &program_id,
+        &admin.pubkey(),
+        &mint,
+        &admin_token_account,
+        PRICE,
+        60,
+        1,
+    );
+    bench.measure("create_rental", create.clone(), &admin).await;
+    bench.process(create, &admin).await;
+    let rent = factory::rent_item(
+        &program_id,
+        &buyer.pubkey(),
+        &admin.pubkey(),
+        &mint,
+        1,
+        None,
+    );
+    bench.measure("rent_item", rent, &buyer).await;
+    let reclaim =
+        factory::reclaim_rental(&program_id, &admin.pubkey(), &admin.pubkey(), &mint, None);
+    bench
+        .measure("reclaim_rental", reclaim.clone(), &admin)
+        .await;
+    bench.process(reclaim, &admin).await;
+}
+
+/// Refers the buyer's purchase of the last item, with fees high enough that
+/// the referral cut is not rounded away.
+async fn bench_referral(bench: &mut Bench) {
+    let program_id = bench.program_id;
+    let admin = bench.admin.insecure_clone();
+    let buyer = bench.buyer.insecure_clone();
+    let mint = bench.mint;
+
+    let register = factory::register_referrer(&program_id, &admin.pubkey());
+    bench
+        .measure("register_referrer", register.clone(), &admin)
+        .await;
+    bench.process(register, &admin).await;
+    let fees = factory::update_config(
+        &program_id,
+        &admin.pubkey(),
+        ConfigUpdate {
+            fee_bps: Some(State::MAX_FEE_BPS),
+            ..ConfigUpdate::default()
+        },
+    );
+    bench.process(fees, &admin).await;
+    let bps = factory::set_referral_bps(&program_id, &admin.pubkey(), 5_000);
+    bench.measure("set_referral_bps", bps.clone(), &admin).await;
+    bench.process(bps, &admin).await;
+    let purchase = factory::purchase_sale(
+        &program_id,
+        &buyer.pubkey(),
+        &get_associated_token_address(&buyer.pubkey(), &mint),
+        &admin.pubkey(),
+        &admin.pubkey(),
+        &mint,
+        bench.items,
+        PRICE,
+        1,
+        Some(&admin.pubkey()),
+    );
+    bench
+        .measure("purchase_sale_referred", purchase.clone(), &buyer)
+        .await;
+    bench.process(purchase, &buyer).await;
+    let claim = factory::claim_referral_earnings(&program_id, &admin.pubkey());
+    bench
+        .measure("claim_referral_earnings", claim, &admin)
+        .await;
+}
+
+/// A two of two multisig holding the pauser role, unpausing the marketplace.
+async fn bench_multisig(bench: &mut Bench) {
+    let program_id = bench.program_id;
+    let admin = bench.admin.insecure_clone();
+    let buyer = bench.buyer.insecure_clone();
+    let seed = Pubkey::new_unique();
+    let multisig = factory::multisig_address(&program_id, &seed);
+
+    let create = factory::create_multisig(
+        &program_id,
+        &admin.pubkey(),
+        &seed,
+        vec![admin.pubkey(), buyer.pubkey()],
+        2,
+    );
+    bench
+        .measure("create_multisig", create.clone(), &admin)
+        .await;
+    bench.process(create, &admin).await;
+    let role = factory::set_role(&program_id, &admin.pubkey(), Role::Pauser, &multisig);
+    bench.process(role, &admin).await;
+    let action = factory::set_paused(&program_id, &multisig, 0);
+    let propose =
+        factory::propose_multisig_action(&program_id, &admin.pubkey(), &multisig, 0, &action);
+    bench
+        .measure("propose_multisig_action", propose.clone(), &admin)
+        .await;
+    bench.process(propose, &admin).await;
+    let approve = factory::approve_multisig_action(&program_id, &buyer.pubkey(), &multisig, 0);
+    bench
+        .measure("approve_multisig_action", approve.clone(), &buyer)
+        .await;
+    bench.process(approve, &buyer).await;
+    let execute = factory::execute_multisig_action(
+        &program_id,
+        &admin.pubkey(),
+        &multisig,
+        0,
+        &admin.pubkey(),
+        &action,
+    );
+    bench
+        .measure("execute_multisig_action", execute, &admin)
+        .await;
+}
+
+#[tokio::test]
+#[cfg_attr(
+    not(feature = "test-bpf"),
+    ignore = "native processors are not metered, run with cargo test-sbf"
+)]
+async fn bench_compute_units() {
+    let mut results = vec![];
+    for items in STATE_SIZES {
+        for pool in POOL_SIZES.into_iter().filter(|pool| *pool < items) {
+            let mut bench = Bench::start(items, pool).await;
+            let program_id = bench.program_id;
+            let admin = bench.admin.insecure_clone();
+            let buyer = bench.buyer.insecure_clone();
+            let mint = bench.mint;
+            let buyer_token_account = get_associated_token_address(&buyer.pubkey(), &mint);
+
+            let candidates = [(admin.pubkey(), mint, buyer_token_account)];
+            let max_qty = pool.min(State::DEFAULT_MAX_GACHA_QTY as u128) as u8;
+            for (name, qty) in [("gacha", 1), ("gacha_max_qty", max_qty)] {
+                if qty == 1 && name != "gacha" {
+                    continue;
+                }
+                let instruction = factory::gacha(
+                    &program_id,
+                    &buyer.pubkey(),
+                    &admin.pubkey(),
+                    qty,
+                    GACHA_PRICE,
+                    None,
+                    &candidates,
+                );
+                bench.measure(name, instruction, &buyer).await;
+            }
+
+            // the rest does not depend on the pool size
+            if pool == POOL_SIZES[0] {
+                let seller_token_account = get_associated_token_address(&admin.pubkey(), &mint);
+                let listed = factory::create_market_item(
+                    &program_id,
+                    &admin.pubkey(),
+                    &mint,
+                    &seller_token_account,
+                    PRICE,
+                    1,
+                    "card.png",
+                    "a new listing",
+                );
+                bench.measure("create_market_item", listed, &admin).await;
+                let purchase = factory::purchase_sale(
+                    &program_id,
+                    &buyer.pubkey(),
+                    &buyer_token_account,
+                    &admin.pubkey(),
+                    &admin.pubkey(),
+                    &mint,
+                    items,
+                    PRICE,
+                    1,
+                    None,
+                );
+                bench.measure("purchase_sale", purchase, &buyer).await;
+                let cancel = factory::cancel_market_item(
+                    &program_id,
+                    &admin.pubkey(),
+                    &seller_token_account,
+                    &mint,
+                    items,
+                );
+                bench.measure("cancel_market_item", cancel, &admin).await;
+                let fill = factory::create_gacha(&program_id, &admin.pubkey(), &admin.pubkey(), 1);
+                bench.measure("create_gacha", fill, &admin).await;
+                let update = factory::update_config(
+                    &program_id,
+                    &admin.pubkey(),
+                    ConfigUpdate {
+                        fee_bps: Some(100),
+                        ..ConfigUpdate::default()
+                    },
+                );
+                bench.measure("update_config", update, &admin).await;
+                let pause = factory::set_paused(&program_id, &admin.pubkey(), State::PAUSE_GACHA);
+                bench.measure("set_paused", pause, &admin).await;
+                let fetch = factory::fetch_market_items(
+                    &program_id,
+                    ItemFilter::default(),
+                    Page::default(),
+                );
+                bench.measure("fetch_market_items", fetch, &admin).await;
+                let fetch = factory::fetch_my_nfts(&program_id, &buyer.pubkey(), Page::default());
+                bench.measure("fetch_my_nfts", fetch, &admin).await;
+
+                // the flows below run for real between measurements, each
+                // handing the admin's unit of `mint` back for the next one
+                bench_swap(&mut bench).await;
+                bench_staking(&mut bench).await;
+                bench_rental(&mut bench).await;
+                bench_referral(&mut bench).await;
+                bench_multisig(&mut bench).await;
+            }
+            results.append(&mut bench.results);
+        }
+    }
+
+    let over: Vec<String> = results
+        .iter()
+        .filter(|(_, units, budget)| units > budget)
+        .map(|(label, units, budget)| format!("{label}: {units} > {budget}"))
+        .collect();
+    assert!(over.is_empty(), "over budget:\n{}", over.join("\n"));
+}
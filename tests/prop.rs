@@ -0,0 +1,390 @@
+//! Property tests for instruction decoding and state serialization. They
+//! only exercise the codecs, so they need no validator or program-test
+//! bank. The fuzz targets under `fuzz/` cover the same decoders with
+//! coverage guidance.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use proptest::{collection, option, prelude::*, strategy::LazyJust};
+use solana_program::pubkey::Pubkey;
+use testsolana::{
+    instruction::{GachaMarketplaceInstruction as Ix, LoyaltyReward},
+    query::{ItemFilter, Page},
+    state::{LoyaltyConfig, MarketItem, ProgramAccount, Role, Roles, State},
+};
+
+/// Instruction variants, the first byte of the instruction data.
+const VARIANTS: u8 = 39;
+
+fn pubkey() -> impl Strategy<Value = Pubkey> {
+    any::<[u8; 32]>().prop_map(Pubkey::new_from_array)
+}
+
+fn pubkeys() -> impl Strategy<Value = Vec<Pubkey>> {
+    collection::vec(pubkey(), 0..8)
+}
+
+fn text() -> impl Strategy<Value = String> {
+    ".{0,40}"
+}
+
+fn page() -> impl Strategy<Value = Page> {
+    (option::of(any::<u128>()), any::<u16>()).prop_map(|(after, limit)| Page { after, limit })
+}
+
+fn item_filter() -> impl Strategy<Value = ItemFilter> {
+    (
+        any::<bool>(),
+        option::of(pubkey()),
+        option::of(pubkey()),
+        any::<bool>(),
+        option::of(any::<u64>()),
+        option::of(any::<u64>()),
+    )
+        .prop_map(
+            |(unsold, seller, owner, gacha_eligible, min_price, max_price)| ItemFilter {
+                unsold,
+                seller,
+                owner,
+                gacha_eligible,
+                min_price,
+                max_price,
+            },
+        )
+}
+
+fn role() -> impl Strategy<Value = Role> {
+    prop_oneof![
+        Just(Role::Owner),
+        Just(Role::FeeManager),
+        Just(Role::Pauser),
+        Just(Role::PoolCurator),
+        Just(Role::UpgradeAuthority),
+    ]
+}
+
+/// Every variant, with arbitrary fields.
+fn instruction() -> impl Strategy<Value = Ix> {
+    prop_oneof![
+        (
+            pubkey(),
+            pubkey(),
+            any::<u64>(),
+            text(),
+            text(),
+            any::<u64>()
+        )
+            .prop_map(
+                |(token_program_id, mint_address, price, file_name, description, amount)| {
+                    Ix::CreateMarketItem {
+                        token_program_id,
+                        mint_address,
+                        price,
+                        file_name,
+                        description,
+                        amount,
+                    }
+                }
+            ),
+        (
+            pubkey(),
+            any::<u64>(),
+            any::<u128>(),
+            any::<u64>(),
+            option::of(pubkey())
+        )
+            .prop_map(|(token_program_id, price, item_id, amount, referrer)| {
+                Ix::PurchaseSale {
+                    token_program_id,
+                    price,
+                    item_id,
+                    amount,
+                    referrer,
+                }
+            }),
+        (pubkey(), any::<u8>()).prop_map(|(token_program_id, qty)| Ix::CreateGacha {
+            token_program_id,
+            qty
+        }),
+        (pubkey(), any::<u8>(), any::<u64>(), option::of(pubkey())).prop_map(
+            |(token_program_id, qty, price, referrer)| Ix::Gacha {
+                token_program_id,
+                qty,
+                price,
+                referrer,
+            }
+        ),
+        any::<u64>().prop_map(|listing_price| Ix::InitState { listing_price }),
+        (pubkey(), any::<u128>()).prop_map(|(token_program_id, item_id)| {
+            Ix::CancelMarketItem {
+                token_program_id,
+                item_id,
+            }
+        }),
+        (
+            any::<u64>(),
+            pubkey(),
+            pubkeys(),
+            any::<u64>(),
+            pubkeys(),
+            any::<u64>()
+        )
+            .prop_map(
+                |(
+                    swap_id,
+                    counterparty,
+                    offered_mints,
+                    offered_lamports,
+                    requested_mints,
+                    requested_lamports,
+                )| Ix::ProposeSwap {
+                    swap_id,
+                    counterparty,
+                    offered_mints,
+                    offered_lamports,
+                    requested_mints,
+                    requested_lamports,
+                }
+            ),
+        (pubkey(), any::<u64>())
+            .prop_map(|(proposer, swap_id)| Ix::AcceptSwap { proposer, swap_id }),
+        (pubkey(), any::<u64>())
+            .prop_map(|(proposer, swap_id)| Ix::CancelSwap { proposer, swap_id }),
+        (any::<u64>(), any::<i64>(), any::<u32>()).prop_map(
+            |(price_per_period, period_secs, max_periods)| Ix::CreateRental {
+                price_per_period,
+                period_secs,
+                max_periods,
+            }
+        ),
+        any::<u32>().prop_map(|periods| Ix::RentItem { periods }),
+        LazyJust::new(|| Ix::ReclaimRental {}),
+        collection::vec(any::<u64>(), 0..8)
+            .prop_map(|emission_rates| Ix::InitStakePool { emission_rates }),
+        any::<u64>().prop_map(|amount| Ix::FundStakePool { amount }),
+        collection::vec(any::<u64>(), 0..8)
+            .prop_map(|emission_rates| Ix::SetEmissionRates { emission_rates }),
+        any::<u8>().prop_map(|tier| Ix::SetRarityTier { tier }),
+        LazyJust::new(|| Ix::StakeNft {}),
+        LazyJust::new(|| Ix::UnstakeNft {}),
+        LazyJust::new(|| Ix::ClaimStakeRewards {}),
+        (any::<u64>(), any::<u64>(), any::<u64>()).prop_map(
+            |(lamports_per_point, point_value, points_per_free_draw)| Ix::SetLoyaltyConfig {
+                lamports_per_point,
+                point_value,
+                points_per_free_draw,
+            }
+        ),
+        (pubkey(), any::<u16>()).prop_map(|(collection, multiplier_bps)| {
+            Ix::SetCollectionMultiplier {
+                collection,
+                multiplier_bps,
+            }
+        }),
+        prop_oneof![
+            any::<u64>().prop_map(|points| LoyaltyReward::FeeCredit { points }),
+            any::<u32>().prop_map(|draws| LoyaltyReward::FreeDraws { draws }),
+        ]
+        .prop_map(|reward| Ix::RedeemLoyaltyPoints { reward }),
+        LazyJust::new(|| Ix::RegisterReferrer {}),
+        any::<u16>().prop_map(|referral_bps| Ix::SetReferralBps { referral_bps }),
+        LazyJust::new(|| Ix::ClaimReferralEarnings {}),
+        (role(), pubkey()).prop_map(|(role, holder)| Ix::SetRole { role, holder }),
+        pubkey().prop_map(|new_owner| Ix::TransferOwnership { new_owner }),
+        LazyJust::new(|| Ix::AcceptOwnership {}),
+        (pubkey(), pubkeys(), any::<u8>()).prop_map(|(seed, signers, threshold)| {
+            Ix::CreateMultisig {
+                seed,
+                signers,
+                threshold,
+            }
+        }),
+        (pubkeys(), collection::vec(any::<u8>(), 0..64))
+            .prop_map(|(accounts, data)| Ix::ProposeMultisigAction { accounts, data }),
+        LazyJust::new(|| Ix::ApproveMultisigAction {}),
+        LazyJust::new(|| Ix::ExecuteMultisigAction {}),
+        any::<u32>().prop_map(|paused| Ix::SetPaused { paused }),
+        (
+            option::of(any::<u64>()),
+            option::of(any::<u16>()),
+            option::of(any::<u8>()),
+            option::of(pubkey()),
+            option::of(any::<u64>())
+        )
+            .prop_map(
+                |(listing_price, fee_bps, max_gacha_qty, treasury, gacha_fee)| {
+                    Ix::UpdateConfig {
+                        listing_price,
+                        fee_bps,
+                        max_gacha_qty,
+                        treasury,
+                        gacha_fee,
+                    }
+                }
+            ),
+        LazyJust::new(|| Ix::MigrateState {}),
+        (item_filter(), page()).prop_map(|(filter, page)| Ix::FetchMarketItems { filter, page }),
+        (pubkey(), page()).prop_map(|(wallet, page)| Ix::FetchMyNFTs { wallet, page }),
+        (pubkey(), page()).prop_map(|(seller, page)| Ix::FetchItemsCreated { seller, page }),
+        option::of(pubkey()).prop_map(|collection| Ix::SetMintCollection { collection }),
+    ]
+}
+
+fn market_item(item_id: u128) -> impl Strategy<Value = MarketItem> {
+    (
+        (
+            pubkey(),
+            pubkey(),
+            pubkey(),
+            option::of(pubkey()),
+            any::<u64>(),
+        ),
+        (text(), text(), option::of(pubkey())),
+        (
+            any::<bool>(),
+            any::<bool>(),
+            any::<u64>(),
+            any::<u64>(),
+            any::<u8>(),
+        ),
+    )
+        .prop_map(
+            move |(
+                (token_program_id, mint_address, seller, owner, price),
+                (file_name, description, collection),
+                (sold, gacha, amount, remaining, decimals),
+            )| MarketItem {
+                item_id,
+                token_program_id,
+                mint_address,
+                seller,
+                owner,
+                price,
+                file_name,
+                description,
+                collection,
+                sold,
+                gacha,
+                amount,
+                remaining,
+                decimals,
+            },
+        )
+}
+
+fn state() -> impl Strategy<Value = State> {
+    let map = collection::btree_set(any::<u128>(), 0..8).prop_flat_map(|ids| {
+        ids.into_iter()
+            .map(|id| market_item(id).prop_map(move |item| (id, item)))
+            .collect::<Vec<_>>()
+            .prop_map(|items| items.into_iter().collect())
+    });
+    let roles = (pubkey(), pubkey(), pubkey(), pubkey()).prop_map(
+        |(fee_manager, pauser, pool_curator, upgrade_authority)| Roles {
+            fee_manager,
+            pauser,
+            pool_curator,
+            upgrade_authority,
+        },
+    );
+    let loyalty = (
+        any::<u64>(),
+        any::<u64>(),
+        any::<u64>(),
+        collection::btree_map(pubkey(), any::<u16>(), 0..4),
+    )
+        .prop_map(
+            |(lamports_per_point, point_value, points_per_free_draw, collection_multipliers)| {
+                LoyaltyConfig {
+                    lamports_per_point,
+                    point_value,
+                    points_per_free_draw,
+                    collection_multipliers,
+                }
+            },
+        );
+    (
+        (map, any::<u128>(), any::<u128>(), pubkey(), any::<u64>()),
+        (any::<bool>(), loyalty, any::<u16>(), roles),
+        (option::of(pubkey()), any::<u32>(), pubkey(), any::<u16>()),
+        (any::<u8>(), any::<u64>()),
+    )
+        .prop_map(
+            |(
+                (map, item_ids, item_sold, owner, listing_price),
+                (initialized, loyalty, referral_bps, roles),
+                (pending_owner, paused, treasury, fee_bps),
+                (max_gacha_qty, gacha_fee),
+            )| State {
+                map,
+                item_ids,
+                item_sold,
+                owner,
+                listing_price,
+                initialized,
+                loyalty,
+                referral_bps,
+                roles,
+                pending_owner,
+                paused,
+                treasury,
+                fee_bps,
+                max_gacha_qty,
+                gacha_fee,
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn unpack_never_panics(data in collection::vec(any::<u8>(), 0..512)) {
+        let _ = Ix::unpack(&data);
+    }
+
+    #[test]
+    fn unpack_never_panics_on_known_variants(
+        variant in 0..VARIANTS,
+        body in collection::vec(any::<u8>(), 0..512),
+    ) {
+        let mut data = vec![variant];
+        data.extend(body);
+        // whatever decodes is the canonical encoding of what it decoded to
+        if let Ok(instruction) = Ix::unpack(&data) {
+            prop_assert_eq!(instruction.pack(), data);
+        }
+    }
+
+    #[test]
+    fn instructions_round_trip(instruction in instruction()) {
+        let data = instruction.pack();
+        prop_assert!(data[0] < VARIANTS);
+        let unpacked = Ix::unpack(&data).unwrap();
+        prop_assert_eq!(format!("{unpacked:?}"), format!("{instruction:?}"));
+        prop_assert_eq!(unpacked.pack(), data);
+    }
+
+    #[test]
+    fn market_items_round_trip(item in any::<u128>().prop_flat_map(market_item)) {
+        let data = item.try_to_vec().unwrap();
+        let decoded = MarketItem::try_from_slice(&data).unwrap();
+        prop_assert_eq!(decoded.try_to_vec().unwrap(), data);
+    }
+
+    #[test]
+    fn states_round_trip(state in state()) {
+        let account = state.pack_account().unwrap();
+        prop_assert_eq!(account.len(), state.packed_len().unwrap());
+        let unpacked = State::unpack_account(&account).unwrap();
+        prop_assert_eq!(unpacked.pack_account().unwrap(), account);
+    }
+
+    #[test]
+    fn unpack_account_never_panics(
+        version in 0..=State::VERSION + 1,
+        body in collection::vec(any::<u8>(), 0..1024),
+    ) {
+        let mut data = State::DISCRIMINATOR.to_vec();
+        data.push(version);
+        data.extend(body);
+        let _ = State::unpack_account(&data);
+    }
+}
@@ -1,345 +1,3203 @@
-use std::{collections::BTreeMap, mem};
-
-use borsh::{BorshDeserialize, BorshSerialize};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use borsh::BorshSerialize;
+use serde_json::json;
 use solana_program::{
-    account_info::AccountInfo,
-    borsh::try_from_slice_unchecked,
-    instruction::{AccountMeta, Instruction},
-    lamports,
-    pubkey::Pubkey,
-    stake_history::Epoch,
-    system_program,
+    clock::Clock, program::MAX_RETURN_DATA, program_error::ProgramError, program_option::COption,
+    pubkey::Pubkey, rent::Rent,
 };
-use solana_program_test::{processor, tokio, ProgramTest};
+use solana_program_test::{processor, tokio, BanksClient, ProgramTest, ProgramTestBanksClientExt};
 use solana_sdk::{
-    program_pack::Pack, signature::Keypair, signer::Signer, system_instruction,
-    transaction::Transaction,
+    account::Account as SolanaAccount,
+    hash::Hash,
+    instruction::{Instruction, InstructionError},
+    program_pack::Pack,
+    signature::{keypair_from_seed, Keypair},
+    signer::Signer,
+    system_instruction,
+    transaction::{Transaction, TransactionError},
 };
 use spl_associated_token_account::{
-    get_associated_token_address, instruction::create_associated_token_account, ID
+    get_associated_token_address, instruction::create_associated_token_account,
 };
 use spl_token::{
-    id, instruction,
+    id,
+    instruction::{self, AuthorityType},
     state::{Account, Mint},
 };
-use testsolana::{entrypoint::process_instruction, state::MarketItem};
+use testsolana::{
+    cli::{self, Options},
+    client::{ClientError, MarketplaceClient, NewListing},
+    entrypoint::process_instruction,
+    error::GachaError,
+    event::{self, MarketplaceEvent, EVENT_DISCRIMINATOR},
+    idl,
+    indexer::Indexer,
+    instruction::factory,
+    instruction::LoyaltyReward,
+    query::{self, ItemFilter, ItemPage, Page},
+    state::{
+        IndexKind, LoyaltyAccount, MarketItem, ProgramAccount, ReferrerAccount, RentalListing,
+        Role, Roles, StakeEntry, State, StateHeader, SwapOffer, WalletIndex,
+    },
+};
 
-#[tokio::test]
-async fn test_initialize_mint() {
-    let program_id = Pubkey::new_unique();
-    let program_test = ProgramTest::new(
+async fn process(
+    banks_client: &mut BanksClient,
+    instructions: &[Instruction],
+    payer: &Keypair,
+    signers: &[&Keypair],
+    recent_blockhash: Hash,
+) -> Result<(), solana_program_test::BanksClientError> {
+    let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+    let mut all_signers = vec![payer];
+    all_signers.extend_from_slice(signers);
+    transaction.sign(&all_signers, recent_blockhash);
+    banks_client.process_transaction(transaction).await
+}
+
+/// Creates a mint and puts `supply` units in the payer's associated token account.
+async fn create_mint(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    supply: u64,
+) -> (Pubkey, Pubkey) {
+    let mint_account = Keypair::new();
+    let rent = banks_client.get_rent().await.unwrap();
+    let token_account = get_associated_token_address(&payer.pubkey(), &mint_account.pubkey());
+
+    process(
+        banks_client,
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &mint_account.pubkey(),
+                rent.minimum_balance(Mint::LEN),
+                Mint::LEN as u64,
+                &id(),
+            ),
+            instruction::initialize_mint(
+                &id(),
+                &mint_account.pubkey(),
+                &payer.pubkey(),
+                Some(&payer.pubkey()),
+                0,
+            )
+            .unwrap(),
+            create_associated_token_account(
+                &payer.pubkey(),
+                &payer.pubkey(),
+                &mint_account.pubkey(),
+                &id(),
+            ),
+            instruction::mint_to(
+                &id(),
+                &mint_account.pubkey(),
+                &token_account,
+                &payer.pubkey(),
+                &[],
+                supply,
+            )
+            .unwrap(),
+        ],
+        payer,
+        &[&mint_account],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    (mint_account.pubkey(), token_account)
+}
+
+/// Funds a fresh wallet and gives it an associated token account for `mint`.
+async fn create_buyer(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    mint: &Pubkey,
+) -> (Keypair, Pubkey) {
+    let buyer = Keypair::new();
+    process(
+        banks_client,
+        &[
+            system_instruction::transfer(&payer.pubkey(), &buyer.pubkey(), 1_000_000_000),
+            create_associated_token_account(&payer.pubkey(), &buyer.pubkey(), mint, &id()),
+        ],
+        payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let token_account = get_associated_token_address(&buyer.pubkey(), mint);
+    (buyer, token_account)
+}
+
+async fn token_balance(banks_client: &mut BanksClient, token_account: Pubkey) -> u64 {
+    let account = banks_client
+        .get_account(token_account)
+        .await
+        .unwrap()
+        .expect("token account not found");
+    Account::unpack(&account.data).unwrap().amount
+}
+
+async fn get_state(banks_client: &mut BanksClient, program_id: &Pubkey) -> State {
+    let (state_pda, _) = State::find_address(program_id);
+    let state_account = banks_client
+        .get_account(state_pda)
+        .await
+        .expect("get_account")
+        .expect("state_account not found");
+    State::unpack_account(&state_account.data).unwrap()
+}
+
+async fn get_wallet_index(
+    banks_client: &mut BanksClient,
+    program_id: &Pubkey,
+    wallet: &Pubkey,
+    kind: IndexKind,
+) -> Vec<u128> {
+    let address = factory::wallet_index_address(program_id, wallet, kind);
+    let account = banks_client
+        .get_account(address)
+        .await
+        .unwrap()
+        .expect("wallet index not found");
+    WalletIndex::unpack_account(&account.data).unwrap().item_ids
+}
+
+fn program_test(program_id: Pubkey) -> ProgramTest {
+    ProgramTest::new(
         "testsolana", // Run the BPF version with `cargo test-bpf`
         program_id,
         processor!(process_instruction), // Run the native version with `cargo test`
-    );
-    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    )
+}
 
-    let mint_account = Keypair::new();
-    let token_program = &id();
-    let rent = banks_client.get_rent().await.unwrap();
-    let mint_rent = rent.minimum_balance(Mint::LEN);
+#[tokio::test]
+async fn test_partial_fill_listing() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
 
-    let token_mint_a_account_ix = solana_program::system_instruction::create_account(
-        &payer.pubkey(),
-        &mint_account.pubkey(),
-        mint_rent,
-        Mint::LEN as u64,
-        token_program,
-    );
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 10).await;
 
-    let token_mint_a_ix = instruction::initialize_mint(
-        token_program,
-        &mint_account.pubkey(),
-        &payer.pubkey(),
-        None,
-        0,
+    // INIT STATE
+    process(
+        &mut banks_client,
+        &[factory::init_state(&program_id, &payer.pubkey(), 1)],
+        &payer,
+        &[],
+        recent_blockhash,
     )
+    .await
     .unwrap();
 
-    // create mint transaction
-    let token_mint_a_tx = Transaction::new_signed_with_payer(
-        &[token_mint_a_account_ix, token_mint_a_ix],
-        Some(&payer.pubkey()),
-        &[&payer, &mint_account],
+    // CREATE MARKET ITEM
+    process(
+        &mut banks_client,
+        &[factory::create_market_item(
+            &program_id,
+            &payer.pubkey(),
+            &mint,
+            &seller_token_account,
+            100,
+            5,
+            "potion",
+            "heals 10hp",
+        )],
+        &payer,
+        &[],
         recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let escrow = factory::escrow_address(&program_id, &mint, &id());
+    assert_eq!(token_balance(&mut banks_client, escrow).await, 5);
+    assert_eq!(
+        token_balance(&mut banks_client, seller_token_account).await,
+        5
     );
 
-    banks_client
-        .process_transaction(token_mint_a_tx)
-        .await
-        .unwrap();
+    let state = get_state(&mut banks_client, &program_id).await;
+    let item = state.map.get(&1).unwrap();
+    assert_eq!(item.file_name, "potion");
+    assert_eq!(item.amount, 5);
+    assert_eq!(item.remaining, 5);
 
-    // Create account that can hold the newly minted tokens
-    let account_rent = rent.minimum_balance(Account::LEN);
-    let token_account = Keypair::new();
-    let new_token_account_ix = system_instruction::create_account(
-        &payer.pubkey(),
-        &token_account.pubkey(),
-        account_rent,
-        Account::LEN as u64,
-        token_program,
+    // PURCHASE SALE, partial fill
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+    let purchase = |amount| {
+        factory::purchase_sale(
+            &program_id,
+            &buyer.pubkey(),
+            &buyer_token_account,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &mint,
+            1,
+            100,
+            amount,
+            None,
+        )
+    };
+    process(
+        &mut banks_client,
+        &[purchase(2)],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut banks_client, buyer_token_account).await,
+        2
     );
+    let state = get_state(&mut banks_client, &program_id).await;
+    let item = state.map.get(&1).unwrap();
+    assert_eq!(item.remaining, 3);
+    assert!(!item.sold);
+    assert_eq!(state.item_sold, 0);
 
-    let initialize_account_ix = instruction::initialize_account(
-        token_program,
-        &token_account.pubkey(),
-        &mint_account.pubkey(),
-        &payer.pubkey(),
+    // more than what is left
+    assert!(process(
+        &mut banks_client,
+        &[purchase(4)],
+        &buyer,
+        &[],
+        recent_blockhash
     )
-    .unwrap();
+    .await
+    .is_err());
 
-    let create_new_token_account_tx = Transaction::new_signed_with_payer(
-        &[new_token_account_ix, initialize_account_ix],
-        Some(&payer.pubkey()),
-        &[&payer, &token_account],
+    // exhaust the listing
+    process(
+        &mut banks_client,
+        &[purchase(3)],
+        &buyer,
+        &[],
         recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut banks_client, buyer_token_account).await,
+        5
     );
-    banks_client
-        .process_transaction(create_new_token_account_tx)
+    assert_eq!(token_balance(&mut banks_client, escrow).await, 0);
+    let state = get_state(&mut banks_client, &program_id).await;
+    let item = state.map.get(&1).unwrap();
+    assert_eq!(item.remaining, 0);
+    assert!(item.sold);
+    // spread over several buyers, a multi-unit listing keeps no owner
+    assert_eq!(item.owner, None);
+    assert_eq!(state.item_sold, 1);
+
+    // a sold out listing fails with its own code, printed in the logs
+    let mut transaction = Transaction::new_with_payer(&[purchase(1)], Some(&buyer.pubkey()));
+    transaction.sign(&[&buyer], recent_blockhash);
+    let simulation = banks_client
+        .simulate_transaction(transaction)
         .await
         .unwrap();
+    let Some(Err(TransactionError::InstructionError(0, err))) = simulation.result else {
+        panic!("purchase of a sold out item succeeded");
+    };
+    assert_eq!(
+        GachaError::from_instruction_error(&err),
+        Some(GachaError::ItemAlreadySold)
+    );
+    assert!(simulation
+        .simulation_details
+        .unwrap()
+        .logs
+        .contains(&"Program log: Error: Market item has already sold out".to_string()));
+}
 
-    // Mint tokens into newly created account
-    let mint_amount: u64 = 1;
-    let mint_to_ix = instruction::mint_to(
-        &token_program,
-        &mint_account.pubkey(),
-        &token_account.pubkey(),
-        &payer.pubkey(),
+#[tokio::test]
+async fn test_gacha_draw_and_cancel() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 4).await;
+
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                50,
+                2,
+                "card",
+                "common",
+            ),
+            factory::create_gacha(&program_id, &payer.pubkey(), &payer.pubkey(), 1),
+            factory::update_config(
+                &program_id,
+                &payer.pubkey(),
+                factory::ConfigUpdate {
+                    gacha_fee: Some(10),
+                    ..Default::default()
+                },
+            ),
+        ],
+        &payer,
         &[],
-        mint_amount.clone(),
+        recent_blockhash,
     )
+    .await
     .unwrap();
 
-    let mint_to_tx = Transaction::new_signed_with_payer(
-        &[mint_to_ix],
-        Some(&payer.pubkey()),
-        &[&payer, &payer],
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert!(state.map.get(&1).unwrap().gacha);
+    assert_eq!(state.gacha_fee, 10);
+
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+
+    // only the pool curator fills the pool
+    let err = process(
+        &mut banks_client,
+        &[factory::create_gacha(
+            &program_id,
+            &buyer.pubkey(),
+            &payer.pubkey(),
+            1,
+        )],
+        &buyer,
+        &[],
         recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::MissingRole as u32)
+        )
     );
-    banks_client.process_transaction(mint_to_tx).await.unwrap();
 
-    // Inspect account
-    let token_account_info = banks_client
-        .get_account(token_account.pubkey().clone())
-        .await
-        .unwrap()
-        .expect("could not fetch account information");
-    let account_data = Account::unpack(&token_account_info.data).unwrap();
-    println!("account data: {:?}", account_data);
+    // pooled units can only be drawn
+    let err = process(
+        &mut banks_client,
+        &[factory::purchase_sale(
+            &program_id,
+            &buyer.pubkey(),
+            &buyer_token_account,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &mint,
+            1,
+            50,
+            1,
+            None,
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
     assert_eq!(
-        account_data.amount,
-        mint_amount.clone(),
-        "not correct amount"
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::ItemInGachaPool as u32)
+        )
     );
 
-    let rs = banks_client
-        .get_account(account_data.mint)
-        .await
-        .unwrap()
-        .expect("could not fetch account information");
-    println!("...........{:?}", rs);
-
-    let other_user = Keypair::new();
-    let new_owner = Pubkey::default();
-
-    #[derive(BorshDeserialize, BorshSerialize)]
-    struct GachaMarketplacePayload {
-        token_program_id: Pubkey, // program id,
-        mint_address: Pubkey,     // ATA
-        price: u128,
-        file_name: String,
-        description: String,
-        cash_back: u8,
-        qty: u8,
-        fee: u128,
-        item_id: u128,
-        listing_price: u128,
-        variant: u8,
-    }
-
-    let (state_pda, state_bump) = Pubkey::find_program_address(&[b"state".as_ref()], &program_id);
-
-    // INIT STATE
-    let param_init_state = GachaMarketplacePayload {
-        token_program_id: *token_program,     // program id,
-        mint_address: token_account.pubkey(), // ATA
-        price: 1,
-        file_name: "zxczxc".to_string(),
-        description: "zxczxc".to_string(),
-        cash_back: 0,
-        variant: 4,
-        qty: 0,
-        fee: 0,
-        item_id: 0,
-        listing_price: 1,
-    };
-    let mut transaction = Transaction::new_with_payer(
-        &[Instruction::new_with_borsh(
-            program_id,
-            &param_init_state,
-            vec![
-                AccountMeta::new(payer.pubkey(), true),
-                AccountMeta::new(state_pda, false),
-                AccountMeta::new(system_program::ID, false),
-            ],
+    // leaving out the accounts of an eligible item fails before the draw
+    let err = process(
+        &mut banks_client,
+        &[factory::gacha(
+            &program_id,
+            &buyer.pubkey(),
+            &payer.pubkey(),
+            1,
+            50,
+            None,
+            &[],
         )],
-        Some(&payer.pubkey()),
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::MissingGachaAccounts as u32)
+        )
     );
 
-    transaction.sign(&[&payer], recent_blockhash);
+    // the seller, also the treasury here, gets the price and the draw fee
+    let seller_before = banks_client.get_balance(payer.pubkey()).await.unwrap();
+    process(
+        &mut banks_client,
+        &[factory::gacha(
+            &program_id,
+            &buyer.pubkey(),
+            &payer.pubkey(),
+            1,
+            50,
+            None,
+            &[(payer.pubkey(), mint, buyer_token_account)],
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        banks_client.get_balance(payer.pubkey()).await.unwrap(),
+        seller_before + 50 + 10
+    );
 
-    match banks_client.process_transaction(transaction).await {
-        Ok(()) => (),
-        Err(e) => panic!("{}", e),
-    }
+    assert_eq!(
+        token_balance(&mut banks_client, buyer_token_account).await,
+        1
+    );
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert_eq!(state.map.get(&1).unwrap().remaining, 1);
+    assert_eq!(
+        get_wallet_index(
+            &mut banks_client,
+            &program_id,
+            &buyer.pubkey(),
+            IndexKind::Owned
+        )
+        .await,
+        vec![1]
+    );
 
-    #[derive(BorshDeserialize, BorshSerialize)]
-    pub struct State {
-        pub map: BTreeMap<u128, MarketItem>, // 100
-        pub item_ids: u128,
-        pub item_sold: u128,
-        pub owner: Pubkey,
-        pub seed: u64,
-    }
+    // CANCEL returns the remaining unit to the seller
+    process(
+        &mut banks_client,
+        &[factory::cancel_market_item(
+            &program_id,
+            &payer.pubkey(),
+            &seller_token_account,
+            &mint,
+            1,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
 
-    // let new_state_account_after = banks_client
-    //     .get_account(other_user.pubkey())
-    //     .await
-    //     .expect("get_account")
-    //     .expect("state_account not found");
-    // assert_eq!(new_state_account_after.lamports, 5380080);
-    let new_account = Keypair::new();
-    let (nft_pda, nft_bump) = Pubkey::find_program_address(
-        &[b"nft".as_ref(), &token_account.pubkey().to_bytes()],
-        &program_id,
+    assert_eq!(
+        token_balance(&mut banks_client, seller_token_account).await,
+        3
     );
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert!(state.map.is_empty());
+    assert!(get_wallet_index(
+        &mut banks_client,
+        &program_id,
+        &payer.pubkey(),
+        IndexKind::Listed
+    )
+    .await
+    .is_empty());
+}
 
-    let mint_keypair = Keypair::new();
-    let token_address = get_associated_token_address(&mint_keypair.pubkey(), &payer.pubkey());
+#[tokio::test]
+async fn test_swap_accept_and_cancel() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
 
-    // CREATE MARKET ITEM
-    let param_create_item = GachaMarketplacePayload {
-        token_program_id: *token_program,     // program id,
-        mint_address: token_account.pubkey(), // ATA
-        price: 1,
-        file_name: "zxczxc".to_string(),
-        description: "zxczxc".to_string(),
-        cash_back: 0,
-        variant: 0,
-        qty: 0,
-        fee: 0,
-        item_id: 0,
-        listing_price: 0,
-    };
-    let mut transaction = Transaction::new_with_payer(
-        &[Instruction::new_with_borsh(
-            program_id,
-            &param_create_item,
-            vec![
-                AccountMeta::new(payer.pubkey(), true),
-                AccountMeta::new(state_pda, false),
-                // AccountMeta::new(nft_pda, false),
-                AccountMeta::new(mint_keypair.pubkey(), true),
-                AccountMeta::new(token_account.pubkey(), false),
-                AccountMeta::new_readonly(id(), false),
-                AccountMeta::new_readonly(ID, false),
-                // AccountMeta::new(system_program::ID, false),
-            ],
+    let (mint_a, proposer_token_a) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 4).await;
+    let (mint_b, proposer_token_b) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 1).await;
+    let (counterparty, counterparty_token_b) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint_b).await;
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            instruction::transfer(
+                &id(),
+                &proposer_token_b,
+                &counterparty_token_b,
+                &payer.pubkey(),
+                &[],
+                1,
+            )
+            .unwrap(),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // PROPOSE: one A plus lamports for one B plus lamports
+    process(
+        &mut banks_client,
+        &[factory::propose_swap(
+            &program_id,
+            &payer.pubkey(),
+            1,
+            &counterparty.pubkey(),
+            &[(mint_a, proposer_token_a)],
+            1_000,
+            &[mint_b],
+            500,
         )],
-        Some(&payer.pubkey()),
-    );
-    transaction.sign(&[&payer, &mint_keypair], recent_blockhash);
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
 
-    match banks_client.process_transaction(transaction).await {
-        Ok(()) => (),
-        Err(e) => panic!("{}", e),
-    }
+    let (swap_pda, _) = SwapOffer::find_address(&program_id, &payer.pubkey(), 1);
+    let swap_escrow = get_associated_token_address(&swap_pda, &mint_a);
+    assert_eq!(token_balance(&mut banks_client, swap_escrow).await, 1);
+    assert_eq!(token_balance(&mut banks_client, proposer_token_a).await, 3);
+
+    // anyone can send more units to the escrow, they must not block the swap
+    process(
+        &mut banks_client,
+        &[instruction::transfer(
+            &id(),
+            &proposer_token_a,
+            &swap_escrow,
+            &payer.pubkey(),
+            &[],
+            1,
+        )
+        .unwrap()],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
 
-    let new_state_account_after = banks_client
-        .get_account(other_user.pubkey())
+    // ACCEPT
+    let counterparty_before = banks_client
+        .get_balance(counterparty.pubkey())
         .await
-        .expect("get_account")
-        .expect("state_account not found");
-    assert_eq!(new_state_account_after.lamports, 5381080);
-
-    // ANOTHER MARKET ITEM
-    let param_create_item2 = GachaMarketplacePayload {
-        token_program_id: *token_program,     // program id,
-        mint_address: token_account.pubkey(), // ATA
-        price: 3,
-        file_name: "file_name".to_string(),
-        description: "file_name".to_string(),
-        cash_back: 0,
-        variant: 0,
-        qty: 0,
-        fee: 0,
-        item_id: 0,
-        listing_price: 0,
-    };
-    let mut transaction2 = Transaction::new_with_payer(
-        &[Instruction::new_with_borsh(
-            program_id,
-            &param_create_item2,
-            vec![
-                AccountMeta::new(payer.pubkey(), true),
-                AccountMeta::new(state_pda, false),
-                AccountMeta::new(system_program::ID, false),
-            ],
+        .unwrap();
+    process(
+        &mut banks_client,
+        &[factory::accept_swap(
+            &program_id,
+            &counterparty.pubkey(),
+            &payer.pubkey(),
+            1,
+            &[mint_a],
+            &[(mint_b, counterparty_token_b)],
         )],
-        Some(&payer.pubkey()),
+        &counterparty,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let counterparty_token_a = get_associated_token_address(&counterparty.pubkey(), &mint_a);
+    assert_eq!(
+        token_balance(&mut banks_client, counterparty_token_a).await,
+        2
+    );
+    assert_eq!(token_balance(&mut banks_client, proposer_token_b).await, 1);
+    assert_eq!(
+        token_balance(&mut banks_client, counterparty_token_b).await,
+        0
+    );
+    assert!(banks_client.get_account(swap_pda).await.unwrap().is_none());
+    assert!(banks_client
+        .get_account(swap_escrow)
+        .await
+        .unwrap()
+        .is_none());
+    let rent = banks_client.get_rent().await.unwrap();
+    let counterparty_after = banks_client
+        .get_balance(counterparty.pubkey())
+        .await
+        .unwrap();
+    // +1_000 offered, -500 requested, minus the new token account and the fee
+    assert_eq!(
+        counterparty_after,
+        counterparty_before + 500 - rent.minimum_balance(Account::LEN) - 5_000
     );
-    transaction2.sign(&[&payer], recent_blockhash);
 
-    match banks_client.process_transaction(transaction2).await {
-        Ok(()) => (),
-        Err(e) => panic!("{}", e),
-    }
+    // a second offer is called off by the counterparty and refunded
+    process(
+        &mut banks_client,
+        &[factory::propose_swap(
+            &program_id,
+            &payer.pubkey(),
+            2,
+            &counterparty.pubkey(),
+            &[(mint_a, proposer_token_a)],
+            0,
+            &[mint_b],
+            0,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let swap_escrow = get_associated_token_address(
+        &SwapOffer::find_address(&program_id, &payer.pubkey(), 2).0,
+        &mint_a,
+    );
+    process(
+        &mut banks_client,
+        &[instruction::transfer(
+            &id(),
+            &proposer_token_a,
+            &swap_escrow,
+            &payer.pubkey(),
+            &[],
+            1,
+        )
+        .unwrap()],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(token_balance(&mut banks_client, proposer_token_a).await, 0);
+    // the emptied account is closed, the refund has to recreate it
+    process(
+        &mut banks_client,
+        &[instruction::close_account(
+            &id(),
+            &proposer_token_a,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &[],
+        )
+        .unwrap()],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert!(banks_client
+        .get_account(proposer_token_a)
+        .await
+        .unwrap()
+        .is_none());
 
-    let new_state_account = banks_client
-        .get_account(state_pda)
+    process(
+        &mut banks_client,
+        &[factory::cancel_swap(
+            &program_id,
+            &counterparty.pubkey(),
+            &payer.pubkey(),
+            2,
+            &[mint_a],
+        )],
+        &counterparty,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(token_balance(&mut banks_client, proposer_token_a).await, 2);
+    let (swap_pda, _) = SwapOffer::find_address(&program_id, &payer.pubkey(), 2);
+    assert!(banks_client.get_account(swap_pda).await.unwrap().is_none());
+    assert!(banks_client
+        .get_account(swap_escrow)
         .await
-        .expect("get_account")
-        .expect("state_account not found");
-    let state = try_from_slice_unchecked::<State>(&new_state_account.data).unwrap();
-    assert_eq!(state.map.len(), 2);
-    assert_eq!(state.map.get(&1).unwrap().file_name, "zxczxc");
-
-    // PURCHASE SALE
-    let param_create_item = GachaMarketplacePayload {
-        token_program_id: *token_program,     // program id,
-        mint_address: token_account.pubkey(), // ATA
-        price: 3,
-
-        file_name: "file_name".to_string(),
-        description: "file_name".to_string(),
-        cash_back: 0,
-        variant: 0,
-        qty: 0,
-        fee: 0,
-        item_id: 0,
-        listing_price: 0,
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_rental_expiry_and_reclaim() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let payer = Keypair::from_bytes(&context.payer.to_bytes()).unwrap();
+    let recent_blockhash = context.last_blockhash;
+
+    // the owner is a separate wallet so the payer can act as a third party
+    let (mint, payer_token_account) =
+        create_mint(&mut context.banks_client, &payer, recent_blockhash, 1).await;
+    let (owner, owner_token_account) =
+        create_buyer(&mut context.banks_client, &payer, recent_blockhash, &mint).await;
+    // the renters' token accounts are created by the rentals
+    let (renter, next_renter) = (Keypair::new(), Keypair::new());
+    process(
+        &mut context.banks_client,
+        &[
+            system_instruction::transfer(&payer.pubkey(), &renter.pubkey(), 1_000_000_000),
+            system_instruction::transfer(&payer.pubkey(), &next_renter.pubkey(), 1_000_000_000),
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            instruction::transfer(
+                &id(),
+                &payer_token_account,
+                &owner_token_account,
+                &payer.pubkey(),
+                &[],
+                1,
+            )
+            .unwrap(),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // the rental freezes the NFT in the renter's wallet, so the owner must
+    // be able to hand over the freeze authority
+    let create = |price_per_period| {
+        factory::create_rental(
+            &program_id,
+            &owner.pubkey(),
+            &mint,
+            &owner_token_account,
+            price_per_period,
+            100,
+            3,
+        )
     };
-    let mut transaction = Transaction::new_with_payer(
-        &[Instruction::new_with_borsh(
-            program_id,
-            &param_create_item,
-            vec![
-                AccountMeta::new(payer.pubkey(), true),
-                AccountMeta::new(state_pda, false),
-                AccountMeta::new(
-                    state.map.get(&param_create_item.item_id).unwrap().seller,
-                    false,
-                ),
-                AccountMeta::new(system_program::ID, false),
-            ],
+    let err = process(
+        &mut context.banks_client,
+        &[create(999)],
+        &owner,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::InvalidFreezeAuthority as u32)
+        )
+    );
+    process(
+        &mut context.banks_client,
+        &[instruction::set_authority(
+            &id(),
+            &mint,
+            Some(&owner.pubkey()),
+            AuthorityType::FreezeAccount,
+            &payer.pubkey(),
+            &[],
+        )
+        .unwrap()],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    process(
+        &mut context.banks_client,
+        &[create(1_000)],
+        &owner,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, owner_token_account).await,
+        0
+    );
+    let (rental_pda, _) = RentalListing::find_address(&program_id, &mint);
+    let freeze_authority = |mint_account: Option<SolanaAccount>| {
+        Mint::unpack(&mint_account.unwrap().data)
+            .unwrap()
+            .freeze_authority
+    };
+    assert_eq!(
+        freeze_authority(context.banks_client.get_account(mint).await.unwrap()),
+        COption::Some(rental_pda)
+    );
+
+    let owner_before = context
+        .banks_client
+        .get_balance(owner.pubkey())
+        .await
+        .unwrap();
+    process(
+        &mut context.banks_client,
+        &[factory::rent_item(
+            &program_id,
+            &renter.pubkey(),
+            &owner.pubkey(),
+            &mint,
+            2,
+            None,
         )],
-        Some(&payer.pubkey()),
+        &renter,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(owner.pubkey())
+            .await
+            .unwrap(),
+        owner_before + 2_000
     );
-    transaction.sign(&[&payer], recent_blockhash);
 
-    match banks_client.process_transaction(transaction).await {
-        Ok(()) => (),
-        Err(e) => panic!("{}", e),
-    }
+    // the renter holds the NFT, frozen, with the rental PDA as delegate
+    let renter_token_account = get_associated_token_address(&renter.pubkey(), &mint);
+    let held = Account::unpack(
+        &context
+            .banks_client
+            .get_account(renter_token_account)
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert_eq!(held.amount, 1);
+    assert!(held.is_frozen());
+    assert_eq!(held.delegate, COption::Some(rental_pda));
+    assert!(process(
+        &mut context.banks_client,
+        &[instruction::transfer(
+            &id(),
+            &renter_token_account,
+            &owner_token_account,
+            &renter.pubkey(),
+            &[],
+            1,
+        )
+        .unwrap()],
+        &renter,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+
+    let rental_account = context
+        .banks_client
+        .get_account(rental_pda)
+        .await
+        .unwrap()
+        .unwrap();
+    let rental = RentalListing::unpack_account(&rental_account.data).unwrap();
+    let clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    assert_eq!(
+        rental.active_renter(clock.unix_timestamp),
+        Some(renter.pubkey())
+    );
+    assert_eq!(rental.expires_at, clock.unix_timestamp + 200);
+
+    // nobody can reclaim while the rental is running
+    assert!(process(
+        &mut context.banks_client,
+        &[factory::reclaim_rental(
+            &program_id,
+            &renter.pubkey(),
+            &owner.pubkey(),
+            &mint,
+            Some(&renter.pubkey()),
+        )],
+        &renter,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+
+    // once expired the next renter takes it straight from the last one
+    context.set_sysvar(&Clock {
+        unix_timestamp: rental.expires_at,
+        ..clock.clone()
+    });
+    process(
+        &mut context.banks_client,
+        &[factory::rent_item(
+            &program_id,
+            &next_renter.pubkey(),
+            &owner.pubkey(),
+            &mint,
+            1,
+            Some(&renter.pubkey()),
+        )],
+        &next_renter,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let next_renter_token_account = get_associated_token_address(&next_renter.pubkey(), &mint);
+    assert_eq!(
+        token_balance(&mut context.banks_client, next_renter_token_account).await,
+        1
+    );
+    let returned = Account::unpack(
+        &context
+            .banks_client
+            .get_account(renter_token_account)
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+    )
+    .unwrap();
+    assert_eq!(returned.amount, 0);
+    assert!(!returned.is_frozen());
+    assert_eq!(returned.delegate, COption::None);
+
+    // anyone can return it once expired
+    context.set_sysvar(&Clock {
+        unix_timestamp: rental.expires_at + 100,
+        ..clock
+    });
+    process(
+        &mut context.banks_client,
+        &[factory::reclaim_rental(
+            &program_id,
+            &payer.pubkey(),
+            &owner.pubkey(),
+            &mint,
+            Some(&next_renter.pubkey()),
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut context.banks_client, owner_token_account).await,
+        1
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, next_renter_token_account).await,
+        0
+    );
+    assert_eq!(
+        freeze_authority(context.banks_client.get_account(mint).await.unwrap()),
+        COption::Some(owner.pubkey())
+    );
+    assert!(context
+        .banks_client
+        .get_account(rental_pda)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_stake_claim_and_unstake() {
+    let program_id = Pubkey::new_unique();
+    let mut context = program_test(program_id).start_with_context().await;
+    let payer = Keypair::from_bytes(&context.payer.to_bytes()).unwrap();
+    let recent_blockhash = context.last_blockhash;
+
+    process(
+        &mut context.banks_client,
+        &[factory::init_state(&program_id, &payer.pubkey(), 0)],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let (reward_mint, payer_reward_account) =
+        create_mint(&mut context.banks_client, &payer, recent_blockhash, 1_000).await;
+    let (mint, payer_token_account) =
+        create_mint(&mut context.banks_client, &payer, recent_blockhash, 1).await;
+    let (staker, staker_token_account) =
+        create_buyer(&mut context.banks_client, &payer, recent_blockhash, &mint).await;
+    let staker_reward_account = get_associated_token_address(&staker.pubkey(), &reward_mint);
+    process(
+        &mut context.banks_client,
+        &[
+            instruction::transfer(
+                &id(),
+                &payer_token_account,
+                &staker_token_account,
+                &payer.pubkey(),
+                &[],
+                1,
+            )
+            .unwrap(),
+            create_associated_token_account(&payer.pubkey(), &staker.pubkey(), &reward_mint, &id()),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // tier 0 earns 1 per second, tier 1 earns 5; the vault only holds 150
+    process(
+        &mut context.banks_client,
+        &[
+            factory::init_stake_pool(&program_id, &payer.pubkey(), &reward_mint, vec![1, 5]),
+            factory::fund_stake_pool(
+                &program_id,
+                &payer.pubkey(),
+                &reward_mint,
+                &payer_reward_account,
+                150,
+            ),
+            factory::set_rarity_tier(&program_id, &payer.pubkey(), &mint, 1),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // only the marketplace owner may retune emissions
+    assert!(process(
+        &mut context.banks_client,
+        &[factory::set_emission_rates(
+            &program_id,
+            &staker.pubkey(),
+            vec![100, 100]
+        )],
+        &staker,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+
+    // a mint without a rarity tier, or one that is not an NFT, earns nothing
+    let (unregistered, unregistered_account) =
+        create_mint(&mut context.banks_client, &payer, recent_blockhash, 1).await;
+    let (fungible, fungible_account) =
+        create_mint(&mut context.banks_client, &payer, recent_blockhash, 2).await;
+    process(
+        &mut context.banks_client,
+        &[factory::set_rarity_tier(
+            &program_id,
+            &payer.pubkey(),
+            &fungible,
+            0,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    for (mint, token_account, error) in [
+        (
+            unregistered,
+            unregistered_account,
+            GachaError::UnregisteredMint,
+        ),
+        (fungible, fungible_account, GachaError::NotAnNft),
+    ] {
+        let err = process(
+            &mut context.banks_client,
+            &[factory::stake_nft(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &token_account,
+            )],
+            &payer,
+            &[],
+            recent_blockhash,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+        );
+    }
+
+    process(
+        &mut context.banks_client,
+        &[factory::stake_nft(
+            &program_id,
+            &staker.pubkey(),
+            &mint,
+            &staker_token_account,
+        )],
+        &staker,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, staker_token_account).await,
+        0
+    );
+
+    let (entry_pda, _) = StakeEntry::find_address(&program_id, &mint);
+    let entry_account = context
+        .banks_client
+        .get_account(entry_pda)
+        .await
+        .unwrap()
+        .unwrap();
+    let entry = StakeEntry::unpack_account(&entry_account.data).unwrap();
+    assert_eq!(entry.tier, 1);
+    assert_eq!(entry.owner, staker.pubkey());
+
+    let clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    context.set_sysvar(&Clock {
+        unix_timestamp: entry.staked_at + 10,
+        ..clock.clone()
+    });
+    process(
+        &mut context.banks_client,
+        &[factory::claim_stake_rewards(
+            &program_id,
+            &staker.pubkey(),
+            &mint,
+            &reward_mint,
+            &staker_reward_account,
+        )],
+        &staker,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, staker_reward_account).await,
+        50
+    );
+
+    // 150 more are owed but only 100 are left, unstaking still goes through
+    context.set_sysvar(&Clock {
+        unix_timestamp: entry.staked_at + 40,
+        ..clock
+    });
+    process(
+        &mut context.banks_client,
+        &[factory::unstake_nft(
+            &program_id,
+            &staker.pubkey(),
+            &mint,
+            &staker_token_account,
+            &reward_mint,
+            &staker_reward_account,
+        )],
+        &staker,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context.banks_client, staker_reward_account).await,
+        150
+    );
+    assert_eq!(
+        token_balance(&mut context.banks_client, staker_token_account).await,
+        1
+    );
+    assert!(context
+        .banks_client
+        .get_account(entry_pda)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_loyalty_points_and_redemption() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let collection = Pubkey::new_unique();
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 2).await;
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+
+    // one point per 10 lamports, the collection earns double
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 30),
+            factory::set_loyalty_config(&program_id, &payer.pubkey(), 10, 1, 5),
+            factory::set_collection_multiplier(&program_id, &payer.pubkey(), &collection, 20_000),
+            factory::set_mint_collection(&program_id, &payer.pubkey(), &mint, Some(collection)),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                100,
+                2,
+                "badge",
+                "limited",
+            ),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // the listing takes the registered collection, which only the fee
+    // manager can change
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert_eq!(state.map.get(&1).unwrap().collection, Some(collection));
+    let err = process(
+        &mut banks_client,
+        &[factory::set_mint_collection(
+            &program_id,
+            &buyer.pubkey(),
+            &mint,
+            None,
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::MissingRole as u32)
+        )
+    );
+
+    let loyalty_pda = factory::loyalty_address(&program_id, &buyer.pubkey());
+    let purchase = |amount| {
+        factory::purchase_sale(
+            &program_id,
+            &buyer.pubkey(),
+            &buyer_token_account,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &mint,
+            1,
+            100,
+            amount,
+            None,
+        )
+    };
+
+    process(
+        &mut banks_client,
+        &[purchase(1)],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let loyalty_account = banks_client
+        .get_account(loyalty_pda)
+        .await
+        .unwrap()
+        .unwrap();
+    let loyalty = LoyaltyAccount::unpack_account(&loyalty_account.data).unwrap();
+    // 200 for the item at 2x plus 3 for the listing fee
+    assert_eq!(loyalty.points, 23);
+
+    // redeemed credit covers most of the next listing fee
+    let owner_before = banks_client.get_balance(payer.pubkey()).await.unwrap();
+    process(
+        &mut banks_client,
+        &[
+            factory::redeem_loyalty_points(
+                &program_id,
+                &buyer.pubkey(),
+                LoyaltyReward::FeeCredit { points: 20 },
+            ),
+            purchase(1),
+            factory::redeem_loyalty_points(
+                &program_id,
+                &buyer.pubkey(),
+                LoyaltyReward::FreeDraws { draws: 2 },
+            ),
+        ],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        banks_client.get_balance(payer.pubkey()).await.unwrap(),
+        owner_before + 100 + 10
+    );
+
+    let loyalty_account = banks_client
+        .get_account(loyalty_pda)
+        .await
+        .unwrap()
+        .unwrap();
+    let loyalty = LoyaltyAccount::unpack_account(&loyalty_account.data).unwrap();
+    assert_eq!(loyalty.points, 23 - 20 + 21 - 10);
+    assert_eq!(loyalty.fee_credit, 0);
+    assert_eq!(loyalty.free_draws, 2);
+
+    // there are not enough points left for another draw
+    assert!(process(
+        &mut banks_client,
+        &[factory::redeem_loyalty_points(
+            &program_id,
+            &buyer.pubkey(),
+            LoyaltyReward::FreeDraws { draws: 3 },
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_referral_fee_split() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 2).await;
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+    let (referrer, _) = create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+
+    // a quarter of the 1000 lamport listing fee goes to the referrer
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 1_000),
+            factory::set_referral_bps(&program_id, &payer.pubkey(), 2_500),
+            factory::register_referrer(&program_id, &referrer.pubkey()),
+            factory::register_referrer(&program_id, &buyer.pubkey()),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                100,
+                2,
+                "ticket",
+                "referred",
+            ),
+        ],
+        &payer,
+        &[&referrer, &buyer],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let purchase = |referrer: &Pubkey| {
+        factory::purchase_sale(
+            &program_id,
+            &buyer.pubkey(),
+            &buyer_token_account,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &mint,
+            1,
+            100,
+            1,
+            Some(referrer),
+        )
+    };
+
+    assert!(process(
+        &mut banks_client,
+        &[purchase(&buyer.pubkey())],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+
+    let owner_before = banks_client.get_balance(payer.pubkey()).await.unwrap();
+    process(
+        &mut banks_client,
+        &[purchase(&referrer.pubkey())],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        banks_client.get_balance(payer.pubkey()).await.unwrap(),
+        owner_before + 100 + 750
+    );
+
+    let referrer_pda = factory::referrer_address(&program_id, &referrer.pubkey());
+    let referrer_account = banks_client
+        .get_account(referrer_pda)
+        .await
+        .unwrap()
+        .unwrap();
+    let earnings = ReferrerAccount::unpack_account(&referrer_account.data).unwrap();
+    assert_eq!(earnings.unclaimed, 250);
+    assert_eq!(earnings.total_earned, 250);
+
+    let referrer_before = banks_client.get_balance(referrer.pubkey()).await.unwrap();
+    process(
+        &mut banks_client,
+        &[factory::claim_referral_earnings(
+            &program_id,
+            &referrer.pubkey(),
+        )],
+        &payer,
+        &[&referrer],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        banks_client.get_balance(referrer.pubkey()).await.unwrap(),
+        referrer_before + 250
+    );
+
+    // paused referrals leave the whole fee with the owner
+    process(
+        &mut banks_client,
+        &[factory::set_paused(
+            &program_id,
+            &payer.pubkey(),
+            State::PAUSE_REFERRALS,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let blockhash = banks_client
+        .get_new_latest_blockhash(&recent_blockhash)
+        .await
+        .unwrap();
+    let owner_before = banks_client.get_balance(payer.pubkey()).await.unwrap();
+    process(
+        &mut banks_client,
+        &[purchase(&referrer.pubkey())],
+        &buyer,
+        &[],
+        blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        banks_client.get_balance(payer.pubkey()).await.unwrap(),
+        owner_before + 100 + 1_000
+    );
+    let referrer_account = banks_client
+        .get_account(referrer_pda)
+        .await
+        .unwrap()
+        .unwrap();
+    let earnings = ReferrerAccount::unpack_account(&referrer_account.data).unwrap();
+    assert_eq!(earnings.unclaimed, 0);
+    assert_eq!(earnings.total_earned, 250);
+}
+
+#[tokio::test]
+async fn test_multisig_role_and_ownership_transfer() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let signers = [Keypair::new(), Keypair::new(), Keypair::new()];
+    let seed = Pubkey::new_unique();
+    let multisig = factory::multisig_address(&program_id, &seed);
+
+    // a 2 of 3 multisig becomes the fee manager
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            factory::create_multisig(
+                &program_id,
+                &payer.pubkey(),
+                &seed,
+                signers.iter().map(|signer| signer.pubkey()).collect(),
+                2,
+            ),
+            factory::set_role(&program_id, &payer.pubkey(), Role::FeeManager, &multisig),
+            system_instruction::transfer(&payer.pubkey(), &signers[0].pubkey(), 100_000_000),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // the owner no longer manages fees
+    assert!(process(
+        &mut banks_client,
+        &[factory::set_referral_bps(&program_id, &payer.pubkey(), 500)],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+
+    let action = factory::set_referral_bps(&program_id, &multisig, 500);
+    process(
+        &mut banks_client,
+        &[factory::propose_multisig_action(
+            &program_id,
+            &signers[0].pubkey(),
+            &multisig,
+            0,
+            &action,
+        )],
+        &signers[0],
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let execute = |executor: &Keypair| {
+        factory::execute_multisig_action(
+            &program_id,
+            &executor.pubkey(),
+            &multisig,
+            0,
+            &signers[0].pubkey(),
+            &action,
+        )
+    };
+    assert!(process(
+        &mut banks_client,
+        &[execute(&payer)],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+
+    process(
+        &mut banks_client,
+        &[
+            factory::approve_multisig_action(&program_id, &signers[1].pubkey(), &multisig, 0),
+            execute(&signers[1]),
+        ],
+        &payer,
+        &[&signers[1]],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_state(&mut banks_client, &program_id).await.referral_bps,
+        500
+    );
+    assert!(banks_client
+        .get_account(factory::proposal_address(&program_id, &multisig, 0))
+        .await
+        .unwrap()
+        .is_none());
+
+    // ownership only moves once the new owner accepts
+    let new_owner = Keypair::new();
+    process(
+        &mut banks_client,
+        &[factory::transfer_ownership(
+            &program_id,
+            &payer.pubkey(),
+            &new_owner.pubkey(),
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_state(&mut banks_client, &program_id).await.owner,
+        payer.pubkey()
+    );
+
+    process(
+        &mut banks_client,
+        &[factory::accept_ownership(&program_id, &new_owner.pubkey())],
+        &payer,
+        &[&new_owner],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert_eq!(state.owner, new_owner.pubkey());
+    assert_eq!(state.pending_owner, None);
+
+    assert!(process(
+        &mut banks_client,
+        &[factory::set_role(
+            &program_id,
+            &payer.pubkey(),
+            Role::Pauser,
+            &payer.pubkey(),
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_paused_gacha_still_allows_delisting() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 1).await;
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                50,
+                1,
+                "card",
+                "rare",
+            ),
+            factory::create_gacha(&program_id, &payer.pubkey(), &payer.pubkey(), 1),
+            factory::set_paused(&program_id, &payer.pubkey(), State::PAUSE_GACHA),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let err = process(
+        &mut banks_client,
+        &[factory::gacha(
+            &program_id,
+            &buyer.pubkey(),
+            &payer.pubkey(),
+            1,
+            50,
+            None,
+            &[(payer.pubkey(), mint, buyer_token_account)],
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(GachaError::Paused as u32))
+    );
+
+    // the seller can still pull the item while draws are stopped
+    process(
+        &mut banks_client,
+        &[factory::cancel_market_item(
+            &program_id,
+            &payer.pubkey(),
+            &seller_token_account,
+            &mint,
+            1,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut banks_client, seller_token_account).await,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_update_config_routes_fees_to_treasury() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 1).await;
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+    let treasury = Pubkey::new_unique();
+
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            system_instruction::transfer(&payer.pubkey(), &treasury, 1_000_000),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                1_000,
+                1,
+                "relic",
+                "taxed",
+            ),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // only the fee manager may retune fees
+    assert!(process(
+        &mut banks_client,
+        &[factory::update_config(
+            &program_id,
+            &buyer.pubkey(),
+            factory::ConfigUpdate {
+                fee_bps: Some(10_000),
+                ..Default::default()
+            },
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .is_err());
+
+    process(
+        &mut banks_client,
+        &[factory::update_config(
+            &program_id,
+            &payer.pubkey(),
+            factory::ConfigUpdate {
+                listing_price: Some(20),
+                fee_bps: Some(1_000),
+                max_gacha_qty: Some(3),
+                treasury: Some(treasury),
+                gacha_fee: Some(5),
+            },
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert_eq!(state.listing_price, 20);
+    assert_eq!(state.fee_bps, 1_000);
+    assert_eq!(state.max_gacha_qty, 3);
+    assert_eq!(state.treasury, treasury);
+    assert_eq!(state.gacha_fee, 5);
+
+    let seller_before = banks_client.get_balance(payer.pubkey()).await.unwrap();
+    let treasury_before = banks_client.get_balance(treasury).await.unwrap();
+    process(
+        &mut banks_client,
+        &[factory::purchase_sale(
+            &program_id,
+            &buyer.pubkey(),
+            &buyer_token_account,
+            &payer.pubkey(),
+            &treasury,
+            &mint,
+            1,
+            1_000,
+            1,
+            None,
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        banks_client.get_balance(payer.pubkey()).await.unwrap(),
+        seller_before + 900
+    );
+    assert_eq!(
+        banks_client.get_balance(treasury).await.unwrap(),
+        treasury_before + 100 + 20
+    );
+}
+
+#[tokio::test]
+async fn test_spoofed_state_account_is_rejected() {
+    let program_id = Pubkey::new_unique();
+    let attacker = Keypair::new();
+
+    // a well formed state naming the attacker, but owned by another program
+    let spoofed = State {
+        owner: attacker.pubkey(),
+        roles: Roles::all(attacker.pubkey()),
+        initialized: true,
+        ..State::default()
+    };
+    let mut data = spoofed.pack_account().unwrap();
+    data.resize(State::LEN, 0);
+    let mut program_test = program_test(program_id);
+    program_test.add_account(
+        State::find_address(&program_id).0,
+        SolanaAccount {
+            lamports: 1_000_000_000,
+            data,
+            owner: Pubkey::new_unique(),
+            ..SolanaAccount::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let err = process(
+        &mut banks_client,
+        &[factory::set_paused(
+            &program_id,
+            &attacker.pubkey(),
+            State::PAUSE_ALL,
+        )],
+        &payer,
+        &[&attacker],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::InvalidAccountOwner as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_account_type_and_token_program_are_checked() {
+    let program_id = Pubkey::new_unique();
+    let lister = Keypair::new();
+    let rental_mint = Pubkey::new_unique();
+
+    // a program owned rental PDA that actually carries a stake entry's tag
+    let listing = RentalListing {
+        owner: lister.pubkey(),
+        mint: rental_mint,
+        price_per_period: 0,
+        period_secs: 60,
+        max_periods: 1,
+        renter: None,
+        expires_at: 0,
+        bump: 0,
+    };
+    let mut data = listing.pack_account().unwrap();
+    data[..8].copy_from_slice(&StakeEntry::DISCRIMINATOR);
+    data.resize(RentalListing::LEN, 0);
+    let mut program_test = program_test(program_id);
+    program_test.add_account(
+        RentalListing::find_address(&program_id, &rental_mint).0,
+        SolanaAccount {
+            lamports: 1_000_000_000,
+            data,
+            owner: program_id,
+            ..SolanaAccount::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 1).await;
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                50,
+                1,
+                "card",
+                "rare",
+            ),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let err = process(
+        &mut banks_client,
+        &[factory::rent_item(
+            &program_id,
+            &payer.pubkey(),
+            &lister.pubkey(),
+            &rental_mint,
+            1,
+            None,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::InvalidDiscriminator as u32)
+        )
+    );
+
+    // delisting through a look-alike token program never reaches the CPI
+    let mut cancel = factory::cancel_market_item(
+        &program_id,
+        &payer.pubkey(),
+        &seller_token_account,
+        &mint,
+        1,
+    );
+    cancel.accounts[5].pubkey = Pubkey::new_unique();
+    let err = process(&mut banks_client, &[cancel], &payer, &[], recent_blockhash)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::InvalidTokenProgram as u32)
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_overflowing_purchase_returns_math_error() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 2).await;
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                u64::MAX,
+                2,
+                "card",
+                "priceless",
+            ),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // two units at u64::MAX lamports each cannot be priced
+    let err = process(
+        &mut banks_client,
+        &[factory::purchase_sale(
+            &program_id,
+            &buyer.pubkey(),
+            &buyer_token_account,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &mint,
+            1,
+            u64::MAX,
+            2,
+            None,
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::MathOverflow as u32)
+        )
+    );
+}
+
+// State accounts captured from the unversioned program, the bare borsh
+// layout (items written with its own State type, its listing instruction
+// never completed). The authority in both is keypair_from_seed(&[7; 32]).
+const STATE_V0: &[u8] = include_bytes!("fixtures/state_baseline.bin");
+const STATE_V0_LISTED: &[u8] = include_bytes!("fixtures/state_baseline_listed.bin");
+
+fn fixture_authority() -> Keypair {
+    keypair_from_seed(&[7; 32]).unwrap()
+}
+
+#[test]
+fn test_legacy_state_fixtures_decode() {
+    let authority = fixture_authority().pubkey();
+
+    for data in [STATE_V0, STATE_V0_LISTED] {
+        assert_eq!(State::account_version(data).unwrap(), 0);
+        let state = State::unpack_account(data).unwrap();
+        assert!(state.initialized);
+        assert_eq!(state.owner, authority);
+        assert_eq!(state.listing_price, 1);
+        assert_eq!(state.role_holder(Role::UpgradeAuthority), authority);
+        assert_eq!(state.treasury, authority);
+        assert_eq!(state.max_gacha_qty, State::DEFAULT_MAX_GACHA_QTY);
+    }
+    let state = State::unpack_account(STATE_V0).unwrap();
+    assert!(state.map.is_empty());
+    assert_eq!(state.item_ids, 0);
+
+    let state = State::unpack_account(STATE_V0_LISTED).unwrap();
+    assert_eq!(state.item_ids, 2);
+    assert_eq!(state.item_sold, 1);
+    let sold = &state.map[&1];
+    assert_eq!(sold.file_name, "potion");
+    assert_eq!(sold.price, 100);
+    assert!(sold.sold);
+    assert_eq!((sold.amount, sold.remaining), (1, 0));
+    assert_eq!(sold.owner, Some(Pubkey::new_from_array([2; 32])));
+    let unsold = &state.map[&2];
+    assert_eq!(unsold.description, "two-handed");
+    assert_eq!(unsold.price, 250);
+    assert_eq!((unsold.amount, unsold.remaining), (1, 1));
+
+    // it is not mistaken for a versioned header
+    assert!(StateHeader::from_account_data(STATE_V0).is_err());
+}
+
+#[tokio::test]
+async fn test_migrate_legacy_states() {
+    let authority = fixture_authority();
+
+    for data in [STATE_V0, STATE_V0_LISTED] {
+        let program_id = Pubkey::new_unique();
+        let (state_pda, _) = State::find_address(&program_id);
+        let mut program_test = program_test(program_id);
+        program_test.add_account(
+            state_pda,
+            SolanaAccount {
+                lamports: Rent::default().minimum_balance(data.len()),
+                data: data.to_vec(),
+                owner: program_id,
+                ..SolanaAccount::default()
+            },
+        );
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let before = get_state(&mut banks_client, &program_id).await;
+        assert_eq!(State::account_version(data).unwrap(), 0);
+
+        // handlers that read the header in place refuse the old layout
+        let err = process(
+            &mut banks_client,
+            &[factory::set_paused(
+                &program_id,
+                &authority.pubkey(),
+                State::PAUSE_GACHA,
+            )],
+            &payer,
+            &[&authority],
+            recent_blockhash,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(GachaError::UnsupportedVersion as u32)
+            )
+        );
+
+        let err = process(
+            &mut banks_client,
+            &[factory::migrate_state(&program_id, &payer.pubkey())],
+            &payer,
+            &[],
+            recent_blockhash,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(GachaError::MissingRole as u32)
+            )
+        );
+
+        let mut transaction = Transaction::new_with_payer(
+            &[
+                system_instruction::transfer(&payer.pubkey(), &authority.pubkey(), 1_000_000_000),
+                factory::migrate_state(&program_id, &authority.pubkey()),
+                factory::set_paused(&program_id, &authority.pubkey(), State::PAUSE_GACHA),
+            ],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer, &authority], recent_blockhash);
+        banks_client.process_transaction(transaction).await.unwrap();
+
+        let account = banks_client.get_account(state_pda).await.unwrap().unwrap();
+        assert!(account.data.len() >= State::LEN);
+        assert_eq!(
+            State::account_version(&account.data).unwrap(),
+            State::VERSION
+        );
+        assert!(account.lamports >= Rent::default().minimum_balance(account.data.len()));
+        let state = get_state(&mut banks_client, &program_id).await;
+        assert!(state.is_paused(State::PAUSE_GACHA));
+        assert_eq!(state.owner, before.owner);
+        assert_eq!(state.item_ids, before.item_ids);
+        assert_eq!(
+            state
+                .map
+                .values()
+                .map(|item| &item.file_name)
+                .collect::<Vec<_>>(),
+            before
+                .map
+                .values()
+                .map(|item| &item.file_name)
+                .collect::<Vec<_>>()
+        );
+
+        // the migrated counters and config are readable in place
+        let header = StateHeader::from_account_data(&account.data).unwrap();
+        assert_eq!(header.owner, authority.pubkey());
+        assert!(header.is_paused(State::PAUSE_GACHA));
+    }
+}
+
+#[tokio::test]
+async fn test_state_grows_with_listings() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 4).await;
+    process(
+        &mut banks_client,
+        &[factory::init_state(&program_id, &payer.pubkey(), 0)],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let err = banks_client
+        .process_transaction(Transaction::new_signed_with_payer(
+            &[factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                10,
+                1,
+                "card",
+                &"d".repeat(MarketItem::MAX_DESCRIPTION_LEN + 1),
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        ))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(GachaError::ListingTextTooLong as u32)
+        )
+    );
+
+    // four long listings no longer fit in the initial allocation
+    let description = |i: u128| format!("{i}{}", "d".repeat(300));
+    for i in 1..=4 {
+        process(
+            &mut banks_client,
+            &[factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                10,
+                1,
+                "card",
+                &description(i),
+            )],
+            &payer,
+            &[],
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+    }
+
+    let (state_pda, _) = State::find_address(&program_id);
+    let account = banks_client.get_account(state_pda).await.unwrap().unwrap();
+    assert!(account.data.len() > State::LEN);
+    let rent = banks_client.get_rent().await.unwrap();
+    assert!(rent.is_exempt(account.lamports, account.data.len()));
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert_eq!(state.map.len(), 4);
+    assert_eq!(state.map.get(&4).unwrap().description, description(4));
+
+    // the account is now full, a multiplier still fits by growing it again
+    let collection = Pubkey::new_unique();
+    process(
+        &mut banks_client,
+        &[factory::set_collection_multiplier(
+            &program_id,
+            &payer.pubkey(),
+            &collection,
+            20_000,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    let grown = banks_client.get_account(state_pda).await.unwrap().unwrap();
+    assert!(grown.data.len() > account.data.len());
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert_eq!(
+        state.loyalty.collection_multipliers.get(&collection),
+        Some(&20_000)
+    );
+}
+
+#[tokio::test]
+async fn test_wallet_indexes_track_listings_and_purchases() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 3).await;
+    let (other_mint, other_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 1).await;
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                10,
+                3,
+                "card",
+                "first",
+            ),
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &other_mint,
+                &other_token_account,
+                10,
+                1,
+                "card",
+                "second",
+            ),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_wallet_index(
+            &mut banks_client,
+            &program_id,
+            &payer.pubkey(),
+            IndexKind::Listed
+        )
+        .await,
+        vec![1, 2]
+    );
+
+    // two partial fills of the same listing are recorded once
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+    for amount in 1..=2 {
+        process(
+            &mut banks_client,
+            &[factory::purchase_sale(
+                &program_id,
+                &buyer.pubkey(),
+                &buyer_token_account,
+                &payer.pubkey(),
+                &payer.pubkey(),
+                &mint,
+                1,
+                10,
+                amount,
+                None,
+            )],
+            &buyer,
+            &[],
+            recent_blockhash,
+        )
+        .await
+        .unwrap();
+    }
+    assert_eq!(
+        get_wallet_index(
+            &mut banks_client,
+            &program_id,
+            &buyer.pubkey(),
+            IndexKind::Owned
+        )
+        .await,
+        vec![1]
+    );
+
+    process(
+        &mut banks_client,
+        &[factory::cancel_market_item(
+            &program_id,
+            &payer.pubkey(),
+            &other_token_account,
+            &other_mint,
+            2,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_wallet_index(
+            &mut banks_client,
+            &program_id,
+            &payer.pubkey(),
+            IndexKind::Listed
+        )
+        .await,
+        vec![1]
+    );
+}
+
+#[tokio::test]
+async fn test_fetch_queries_filter_and_paginate() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 3).await;
+    let listings: Vec<_> = [10, 20, 30]
+        .iter()
+        .map(|price| {
+            factory::create_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &mint,
+                &seller_token_account,
+                *price,
+                1,
+                "card",
+                &format!("priced {price}"),
+            )
+        })
+        .collect();
+    process(
+        &mut banks_client,
+        &[
+            &[factory::init_state(&program_id, &payer.pubkey(), 0)],
+            &listings[..],
+        ]
+        .concat(),
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let (buyer, buyer_token_account) =
+        create_buyer(&mut banks_client, &payer, recent_blockhash, &mint).await;
+    process(
+        &mut banks_client,
+        &[factory::purchase_sale(
+            &program_id,
+            &buyer.pubkey(),
+            &buyer_token_account,
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &mint,
+            2,
+            20,
+            1,
+            None,
+        )],
+        &buyer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // client side, over the decoded state
+    let state = get_state(&mut banks_client, &program_id).await;
+    let ids = |page: &ItemPage| {
+        page.items
+            .iter()
+            .map(|item| item.item_id)
+            .collect::<Vec<_>>()
+    };
+    let unsold = ItemFilter {
+        unsold: true,
+        ..ItemFilter::default()
+    };
+    let first = query::market_items(
+        &state,
+        &unsold,
+        Page {
+            after: None,
+            limit: 1,
+        },
+    );
+    assert_eq!((ids(&first), first.next), (vec![1], Some(1)));
+    let second = query::market_items(
+        &state,
+        &unsold,
+        Page {
+            after: first.next,
+            limit: 1,
+        },
+    );
+    assert_eq!((ids(&second), second.next), (vec![3], None));
+    let priced = ItemFilter {
+        min_price: Some(15),
+        max_price: Some(25),
+        ..ItemFilter::default()
+    };
+    assert_eq!(
+        ids(&query::market_items(&state, &priced, Page::default())),
+        vec![2]
+    );
+    assert_eq!(
+        ids(&query::items_created(
+            &state,
+            &payer.pubkey(),
+            Page::default()
+        )),
+        vec![1, 2, 3]
+    );
+
+    // on chain, answered through the return data of a simulation
+    let mut transaction = Transaction::new_with_payer(
+        &[factory::fetch_my_nfts(
+            &program_id,
+            &buyer.pubkey(),
+            Page::default(),
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let simulation = banks_client
+        .simulate_transaction(transaction)
+        .await
+        .unwrap();
+    let return_data = simulation
+        .simulation_details
+        .unwrap()
+        .return_data
+        .expect("no return data");
+    assert_eq!(return_data.program_id, program_id);
+    let page = ItemPage::from_return_data(&return_data.data).unwrap();
+    assert_eq!((ids(&page), page.next), (vec![2], None));
+    assert_eq!(page.items[0].owner, Some(buyer.pubkey()));
+}
+
+#[test]
+fn test_pages_truncate_to_return_data() {
+    let item = |item_id: u128, description: String| MarketItem {
+        item_id,
+        token_program_id: spl_token::id(),
+        mint_address: Pubkey::new_unique(),
+        seller: Pubkey::new_unique(),
+        owner: None,
+        price: 10,
+        file_name: "card".to_string(),
+        description,
+        collection: None,
+        sold: false,
+        gacha: true,
+        amount: 1,
+        remaining: 1,
+        decimals: 0,
+    };
+    let ids = |page: &ItemPage| {
+        page.items
+            .iter()
+            .map(|item| item.item_id)
+            .collect::<Vec<_>>()
+    };
+    let long = || "d".repeat(MarketItem::MAX_DESCRIPTION_LEN);
+
+    let mut page = ItemPage {
+        items: (1..=20).map(|id| item(id, long())).collect(),
+        next: None,
+    };
+    page.truncate_to(MAX_RETURN_DATA).unwrap();
+    assert!(page.try_to_vec().unwrap().len() <= MAX_RETURN_DATA);
+    let fits = page.items.len();
+    assert!(fits > 0 && fits < 20);
+    assert_eq!(page.next, Some(fits as u128));
+
+    // an item too large for any page, written before the length caps, is
+    // skipped with an empty page pointing past it
+    let mut page = ItemPage {
+        items: vec![item(7, "d".repeat(MAX_RETURN_DATA)), item(8, long())],
+        next: None,
+    };
+    page.truncate_to(MAX_RETURN_DATA).unwrap();
+    assert_eq!((ids(&page), page.next), (vec![], Some(7)));
+}
+
+#[test]
+fn test_event_logs_are_decoded() {
+    let program_id = Pubkey::new_unique();
+    let other_program = Pubkey::new_unique();
+    let listed = MarketplaceEvent::ItemListed {
+        item_id: 1,
+        seller: Pubkey::new_unique(),
+        mint: Pubkey::new_unique(),
+        price: 100,
+        amount: 2,
+    };
+    let paid = MarketplaceEvent::FeesCollected {
+        payer: Pubkey::new_unique(),
+        treasury: Pubkey::new_unique(),
+        amount: 5,
+        referrer: None,
+        referral_amount: 0,
+    };
+    let data = |event: &MarketplaceEvent| {
+        let mut data = EVENT_DISCRIMINATOR.to_vec();
+        data.extend(event.try_to_vec().unwrap());
+        format!("Program data: {}", STANDARD.encode(data))
+    };
+
+    // the format the runtime writes for sol_log_data, with data logged by
+    // an invoked program and by a later top level instruction mixed in
+    let logs = [
+        format!("Program {program_id} invoke [1]"),
+        data(&listed),
+        format!("Program {other_program} invoke [2]"),
+        data(&paid),
+        format!("Program {other_program} success"),
+        "Program data: bm90IGFuIGV2ZW50".to_string(),
+        data(&paid),
+        format!("Program {program_id} consumed 5000 of 200000 compute units"),
+        format!("Program {program_id} success"),
+        format!("Program {other_program} invoke [1]"),
+        data(&listed),
+        format!("Program {other_program} success"),
+    ];
+    assert_eq!(event::parse_logs(&program_id, &logs), vec![listed, paid]);
+}
+
+#[tokio::test]
+async fn test_debug_logs_follow_the_feature() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[factory::init_state(&program_id, &payer.pubkey(), 1)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let logs = banks_client
+        .simulate_transaction(transaction)
+        .await
+        .unwrap()
+        .simulation_details
+        .unwrap()
+        .logs;
+    let traced = |prefix: &str| logs.iter().any(|log| log.starts_with(prefix));
+    assert_eq!(
+        traced("Program log: Instruction: InitState"),
+        cfg!(feature = "debug-logs")
+    );
+    assert_eq!(
+        traced("Program log: Handler end"),
+        cfg!(feature = "debug-logs")
+    );
+}
+
+#[test]
+fn test_indexer_replays_fixture_dir() {
+    let program_id = Pubkey::new_unique();
+    let seller = Pubkey::new_unique();
+    let buyer = Pubkey::new_unique();
+    let item = |item_id: u128, amount: u64| MarketItem {
+        item_id,
+        token_program_id: id(),
+        mint_address: Pubkey::new_unique(),
+        seller,
+        owner: None,
+        price: 10,
+        file_name: "card".to_string(),
+        description: String::new(),
+        collection: None,
+        sold: false,
+        gacha: false,
+        amount,
+        remaining: amount,
+        decimals: 0,
+    };
+    let (first, second) = (item(1, 2), item(2, 1));
+    let logs = |events: &[MarketplaceEvent]| {
+        let mut logs = vec![format!("Program {program_id} invoke [1]")];
+        for event in events {
+            let mut data = EVENT_DISCRIMINATOR.to_vec();
+            data.extend(event.try_to_vec().unwrap());
+            logs.push(format!("Program data: {}", STANDARD.encode(data)));
+        }
+        logs.push(format!("Program {program_id} success"));
+        logs.join("\n")
+    };
+    let listed = |item: &MarketItem| MarketplaceEvent::ItemListed {
+        item_id: item.item_id,
+        seller,
+        mint: item.mint_address,
+        price: item.price,
+        amount: item.amount,
+    };
+
+    // the state after item 1 was cancelled and item 2 drawn
+    let mut state = State {
+        initialized: true,
+        ..State::default()
+    };
+    let mut drawn = second.clone();
+    drawn.owner = Some(buyer);
+    drawn.gacha = true;
+    drawn.sold = true;
+    drawn.remaining = 0;
+    state.map.insert(2, drawn);
+
+    let dir = std::env::temp_dir().join(format!("marketplace-indexer-{program_id}"));
+    std::fs::create_dir_all(&dir).unwrap();
+    let fixtures = [
+        ("0001-list.log", logs(&[listed(&first), listed(&second)])),
+        (
+            "0002-buy.log",
+            logs(&[
+                MarketplaceEvent::FeesCollected {
+                    payer: buyer,
+                    treasury: Pubkey::new_unique(),
+                    amount: 1,
+                    referrer: None,
+                    referral_amount: 0,
+                },
+                MarketplaceEvent::ItemSold {
+                    item_id: 1,
+                    buyer,
+                    seller,
+                    price: 10,
+                    amount: 1,
+                },
+            ]),
+        ),
+        (
+            "0003-draw.log",
+            logs(&[
+                MarketplaceEvent::GachaPoolFilled { item_ids: vec![2] },
+                MarketplaceEvent::GachaDrawn {
+                    item_id: 2,
+                    buyer,
+                    seller,
+                    price: 10,
+                },
+            ]),
+        ),
+    ];
+    for (name, contents) in &fixtures {
+        std::fs::write(dir.join(name), contents).unwrap();
+    }
+    std::fs::write(
+        dir.join("0004-snapshot.state"),
+        state.pack_account().unwrap(),
+    )
+    .unwrap();
+
+    let mut indexer = Indexer::open_in_memory(program_id).unwrap();
+    assert_eq!(indexer.replay_dir(&dir).unwrap(), 4);
+    // replaying again must not count any transaction twice
+    indexer.replay_dir(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let listings = indexer.listings(false).unwrap();
+    assert_eq!(listings.len(), 2);
+    assert_eq!(listings[0].remaining, 1);
+    assert_eq!(listings[0].owner, None);
+    assert!(listings[0].cancelled);
+    assert_eq!(listings[1].remaining, 0);
+    assert_eq!(listings[1].owner, Some(buyer));
+    assert!(listings[1].gacha);
+    assert!(!listings[1].cancelled);
+    assert!(indexer.listings(true).unwrap().is_empty());
+    assert_eq!(indexer.last_signature().unwrap().as_deref(), Some("draw"));
+
+    let kinds = |wallet: &Pubkey| -> Vec<String> {
+        indexer
+            .wallet_history(wallet)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.kind)
+            .collect()
+    };
+    assert_eq!(kinds(&buyer), ["fee_paid", "bought", "drew"]);
+    assert_eq!(kinds(&seller), ["listed", "listed", "sold", "sold"]);
+}
+
+/// Runs one gacha-cli command line the way the binary would, signed by `signer`.
+async fn run_cli(
+    banks_client: &mut BanksClient,
+    signer: &Keypair,
+    recent_blockhash: Hash,
+    program_id: &Pubkey,
+    command: &str,
+) -> Result<(), solana_program_test::BanksClientError> {
+    let args = format!("--program {program_id} {command}");
+    let options = Options::parse(args.split(' ').map(str::to_string)).unwrap();
+    assert_eq!(options.program_id, *program_id);
+    let state = if options.command.needs_state() {
+        Some(get_state(banks_client, program_id).await)
+    } else {
+        None
+    };
+    let instructions = options
+        .command
+        .instructions(program_id, &signer.pubkey(), state.as_ref())
+        .unwrap();
+    process(banks_client, &instructions, signer, &[], recent_blockhash).await
+}
+
+#[tokio::test]
+async fn test_cli_commands() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+    let (mint, seller_token_account) =
+        create_mint(&mut banks_client, &payer, recent_blockhash, 4).await;
+    // the buyer has no token account, the cli creates it
+    let buyer = Keypair::new();
+    process(
+        &mut banks_client,
+        &[system_instruction::transfer(
+            &payer.pubkey(),
+            &buyer.pubkey(),
+            1_000_000_000,
+        )],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    // pooled units can only be drawn, so the buy comes before create-pool
+    for (signer, command) in [
+        (&payer, "init-state 0".to_string()),
+        (&payer, format!("list --name card {mint} 50 3")),
+        (&buyer, "buy 1 --amount 1".to_string()),
+        (&payer, "create-pool 1".to_string()),
+        (&buyer, "draw 1 50".to_string()),
+    ] {
+        run_cli(
+            &mut banks_client,
+            signer,
+            recent_blockhash,
+            &program_id,
+            &command,
+        )
+        .await
+        .unwrap();
+    }
+
+    let buyer_token_account = get_associated_token_address(&buyer.pubkey(), &mint);
+    assert_eq!(
+        token_balance(&mut banks_client, buyer_token_account).await,
+        2
+    );
+    let state = get_state(&mut banks_client, &program_id).await;
+    let item = state.map.get(&1).unwrap();
+    assert_eq!(item.file_name, "card");
+    assert_eq!(item.remaining, 1);
+    assert!(item.gacha);
+    let summary = cli::describe_state(&State::find_address(&program_id).0, &state);
+    assert!(summary.contains("item 1:") && summary.contains("remaining 1/3 gacha"));
+
+    run_cli(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        "update-config --fee-bps 100 --max-gacha-qty 5",
+    )
+    .await
+    .unwrap();
+    run_cli(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &program_id,
+        "delist 1",
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        token_balance(&mut banks_client, seller_token_account).await,
+        2
+    );
+    let state = get_state(&mut banks_client, &program_id).await;
+    assert_eq!((state.fee_bps, state.max_gacha_qty), (100, 5));
+    assert!(state.map.is_empty());
+
+    for bad in ["list 1 2", "buy one", "draw 1 50 extra", "mint-all"] {
+        assert!(Options::parse(bad.split(' ').map(str::to_string)).is_err());
+    }
+    let draw = Options::parse(["draw", "1", "60"].map(str::to_string)).unwrap();
+    assert!(draw
+        .command
+        .instructions(&program_id, &buyer.pubkey(), Some(&state))
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_marketplace_client() {
+    let program_id = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(program_id).start().await;
+    let (mint, _) = create_mint(&mut banks_client, &payer, recent_blockhash, 4).await;
+    let buyer = Keypair::new();
+    process(
+        &mut banks_client,
+        &[
+            factory::init_state(&program_id, &payer.pubkey(), 0),
+            system_instruction::transfer(&payer.pubkey(), &buyer.pubkey(), 1_000_000_000),
+        ],
+        &payer,
+        &[],
+        recent_blockhash,
+    )
+    .await
+    .unwrap();
+
+    let mut client = MarketplaceClient::new(
+        banks_client.clone(),
+        program_id,
+        Keypair::from_bytes(&payer.to_bytes()).unwrap(),
+    );
+    let item_id = client
+        .list_item(
+            &payer,
+            &NewListing {
+                mint,
+                price: 50,
+                amount: 3,
+                file_name: "card".to_string(),
+                ..NewListing::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(item_id, 1);
+
+    // the buyer's token account is created by the first purchase
+    client.buy(&buyer, item_id, 1, None).await.unwrap();
+    // program errors come back typed
+    assert!(matches!(
+        client.buy(&buyer, item_id, 5, None).await,
+        Err(ClientError::Program(GachaError::InsufficientQuantity))
+    ));
+    client
+        .send(
+            &[factory::create_gacha(
+                &program_id,
+                &payer.pubkey(),
+                &payer.pubkey(),
+                1,
+            )],
+            &[&payer],
+        )
+        .await
+        .unwrap();
+    client.draw_gacha(&buyer, 1, 50, None).await.unwrap();
+    let buyer_token_account = get_associated_token_address(&buyer.pubkey(), &mint);
+    assert_eq!(
+        token_balance(&mut banks_client, buyer_token_account).await,
+        2
+    );
+    let item = client.get_item(item_id).await.unwrap();
+    assert_eq!((item.remaining, item.owner), (1, None));
+
+    assert!(matches!(
+        client.buy(&buyer, item_id, 1, None).await,
+        Err(ClientError::Program(GachaError::ItemInGachaPool))
+    ));
+    assert!(matches!(
+        client.draw_gacha(&buyer, 1, 60, None).await,
+        Err(ClientError::Program(GachaError::PoolEmpty))
+    ));
+    assert!(matches!(
+        client.get_item(9).await,
+        Err(ClientError::ItemNotFound(9))
+    ));
+
+    // a listing cancelled after partial fills stays in the buyer's index
+    let owned = client
+        .get_wallet_items(&buyer.pubkey(), IndexKind::Owned)
+        .await
+        .unwrap();
+    assert_eq!(owned.len(), 1);
+    assert_eq!(owned[0].item_id, item_id);
+    client
+        .send(
+            &[factory::cancel_market_item(
+                &program_id,
+                &payer.pubkey(),
+                &get_associated_token_address(&payer.pubkey(), &mint),
+                &mint,
+                item_id,
+            )],
+            &[&payer],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        get_wallet_index(
+            &mut banks_client,
+            &program_id,
+            &buyer.pubkey(),
+            IndexKind::Owned
+        )
+        .await,
+        vec![item_id]
+    );
+    assert!(client
+        .get_wallet_items(&buyer.pubkey(), IndexKind::Owned)
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(client
+        .get_wallet_items(&Pubkey::new_unique(), IndexKind::Owned)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // relisting the same mint gets the next id
+    let relisted = client
+        .list_item(
+            &payer,
+            &NewListing {
+                mint,
+                price: 50,
+                amount: 2,
+                file_name: "card".to_string(),
+                ..NewListing::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(relisted, 2);
+    assert_eq!(client.get_item(relisted).await.unwrap().remaining, 2);
+}
+
+#[test]
+fn test_idl_matches_the_factory() {
+    let program_id = testsolana::id();
+    let wallet = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+    let generated = idl::generate(&program_id.to_string());
+    let instructions = generated["instructions"].as_array().unwrap();
+    assert_eq!(instructions.len(), idl::INSTRUCTION_ACCOUNTS.len());
+
+    let samples = [
+        factory::init_state(&program_id, &wallet, 100),
+        factory::create_market_item(
+            &program_id,
+            &wallet,
+            &mint,
+            &Pubkey::new_unique(),
+            10,
+            1,
+            "a.png",
+            "a",
+        ),
+        factory::purchase_sale(
+            &program_id,
+            &wallet,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &mint,
+            1,
+            10,
+            1,
+            Some(&Pubkey::new_unique()),
+        ),
+        factory::gacha(
+            &program_id,
+            &wallet,
+            &Pubkey::new_unique(),
+            1,
+            10,
+            None,
+            &[(Pubkey::new_unique(), mint, Pubkey::new_unique())],
+        ),
+        factory::cancel_swap(&program_id, &wallet, &wallet, 0, &[mint]),
+        factory::stake_nft(&program_id, &wallet, &mint, &Pubkey::new_unique()),
+        factory::accept_ownership(&program_id, &wallet),
+        factory::migrate_state(&program_id, &wallet),
+        factory::fetch_market_items(&program_id, ItemFilter::default(), Page::default()),
+    ];
+    for sample in samples {
+        let instruction = &instructions[sample.data[0] as usize];
+        assert_eq!(instruction["discriminator"][0], sample.data[0]);
+        let accounts = instruction["accounts"].as_array().unwrap();
+        // trailing accounts are only described in the docs
+        assert!(accounts.len() <= sample.accounts.len() || instruction["docs"].is_null());
+        for (meta, account) in sample.accounts.iter().zip(accounts) {
+            assert_eq!(
+                meta.is_writable,
+                account["writable"] == true,
+                "{} {}",
+                instruction["name"],
+                account["name"]
+            );
+            assert_eq!(meta.is_signer, account["signer"] == true);
+        }
+    }
+
+    let errors = generated["errors"].as_array().unwrap();
+    assert_eq!(errors[0]["name"], "InvalidPrice");
+    assert_eq!(
+        errors.last().unwrap()["code"],
+        errors.len() as u64 - 1,
+        "error codes are contiguous"
+    );
+    let state = &generated["accounts"][0];
+    assert_eq!(state["name"], "State");
+    let mut discriminator = State::DISCRIMINATOR.to_vec();
+    discriminator.push(State::VERSION);
+    assert_eq!(state["discriminator"], json!(discriminator));
+    assert_eq!(
+        generated["events"][0]["discriminator"],
+        json!(EVENT_DISCRIMINATOR)
+    );
+}
+
+#[test]
+fn test_error_codes_are_stable() {
+    // codes are part of the interface and must never change, the first six
+    // are the ones the original program returned
+    assert_eq!(GachaError::InvalidPrice as u32, 0);
+    assert_eq!(GachaError::CashbackMax as u32, 1);
+    assert_eq!(GachaError::InvalidPayment as u32, 2);
+    assert_eq!(GachaError::StateAlreadyInitialized as u32, 5);
+    assert_eq!(GachaError::ItemNotFound as u32, 8);
+    assert_eq!(GachaError::NotSeller as u32, 9);
+    assert_eq!(GachaError::PoolEmpty as u32, 13);
+    assert_eq!(GachaError::WrongMint as u32, 15);
+    assert_eq!(GachaError::Paused as u32, 37);
+    assert_eq!(GachaError::InvalidWalletIndexAccount as u32, 46);
+    assert_eq!(GachaError::ItemAlreadySold as u32, 47);
+    assert_eq!(GachaError::InsufficientPoints as u32, 48);
+    assert_eq!(GachaError::InvalidCollectionAccount as u32, 49);
+    assert_eq!(GachaError::ListingTextTooLong as u32, 50);
+    assert_eq!(GachaError::ItemInGachaPool as u32, 51);
+    assert_eq!(GachaError::UnregisteredMint as u32, 52);
+    assert_eq!(GachaError::NotAnNft as u32, 53);
+    assert_eq!(GachaError::InvalidFreezeAuthority as u32, 54);
+
+    for code in 0..=GachaError::InvalidFreezeAuthority as u32 {
+        let error = GachaError::from_program_error(&ProgramError::Custom(code)).unwrap();
+        assert_eq!(error as u32, code);
+        assert_eq!(ProgramError::from(error), ProgramError::Custom(code));
+        assert_eq!(
+            GachaError::from_instruction_error(&InstructionError::Custom(code)),
+            Some(error)
+        );
+    }
+    assert_eq!(
+        GachaError::from_program_error(&ProgramError::Custom(
+            GachaError::InvalidFreezeAuthority as u32 + 1
+        )),
+        None
+    );
+    assert_eq!(
+        GachaError::from_program_error(&ProgramError::MissingRequiredSignature),
+        None
+    );
 }